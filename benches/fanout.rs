@@ -0,0 +1,66 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+//! Measure the per-viewer cost of fanning frames out through the broadcast
+//! channel: the frame is stamped when it is sent as by the stream's sender, and
+//! every viewer receives it and takes the shared metadata, its own times and
+//! the payload as a WebSocket session writes them.
+//!
+//!   cargo bench --bench fanout
+
+#[allow(dead_code)]
+#[path = "../src/dataframe.rs"]
+mod dataframe;
+
+use dataframe::DataFrame;
+use serde_json::json;
+use std::hint::black_box;
+use std::time::Instant;
+use tokio::sync::broadcast;
+
+const FRAMES: usize = 200;
+
+fn run(viewers: usize, frame_size: usize) {
+    let (tx, _) = broadcast::channel::<DataFrame>(FRAMES);
+    let mut receivers: Vec<_> = (0..viewers).map(|_| tx.subscribe()).collect();
+    let payload = vec![0u8; frame_size];
+
+    let start = Instant::now();
+    for i in 0..FRAMES {
+        let metadata = json!({
+            "ts": i as f64 * 40.0,
+            "media": "video",
+            "codec": "avc1.640033",
+            "type": "keyframe",
+        });
+        let _ = tx.send(DataFrame::new(metadata, payload.clone()).stamped());
+
+        for rx in receivers.iter_mut() {
+            let frame = rx.try_recv().expect("frame");
+            let [json, times] = frame.json_parts();
+            black_box((json, times, frame.data.clone()));
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let per_viewer = elapsed.as_nanos() as f64 / (FRAMES * viewers) as f64;
+    println!(
+        "viewers:{viewers:4} frame:{:5}KiB total:{:8.2}ms per frame per viewer:{per_viewer:9.0}ns",
+        frame_size / 1024,
+        elapsed.as_secs_f64() * 1000.0,
+    );
+}
+
+fn main() {
+    for frame_size in [16 * 1024, 512 * 1024] {
+        for viewers in [1, 10, 50, 100] {
+            run(viewers, frame_size);
+        }
+    }
+}
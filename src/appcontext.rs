@@ -8,22 +8,225 @@
 ** -------------------------------------------------------------------------*/
 
 
-use std::{collections::HashMap, sync::{Arc, Mutex}};
-use crate::streamdef::StreamsDef;
+use std::{collections::HashMap, fmt, sync::{Arc, Mutex, RwLock}};
+use log::{info, warn};
+use serde_json::json;
+use crate::clientsession::Clients;
+use crate::dash;
+use crate::events::Events;
+use crate::record;
+use crate::webhook;
+use crate::rtsp::Credentials;
+use crate::streamdef::{Created, StreamsDef, DEFAULT_CAPACITY};
+use crate::whip;
+
+/// Why a publisher is refused a stream.
+#[derive(Debug)]
+pub enum PublishError {
+    /// The path is not configured and publishing to other paths is disabled.
+    UnknownStream(String),
+    Unauthorized(String),
+    /// The stream is pulled from its camera or already has a publisher.
+    Forbidden(String),
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownStream(path) => write!(f, "unknown stream '{path}'"),
+            Self::Unauthorized(path) => write!(f, "invalid credentials to publish '{path}'"),
+            Self::Forbidden(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for PublishError {}
 
 pub struct AppContext {
-    pub streams: HashMap<String,Arc<Mutex<StreamsDef>>>,
+    /// Streams by path, the configured ones and those created by publishers.
+    pub streams: Arc<RwLock<HashMap<String,Arc<Mutex<StreamsDef>>>>>,
+    /// Credentials allowing publishers to create streams at unconfigured paths.
+    pub publish: Option<Credentials>,
     pub quic_port: Option<u16>,
     pub cert_fingerprint: Option<Vec<u8>>,
+    pub clients: Clients,
+    pub whip: whip::Sessions,
+    pub dash: dash::Packagers,
+    pub recordings: record::Recorders,
+    pub events: Events,
+    pub webhooks: Vec<Arc<webhook::Target>>,
+    /// Credentials of the ONVIF devices found by discovery.
+    pub onvif: Option<Credentials>,
+    /// Transport of the cameras that do not set theirs, from the command line.
+    pub transport: Option<String>,
+    /// Credentials required by the ONVIF discovery endpoints, which are
+    /// disabled without.
+    pub discovery: Option<Credentials>,
 }
 
 impl AppContext {
     pub fn new(
         streams: HashMap<String,Arc<Mutex<StreamsDef>>>,
+        publish: Option<Credentials>,
         quic_port: Option<u16>,
         cert_fingerprint: Option<Vec<u8>>,
     ) -> Self {
-        Self { streams, quic_port, cert_fingerprint }
+        let events = Events::default();
+        for stream_def in streams.values() {
+            stream_def.lock().unwrap().events = events.clone();
+        }
+        Self {
+            streams: Arc::new(RwLock::new(streams)),
+            publish,
+            quic_port,
+            cert_fingerprint,
+            clients: Clients::new(events.clone()),
+            whip: whip::Sessions::default(),
+            dash: dash::Packagers::default(),
+            recordings: record::Recorders::default(),
+            events,
+            webhooks: vec![],
+            onvif: None,
+            transport: None,
+            discovery: None,
+        }
+    }
+
+    pub fn stream(&self, path: &str) -> Option<Arc<Mutex<StreamsDef>>> {
+        self.streams.read().unwrap().get(path).cloned()
+    }
+
+    /// Snapshot of the streams, sorted by path.
+    pub fn stream_list(&self) -> Vec<(String, Arc<Mutex<StreamsDef>>)> {
+        let mut streams: Vec<_> = self
+            .streams
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(path, streamdef)| (path.clone(), streamdef.clone()))
+            .collect();
+        streams.sort_by(|a, b| a.0.cmp(&b.0));
+        streams
+    }
+
+    /// Stream at `path`, created with `create` when it does not exist yet.
+    pub fn stream_or_insert(&self, path: &str, create: impl FnOnce() -> StreamsDef) -> Arc<Mutex<StreamsDef>> {
+        self.streams
+            .write()
+            .unwrap()
+            .entry(path.to_string())
+            .or_insert_with(|| {
+                let mut stream_def = create();
+                stream_def.events = self.events.clone();
+                Arc::new(Mutex::new(stream_def))
+            })
+            .clone()
+    }
+
+    /// Take the stream at `path` for the publisher at `remote`, checking the
+    /// `authorization` it presents. Streams configured without camera accept
+    /// their own credentials, other paths are created with the global ones.
+    /// `method` is the one of the request carrying a Digest authorization.
+    /// The caller gives the stream back with [`StreamsDef::release`], which
+    /// removes the streams created here once nobody watches them.
+    pub fn claim_stream(&self, path: &str, authorization: Option<&str>, method: &str, remote: &str) -> Result<Arc<Mutex<StreamsDef>>, PublishError> {
+        if path.trim_matches('/').is_empty() {
+            return Err(PublishError::UnknownStream(path.to_string()));
+        }
+        let stream_def = self.stream(path);
+        let credentials = match &stream_def {
+            Some(stream_def) => {
+                let stream_def = stream_def.lock().unwrap();
+                if stream_def.source.is_some() {
+                    return Err(PublishError::Forbidden(format!("stream '{path}' is pulled from its camera")));
+                }
+                stream_def.publish.clone().or_else(|| self.publish.clone())
+            }
+            None => self.publish.clone(),
+        }
+        .ok_or_else(|| PublishError::UnknownStream(path.to_string()))?;
+        if !credentials.authorize(authorization, method) {
+            return Err(PublishError::Unauthorized(path.to_string()));
+        }
+
+        let stream_def = stream_def.unwrap_or_else(|| {
+            info!("Creating stream '{}' for publisher {}", path, remote);
+            self.stream_or_insert(path, || {
+                let mut stream_def = StreamsDef::published(None, DEFAULT_CAPACITY);
+                stream_def.created = Some(Created { path: path.to_string(), streams: Arc::downgrade(&self.streams) });
+                stream_def
+            })
+        });
+        {
+            let mut stream_def = stream_def.lock().unwrap();
+            if let Some(publisher) = &stream_def.publisher {
+                return Err(PublishError::Forbidden(format!("stream '{path}' is already published by {publisher}")));
+            }
+            stream_def.publisher = Some(remote.to_string());
+        }
+        Ok(stream_def)
+    }
+
+    /// Replace the configured streams with those of a reloaded config: new
+    /// paths are added, and the streams removed or changed are closed, their
+    /// viewers being disconnected. Streams created by publishers or imported
+    /// are kept. Returns the streams added or changed.
+    pub fn reload(&self, mut streams: HashMap<String, Arc<Mutex<StreamsDef>>>) -> Vec<(String, Arc<Mutex<StreamsDef>>)> {
+        // the configs are read without the streams locked, see StreamsDef::release
+        let current: Vec<_> = self
+            .stream_list()
+            .into_iter()
+            .map(|(path, stream_def)| {
+                let config = stream_def.lock().unwrap().config.clone();
+                (path, stream_def, config)
+            })
+            .collect();
+        let (mut added, mut changed, mut removed, mut closed) = (vec![], vec![], vec![], vec![]);
+        for (path, stream_def, config) in current {
+            let Some(new) = streams.get(&path) else {
+                if config.is_some() {
+                    removed.push(path);
+                    closed.push(stream_def);
+                }
+                continue;
+            };
+            if config.is_none() {
+                warn!("Keeping stream '{}' not loaded from the config over its new entry", path);
+            } else if new.lock().unwrap().config != config {
+                changed.push(path.clone());
+                closed.push(stream_def);
+                continue;
+            }
+            streams.remove(&path);
+        }
+        added.extend(streams.keys().filter(|path| !changed.contains(path)).cloned());
+        added.sort();
+        for stream_def in streams.values() {
+            stream_def.lock().unwrap().events = self.events.clone();
+        }
+
+        {
+            let mut current = self.streams.write().unwrap();
+            for path in &removed {
+                current.remove(path);
+            }
+            for (path, stream_def) in &streams {
+                current.insert(path.clone(), stream_def.clone());
+            }
+        }
+        for stream_def in closed {
+            stream_def.lock().unwrap().close();
+        }
+        info!("Config reloaded, added {:?}, changed {:?}, removed {:?}", added, changed, removed);
+        self.events.emit("config_reloaded", json!({ "added": added, "changed": changed, "removed": removed }));
+        streams.into_iter().collect()
+    }
+
+    /// Path and definition of the substream configured for a stream, if any.
+    pub fn substream(&self, streamdef: &Arc<Mutex<StreamsDef>>) -> Option<(String, Arc<Mutex<StreamsDef>>)> {
+        let path = streamdef.lock().unwrap().substream.clone()?;
+        let substream = self.stream(&path)?;
+        Some((path, substream))
     }
 }
 
@@ -31,8 +234,100 @@ impl Clone for AppContext {
     fn clone(&self) -> Self {
         Self {
             streams: self.streams.clone(),
+            publish: self.publish.clone(),
             quic_port: self.quic_port,
             cert_fingerprint: self.cert_fingerprint.clone(),
+            clients: self.clients.clone(),
+            whip: self.whip.clone(),
+            dash: self.dash.clone(),
+            recordings: self.recordings.clone(),
+            events: self.events.clone(),
+            webhooks: self.webhooks.clone(),
+            onvif: self.onvif.clone(),
+            transport: self.transport.clone(),
+            discovery: self.discovery.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streamdef::Viewer;
+
+    #[test]
+    fn streams_created_by_publishers_are_removed_once_unused() {
+        let publish = Credentials { user: "pub".to_string(), password: "secret".to_string() };
+        let app_context = AppContext::new(HashMap::new(), Some(publish), None, None);
+
+        let stream_def = app_context.claim_stream("/live", Some("Bearer secret"), "POST", "10.0.0.1:5000").unwrap();
+        stream_def.lock().unwrap().release();
+        assert!(app_context.stream("/live").is_none());
+
+        let stream_def = app_context.claim_stream("/live", Some("Bearer secret"), "POST", "10.0.0.1:5000").unwrap();
+        let _rx = stream_def.lock().unwrap().add_viewer("/live");
+        stream_def.lock().unwrap().release();
+        assert!(app_context.stream("/live").is_some());
+        stream_def.lock().unwrap().remove_viewer();
+        assert!(app_context.stream("/live").is_none());
+    }
+
+    #[test]
+    fn configured_streams_are_kept() {
+        let streams = HashMap::from([(
+            "/cam".to_string(),
+            Arc::new(Mutex::new(StreamsDef::published(Some(Credentials { user: "pub".to_string(), password: "secret".to_string() }), DEFAULT_CAPACITY))),
+        )]);
+        let app_context = AppContext::new(streams, None, None, None);
+
+        assert!(app_context.claim_stream("/other", Some("Bearer secret"), "POST", "10.0.0.1:5000").is_err());
+        let stream_def = app_context.claim_stream("/cam", Some("Bearer secret"), "POST", "10.0.0.1:5000").unwrap();
+        stream_def.lock().unwrap().release();
+        assert!(app_context.stream("/cam").is_some());
+    }
+
+    /// Stream of the config with its "urls" entry.
+    fn configured(config: serde_json::Value) -> Arc<Mutex<StreamsDef>> {
+        let mut stream_def = StreamsDef::published(None, DEFAULT_CAPACITY);
+        stream_def.config = Some(config);
+        Arc::new(Mutex::new(stream_def))
+    }
+
+    #[tokio::test]
+    async fn reloads_replace_the_changed_streams_only() {
+        let streams = HashMap::from([
+            ("/kept".to_string(), configured(json!({ "capacity": 10 }))),
+            ("/changed".to_string(), configured(json!({ "capacity": 10 }))),
+            ("/removed".to_string(), configured(json!({ "capacity": 10 }))),
+        ]);
+        let publish = Credentials { user: "pub".to_string(), password: "secret".to_string() };
+        let app_context = AppContext::new(streams, Some(publish), None, None);
+        let published = app_context.claim_stream("/published", Some("Bearer secret"), "POST", "10.0.0.1:5000").unwrap();
+        let kept = app_context.stream("/kept").unwrap();
+        let mut removed = Viewer::new(app_context.stream("/removed").unwrap(), "/removed");
+        let mut events = app_context.events.subscribe();
+
+        let started = app_context.reload(HashMap::from([
+            ("/kept".to_string(), configured(json!({ "capacity": 10 }))),
+            ("/changed".to_string(), configured(json!({ "capacity": 20 }))),
+            ("/added".to_string(), configured(json!({ "capacity": 10 }))),
+            ("/published".to_string(), configured(json!({ "capacity": 10 }))),
+        ]));
+        let mut started: Vec<_> = started.into_iter().map(|(path, _)| path).collect();
+        started.sort();
+        assert_eq!(started, ["/added", "/changed"]);
+        let paths: Vec<_> = app_context.stream_list().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, ["/added", "/changed", "/kept", "/published"]);
+        assert!(Arc::ptr_eq(&app_context.stream("/kept").unwrap(), &kept));
+        assert!(Arc::ptr_eq(&app_context.stream("/published").unwrap(), &published));
+        assert_eq!(app_context.stream("/changed").unwrap().lock().unwrap().config, Some(json!({ "capacity": 20 })));
+
+        let event: serde_json::Value = serde_json::from_slice(&events.recv().await.unwrap()).unwrap();
+        assert_eq!(event["type"], "config_reloaded");
+        assert_eq!(event["added"], json!(["/added"]));
+        assert_eq!(event["changed"], json!(["/changed"]));
+        assert_eq!(event["removed"], json!(["/removed"]));
+        // the viewers of the removed streams see them end
+        assert!(matches!(removed.rx.recv().await, Err(tokio::sync::broadcast::error::RecvError::Closed)));
+    }
+}
@@ -0,0 +1,413 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use anyhow::{anyhow, Error};
+use bytes::Bytes;
+use serde_json::json;
+
+use crate::dataframe::DataFrame;
+
+/// Payload format of the video frames delivered to a client, selected with
+/// the `format` query parameter (`/name?format=annexb`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    /// Annex B frames, with the avcC/hvcC record prepended to keyframes (default).
+    #[default]
+    Legacy,
+    /// Annex B frames with start codes and inline SPS/PPS/VPS on keyframes.
+    AnnexB,
+    /// Length-prefixed frames without parameter sets, the avcC/hvcC record
+    /// being sent in a `config` message before the first keyframe using it.
+    Avcc,
+}
+
+impl Format {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::Legacy => "legacy",
+            Format::AnnexB => "annexb",
+            Format::Avcc => "avcc",
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "legacy" => Ok(Format::Legacy),
+            "annexb" => Ok(Format::AnnexB),
+            "avcc" | "hvcc" => Ok(Format::Avcc),
+            _ => Err(anyhow!("unknown bitstream format '{}'", s)),
+        }
+    }
+}
+
+/// Split an Annex B bitstream into NAL units, without their start codes.
+pub fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut units = vec![];
+    let mut start = None;
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            if let Some(start) = start {
+                let mut end = i;
+                while end > start && data[end - 1] == 0 {
+                    end -= 1;
+                }
+                units.push(&data[start..end]);
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    if let Some(start) = start {
+        units.push(&data[start..]);
+    }
+    units
+}
+
+/// AVCDecoderConfigurationRecord of an SPS and a PPS.
+pub fn avc_config(sps: &[u8], pps: &[u8]) -> Option<Vec<u8>> {
+    let mut config = vec![1, *sps.get(1)?, *sps.get(2)?, *sps.get(3)?, 0xff, 0xe1];
+    config.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    config.extend_from_slice(sps);
+    config.push(1);
+    config.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    config.extend_from_slice(pps);
+    Some(config)
+}
+
+/// Reader of the Exp-Golomb coded fields of an RBSP.
+struct BitReader {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl BitReader {
+    /// Reader of a NAL unit payload, without its emulation prevention bytes.
+    fn new(nal: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(nal.len());
+        for &byte in nal {
+            if byte == 3 && data.ends_with(&[0, 0]) {
+                continue;
+            }
+            data.push(byte);
+        }
+        Self { data, pos: 0 }
+    }
+
+    fn bit(&mut self) -> Option<u32> {
+        let byte = self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Some(u32::from(bit))
+    }
+
+    fn bits(&mut self, n: usize) -> Option<u32> {
+        (0..n).try_fold(0, |value, _| Some(value << 1 | self.bit()?))
+    }
+
+    fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u32 << zeros) - 1 + self.bits(zeros)?)
+    }
+
+    fn se(&mut self) -> Option<i32> {
+        let value = self.ue()?;
+        Some(if value & 1 == 1 { (value / 2 + 1) as i32 } else { -((value / 2) as i32) })
+    }
+}
+
+/// Picture width and height of an H.264 SPS, after cropping.
+pub fn sps_dimensions(sps: &[u8]) -> Option<(u32, u32)> {
+    let mut reader = BitReader::new(sps);
+    reader.bits(8)?;
+    let profile_idc = reader.bits(8)?;
+    reader.bits(16)?;
+    reader.ue()?;
+    let mut chroma_format_idc = 1;
+    if matches!(profile_idc, 100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135) {
+        chroma_format_idc = reader.ue()?;
+        if chroma_format_idc == 3 {
+            reader.bit()?;
+        }
+        reader.ue()?;
+        reader.ue()?;
+        reader.bit()?;
+        if reader.bit()? == 1 {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if reader.bit()? == 0 {
+                    continue;
+                }
+                let size = if i < 6 { 16 } else { 64 };
+                let (mut last, mut next) = (8, 8);
+                for _ in 0..size {
+                    if next != 0 {
+                        next = (last + reader.se()? + 256) % 256;
+                    }
+                    if next != 0 {
+                        last = next;
+                    }
+                }
+            }
+        }
+    }
+    reader.ue()?;
+    match reader.ue()? {
+        0 => {
+            reader.ue()?;
+        }
+        1 => {
+            reader.bit()?;
+            reader.se()?;
+            reader.se()?;
+            for _ in 0..reader.ue()? {
+                reader.se()?;
+            }
+        }
+        _ => {}
+    }
+    reader.ue()?;
+    reader.bit()?;
+    let width_in_mbs = reader.ue()? + 1;
+    let height_in_map_units = reader.ue()? + 1;
+    let frame_mbs_only = reader.bit()?;
+    if frame_mbs_only == 0 {
+        reader.bit()?;
+    }
+    reader.bit()?;
+    let (mut left, mut right, mut top, mut bottom) = (0, 0, 0, 0);
+    if reader.bit()? == 1 {
+        (left, right, top, bottom) = (reader.ue()?, reader.ue()?, reader.ue()?, reader.ue()?);
+    }
+    let (crop_x, crop_y) = match chroma_format_idc {
+        0 => (1, 2 - frame_mbs_only),
+        1 => (2, 2 * (2 - frame_mbs_only)),
+        2 => (2, 2 - frame_mbs_only),
+        _ => (1, 2 - frame_mbs_only),
+    };
+    let width = (width_in_mbs * 16).checked_sub((left + right) * crop_x)?;
+    let height = ((2 - frame_mbs_only) * height_in_map_units * 16).checked_sub((top + bottom) * crop_y)?;
+    Some((width, height))
+}
+
+/// SPS/PPS for H.264, VPS/SPS/PPS for H.265.
+fn is_parameter_set(codec: &str, nal: &[u8]) -> bool {
+    let Some(&header) = nal.first() else { return false };
+    if codec.starts_with("avc") {
+        matches!(header & 0x1f, 7 | 8)
+    } else {
+        matches!((header >> 1) & 0x3f, 32..=34)
+    }
+}
+
+/// Convert an Annex B frame to 4-byte length-prefixed NAL units, leaving out
+/// the parameter sets carried by the decoder configuration record.
+fn to_avcc(codec: &str, annexb: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(annexb.len());
+    for nal in nal_units(annexb) {
+        if nal.is_empty() || is_parameter_set(codec, nal) {
+            continue;
+        }
+        out.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+        out.extend_from_slice(nal);
+    }
+    out
+}
+
+/// Per-client conversion of the broadcast frames to the requested format. The
+/// converted payloads are shared by the clients of the same format, only the
+/// configuration sent is tracked per client.
+#[derive(Default)]
+pub struct Bitstream {
+    pub format: Format,
+    /// Decoder configuration record last sent to the client.
+    config: Option<Bytes>,
+}
+
+impl Bitstream {
+    pub fn new(format: Format) -> Self {
+        Self { format, config: None }
+    }
+
+    /// Frames to send for a broadcast frame. In AVCC format a keyframe whose
+    /// configuration the client has not received yet is preceded by a
+    /// synthesized `config` message. Legacy clients get no `config` message,
+    /// their keyframes carrying the record.
+    pub fn convert(&mut self, frame: DataFrame) -> Vec<DataFrame> {
+        if self.format == Format::Legacy && frame.is_config() {
+            return vec![];
+        }
+        // only the video is converted, audio and metadata go through as they are
+        if self.format == Format::Legacy || frame.metadata["media"] != "video" {
+            return vec![frame];
+        }
+        if frame.is_config() {
+            if self.format == Format::Avcc {
+                self.config = Some(frame.data.clone());
+            }
+            return vec![frame];
+        }
+        let Some(codec) = frame.metadata["codec"].as_str() else {
+            return vec![frame];
+        };
+
+        match self.format {
+            Format::Avcc => {
+                let mut frames = vec![];
+                let config = frame.config();
+                if !config.is_empty() && self.config.as_ref() != Some(&config) {
+                    let metadata = json!({ "type": "config", "media": "video", "codec": codec });
+                    frames.push(DataFrame::new(metadata, config.clone()));
+                    self.config = Some(config);
+                }
+                let data = frame.avcc_payload(|payload| to_avcc(codec, payload));
+                frames.push(frame.with_data(data));
+                frames
+            }
+            _ => {
+                let data = frame.payload();
+                vec![frame.with_data(data)]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: &[u8] = &[0x67, 0x64, 0x00, 0x1e, 0xac, 0xd9, 0x40, 0xa0, 0x2f, 0xf9, 0x61, 0x00, 0x00, 0x03, 0x00, 0x01, 0x00, 0x00, 0x03, 0x00, 0x3c, 0x8f, 0x16, 0x2d, 0x96];
+    const PPS: &[u8] = &[0x68, 0xeb, 0xec, 0xb2, 0x2c];
+    const IDR: &[u8] = &[0x65, 0x88, 0x84, 0x21];
+
+    fn config() -> DataFrame {
+        DataFrame::new(json!({ "type": "config", "media": "video", "codec": "avc1.64001E" }), avc_config(SPS, PPS).unwrap())
+    }
+
+    /// Keyframe as broadcast, the avcC record prepended to its Annex B NAL units.
+    fn keyframe() -> DataFrame {
+        let record = avc_config(SPS, PPS).unwrap();
+        let data = [&record[..], &[0, 0, 0, 1], IDR].concat();
+        DataFrame::new(json!({ "type": "keyframe", "media": "video", "codec": "avc1.64001E" }), data).with_config_len(record.len())
+    }
+
+    #[test]
+    fn legacy_clients_get_no_config_message() {
+        let mut bitstream = Bitstream::new(Format::Legacy);
+        assert!(bitstream.convert(config()).is_empty());
+        let audio = DataFrame::new(json!({ "type": "config", "media": "audio", "codec": "mp4a.40.2" }), vec![]);
+        assert!(bitstream.convert(audio).is_empty());
+        let frames = bitstream.convert(keyframe());
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].data, keyframe().data);
+
+        for format in [Format::AnnexB, Format::Avcc] {
+            let frames = Bitstream::new(format).convert(config());
+            assert_eq!(frames.len(), 1);
+            assert!(frames[0].is_config());
+        }
+    }
+
+    #[test]
+    fn nal_units_split_on_both_start_codes() {
+        let data = [&[0, 0, 0, 1][..], SPS, &[0, 0, 1], PPS, &[0, 0, 0, 0, 1], IDR].concat();
+        assert_eq!(nal_units(&data), vec![SPS, PPS, IDR]);
+        // leading garbage is skipped, a start code at the end gives an empty unit
+        assert_eq!(nal_units(&[0x09, 0xf0, 0, 0, 1, 0x41, 0x9a, 0, 0, 1]), vec![&[0x41, 0x9a][..], &[]]);
+        // the zeros of a truncated start code stay in the last unit
+        assert_eq!(nal_units(&[0, 0, 1, 0x65, 0x88, 0, 0]), vec![&[0x65, 0x88, 0, 0][..]]);
+        assert!(nal_units(&[0x65, 0x88, 0, 0]).is_empty());
+        assert!(nal_units(&[0, 0]).is_empty());
+        assert!(nal_units(&[]).is_empty());
+    }
+
+    #[test]
+    fn avcc_frames_leave_out_the_parameter_sets() {
+        let data = [&[0, 0, 0, 1][..], SPS, &[0, 0, 0, 1], PPS, &[0, 0, 0, 1], IDR, &[0, 0, 1, 0, 0, 1, 0x06, 0x05]].concat();
+        assert_eq!(to_avcc("avc1.64001E", &data), [&[0, 0, 0, 4][..], IDR, &[0, 0, 0, 2, 0x06, 0x05]].concat());
+        // VPS, SPS and PPS of H.265
+        let data = [0, 0, 0, 1, 0x40, 0x01, 0, 0, 0, 1, 0x42, 0x01, 0, 0, 0, 1, 0x44, 0x01, 0, 0, 0, 1, 0x26, 0x01, 0xaf];
+        assert_eq!(to_avcc("hvc1.1.6.L93.B0", &data), [0, 0, 0, 3, 0x26, 0x01, 0xaf]);
+        assert!(to_avcc("avc1.64001E", &[0x65, 0x88]).is_empty());
+        assert!(to_avcc("avc1.64001E", &[]).is_empty());
+    }
+
+    #[test]
+    fn sps_dimensions_are_read_after_cropping() {
+        assert_eq!(sps_dimensions(SPS), Some((640, 360)));
+        // baseline profile, without chroma format fields
+        let baseline = [0x67, 0x42, 0x00, 0x1e, 0xab, 0x40, 0x50, 0x1e, 0xd0, 0x0f, 0x08, 0x84, 0x6a];
+        assert_eq!(sps_dimensions(&baseline), Some((640, 480)));
+        // 1088 lines cropped to 1080
+        let cropped = [0x67, 0x64, 0x00, 0x28, 0xac, 0xd9, 0x40, 0x78, 0x02, 0x27, 0xe5, 0xc0, 0x44, 0x00, 0x00, 0x03, 0x00, 0x04, 0x00, 0x00, 0x03, 0x00, 0xf0, 0x3c, 0x60, 0xc6, 0x58];
+        assert_eq!(sps_dimensions(&cropped), Some((1920, 1080)));
+
+        // the fields up to the cropping end in the 11th byte
+        for len in 0..11 {
+            assert_eq!(sps_dimensions(&SPS[..len]), None, "{len} bytes");
+        }
+        // Exp-Golomb code longer than 32 bits
+        assert_eq!(sps_dimensions(&[0x67, 0x64, 0x00, 0x1e, 0, 0, 0, 0, 0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn avcc_clients_get_the_config_before_the_first_keyframe_using_it() {
+        let mut bitstream = Bitstream::new(Format::Avcc);
+        let frames = bitstream.convert(keyframe());
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].is_config());
+        assert_eq!(frames[0].data, avc_config(SPS, PPS).unwrap());
+        assert!(frames[1].is_keyframe());
+        assert_eq!(frames[1].data, [&[0, 0, 0, 4][..], IDR].concat());
+
+        // the record is sent again once it changes only
+        assert_eq!(bitstream.convert(keyframe()).len(), 1);
+        let record = avc_config(&[0x67, 0x42, 0x00, 0x1e, 0xab], PPS).unwrap();
+        let data = [&record[..], &[0, 0, 0, 1], IDR].concat();
+        let changed = DataFrame::new(json!({ "type": "keyframe", "media": "video", "codec": "avc1.42001E" }), data).with_config_len(record.len());
+        let frames = bitstream.convert(changed);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].data, record);
+        assert_eq!(frames[0].metadata["codec"], "avc1.42001E");
+
+        // a config message received first is the one the client has
+        let mut bitstream = Bitstream::new(Format::Avcc);
+        assert_eq!(bitstream.convert(config()).len(), 1);
+        assert_eq!(bitstream.convert(keyframe()).len(), 1);
+    }
+
+    #[test]
+    fn annexb_clients_get_the_frames_without_the_record() {
+        let mut bitstream = Bitstream::new(Format::AnnexB);
+        let frames = bitstream.convert(keyframe());
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].data, [&[0, 0, 0, 1][..], IDR].concat());
+
+        // audio and frames without codec go through as they are
+        let audio = DataFrame::new(json!({ "media": "audio", "codec": "opus" }), vec![1, 2, 3]);
+        let untyped = DataFrame::new(json!({ "media": "video" }), vec![0, 0, 1, 0x41]).with_config_len(2);
+        for format in [Format::AnnexB, Format::Avcc] {
+            let mut bitstream = Bitstream::new(format);
+            assert_eq!(bitstream.convert(audio.clone())[0].data, audio.data);
+            assert_eq!(bitstream.convert(untyped.clone())[0].data, untyped.data);
+        }
+    }
+}
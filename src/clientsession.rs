@@ -0,0 +1,762 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use anyhow::{anyhow, Error};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::bitstream::Bitstream;
+use crate::dataframe::now_ms;
+use crate::events::Events;
+use crate::streamdef::DataFrame;
+
+/// Commands a client can send on its control channel, encoded as JSON text:
+///   {"cmd": "pause"}
+///   {"cmd": "resume"}
+///   {"cmd": "switch", "stream": "name"}
+///   {"cmd": "keyframe-only", "enabled": true}
+///   {"cmd": "stats"}
+///   {"cmd": "subscribe", "stream": "name"}
+///   {"cmd": "unsubscribe", "stream": "name"}
+///   {"cmd": "ping", "t": <client time in ms>}
+///   {"cmd": "latency", "ms": <measured glass-to-glass latency>}
+pub enum Command {
+    Pause,
+    Resume,
+    Switch(String),
+    KeyframeOnly(bool),
+    Stats,
+    Subscribe(String),
+    Unsubscribe(String),
+    /// Clock offset estimation: the reply echoes the client time along with
+    /// the server time.
+    Ping(f64),
+    /// Latency measured by the client; `stream` selects the subscription on
+    /// multiplexed sessions.
+    Latency { ms: f64, stream: Option<String> },
+}
+
+impl Command {
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let msg: serde_json::Value = serde_json::from_str(text)?;
+        let cmd = msg["cmd"].as_str().ok_or_else(|| anyhow!("missing field 'cmd'"))?;
+        match cmd {
+            "pause" => Ok(Command::Pause),
+            "resume" => Ok(Command::Resume),
+            "switch" => Ok(Command::Switch(stream_path(&msg)?)),
+            "keyframe-only" => Ok(Command::KeyframeOnly(msg["enabled"].as_bool().unwrap_or(true))),
+            "stats" => Ok(Command::Stats),
+            "subscribe" => Ok(Command::Subscribe(stream_path(&msg)?)),
+            "unsubscribe" => Ok(Command::Unsubscribe(stream_path(&msg)?)),
+            "ping" => Ok(Command::Ping(msg["t"].as_f64().unwrap_or_default())),
+            "latency" => {
+                let ms = msg["ms"].as_f64().ok_or_else(|| anyhow!("missing field 'ms'"))?;
+                let stream = stream_path(&msg).ok();
+                Ok(Command::Latency { ms, stream })
+            }
+            _ => Err(anyhow!("unknown command '{}'", cmd)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Pause => "pause",
+            Command::Resume => "resume",
+            Command::Switch(_) => "switch",
+            Command::KeyframeOnly(_) => "keyframe-only",
+            Command::Stats => "stats",
+            Command::Subscribe(_) => "subscribe",
+            Command::Unsubscribe(_) => "unsubscribe",
+            Command::Ping(_) => "ping",
+            Command::Latency { .. } => "latency",
+        }
+    }
+}
+
+/// Stream name of a command, accepting both "name" and "/name".
+fn stream_path(msg: &serde_json::Value) -> Result<String, Error> {
+    let stream = msg["stream"]
+        .as_str()
+        .ok_or_else(|| anyhow!("missing field 'stream'"))?;
+    Ok(format!("/{}", stream.trim_start_matches('/')))
+}
+
+/// Build the reply sent back to the client for a command.
+pub fn reply(cmd: &str, result: Result<serde_json::Value, Error>) -> serde_json::Value {
+    match result {
+        Ok(value) => {
+            let mut reply = json!({ "type": "reply", "cmd": cmd, "status": "ok" });
+            if !value.is_null() {
+                reply["result"] = value;
+            }
+            reply
+        }
+        Err(e) => json!({ "type": "reply", "cmd": cmd, "status": "error", "error": e.to_string() }),
+    }
+}
+
+/// Delivery level chosen for a client from the bytes waiting in its send queue.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quality {
+    Full,
+    /// Frames of the configured substream of the same camera.
+    Substream,
+    /// Keyframes only, of the substream when there is one.
+    KeyframeOnly,
+}
+
+impl Quality {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Quality::Full => "full",
+            Quality::Substream => "substream",
+            Quality::KeyframeOnly => "keyframe-only",
+        }
+    }
+
+    fn lower(self, has_substream: bool) -> Option<Quality> {
+        match self {
+            Quality::Full if has_substream => Some(Quality::Substream),
+            Quality::Full | Quality::Substream => Some(Quality::KeyframeOnly),
+            Quality::KeyframeOnly => None,
+        }
+    }
+
+    fn higher(self, has_substream: bool) -> Option<Quality> {
+        match self {
+            Quality::KeyframeOnly if has_substream => Some(Quality::Substream),
+            Quality::KeyframeOnly | Quality::Substream => Some(Quality::Full),
+            Quality::Full => None,
+        }
+    }
+}
+
+/// Minimum time between two quality reductions, so the queue can drain.
+const DEGRADE_DELAY: Duration = Duration::from_secs(2);
+/// Minimum time at a reduced quality before trying a better one.
+const RECOVER_DELAY: Duration = Duration::from_secs(10);
+/// Bytes waiting to be sent to a client above which its quality is reduced.
+const DEGRADE_BACKLOG: u64 = 1024 * 1024;
+/// Bytes waiting to be sent to a client below which its quality can be restored.
+const RECOVER_BACKLOG: u64 = 64 * 1024;
+
+/// Bytes written to a client transport and not yet sent on the wire,
+/// estimated from the bytes written and the bytes the transport reports as
+/// sent. The estimate grows while the writes outpace the connection and
+/// drains as the transport catches up; it never goes below zero, so the
+/// framing overhead counted by the transport does not build up over time.
+#[derive(Default)]
+pub struct SendQueue {
+    pending: u64,
+    sent: u64,
+}
+
+impl SendQueue {
+    /// Account for `written` more bytes given that the transport has sent
+    /// `sent` bytes in total, returning the bytes still pending.
+    pub fn update(&mut self, written: u64, sent: u64) -> u64 {
+        let drained = sent.saturating_sub(self.sent);
+        self.sent = self.sent.max(sent);
+        self.pending = (self.pending + written).saturating_sub(drained);
+        self.pending
+    }
+}
+
+/// Number of latency samples kept per client.
+const LATENCY_SAMPLES: usize = 256;
+
+/// Latency samples of the most recent frames, in milliseconds, with the sum
+/// and count of every sample recorded.
+#[derive(Default)]
+pub struct Latency {
+    samples: VecDeque<f64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Latency {
+    pub fn record(&mut self, ms: f64) {
+        if self.samples.len() == LATENCY_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(ms);
+        self.sum += ms;
+        self.count += 1;
+    }
+
+    /// Add the samples and totals of `other`.
+    pub fn merge(&mut self, other: &Latency) {
+        self.samples.extend(other.samples.iter().copied());
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+
+    /// 50th, 90th and 99th percentiles of the recorded samples.
+    pub fn percentiles(&self) -> Option<[f64; 3]> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<f64> = self.samples.iter().copied().collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let at = |p: f64| sorted[((sorted.len() - 1) as f64 * p).round() as usize];
+        Some([at(0.5), at(0.9), at(0.99)])
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        match self.percentiles() {
+            Some([p50, p90, p99]) => json!({ "p50": p50, "p90": p90, "p99": p99, "samples": self.samples.len() }),
+            None => serde_json::Value::Null,
+        }
+    }
+}
+
+/// Counters of a connected client, shared with the API.
+pub struct ClientStats {
+    pub transport: &'static str,
+    pub remote: String,
+    pub stream: String,
+    pub frames_sent: u64,
+    pub bytes_sent: u64,
+    pub frames_dropped: u64,
+    /// Time from reception from the camera to writing to this client.
+    pub server_latency: Latency,
+    /// Glass-to-glass latency reported by the client.
+    pub client_latency: Latency,
+    connected: Instant,
+}
+
+impl ClientStats {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "transport": self.transport,
+            "remote": self.remote,
+            "frames_sent": self.frames_sent,
+            "bytes_sent": self.bytes_sent,
+            "frames_dropped": self.frames_dropped,
+            "uptime": self.connected.elapsed().as_secs(),
+            "latency": {
+                "server": self.server_latency.to_json(),
+                "client": self.client_latency.to_json(),
+            },
+        })
+    }
+
+    /// Count a frame written to this client.
+    pub fn sent(&mut self, frame: &DataFrame) {
+        self.frames_sent += 1;
+        self.bytes_sent += frame.data.len() as u64;
+        if let Some(received) = frame.metadata["received"].as_f64() {
+            self.server_latency.record(now_ms() - received);
+        }
+    }
+}
+
+/// Counters of the clients of a stream on one transport, including the
+/// clients already gone, so that they only ever increase.
+#[derive(Default)]
+pub struct Totals {
+    pub frames_sent: u64,
+    pub bytes_sent: u64,
+    pub frames_dropped: u64,
+    /// Samples of the connected clients, sum and count of every client.
+    pub server_latency: Latency,
+    pub client_latency: Latency,
+}
+
+impl Totals {
+    fn add(&mut self, stats: &ClientStats) {
+        self.frames_sent += stats.frames_sent;
+        self.bytes_sent += stats.bytes_sent;
+        self.frames_dropped += stats.frames_dropped;
+        self.server_latency.merge(&stats.server_latency);
+        self.client_latency.merge(&stats.client_latency);
+    }
+
+    fn add_totals(&mut self, other: &Totals) {
+        self.frames_sent += other.frames_sent;
+        self.bytes_sent += other.bytes_sent;
+        self.frames_dropped += other.frames_dropped;
+        self.server_latency.merge(&other.server_latency);
+        self.client_latency.merge(&other.client_latency);
+    }
+}
+
+/// Registry of the connected clients, whose arrival and departure are
+/// emitted as events.
+#[derive(Clone)]
+pub struct Clients {
+    next_id: Arc<AtomicU64>,
+    clients: Arc<Mutex<HashMap<u64, Arc<Mutex<ClientStats>>>>>,
+    /// Totals of the clients gone, by stream and transport.
+    gone: Arc<Mutex<HashMap<(String, &'static str), Totals>>>,
+    events: Events,
+}
+
+impl Clients {
+    pub fn new(events: Events) -> Self {
+        Self { next_id: Arc::default(), clients: Arc::default(), gone: Arc::default(), events }
+    }
+
+    fn register(&self, stats: Arc<Mutex<ClientStats>>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        {
+            let stats = stats.lock().unwrap();
+            let event = json!({ "id": id, "stream": stats.stream, "transport": stats.transport, "remote": stats.remote });
+            self.events.emit("viewer_connected", event);
+        }
+        self.clients.lock().unwrap().insert(id, stats);
+        id
+    }
+
+    fn unregister(&self, id: u64) {
+        let Some(stats) = self.clients.lock().unwrap().remove(&id) else {
+            return;
+        };
+        let stats = stats.lock().unwrap();
+        {
+            let mut gone = self.gone.lock().unwrap();
+            let totals = gone.entry((stats.stream.clone(), stats.transport)).or_default();
+            totals.add(&stats);
+            // only the connected clients have recent samples
+            totals.server_latency.samples.clear();
+            totals.client_latency.samples.clear();
+        }
+        let event = json!({
+            "id": id,
+            "stream": stats.stream,
+            "transport": stats.transport,
+            "remote": stats.remote,
+            "frames_sent": stats.frames_sent,
+            "frames_dropped": stats.frames_dropped,
+            "uptime": stats.connected.elapsed().as_secs(),
+        });
+        self.events.emit("viewer_disconnected", event);
+    }
+
+    /// Stats of the clients currently receiving a stream.
+    pub fn of_stream(&self, stream: &str) -> Vec<(u64, Arc<Mutex<ClientStats>>)> {
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, stats)| stats.lock().unwrap().stream == stream)
+            .map(|(id, stats)| (*id, stats.clone()))
+            .collect()
+    }
+
+    /// Totals of the clients by stream and transport, sorted.
+    pub fn totals(&self) -> Vec<((String, &'static str), Totals)> {
+        let mut totals: HashMap<(String, &'static str), Totals> = HashMap::new();
+        for (key, gone) in self.gone.lock().unwrap().iter() {
+            totals.entry(key.clone()).or_default().add_totals(gone);
+        }
+        for stats in self.clients.lock().unwrap().values() {
+            let stats = stats.lock().unwrap();
+            totals.entry((stats.stream.clone(), stats.transport)).or_default().add(&stats);
+        }
+        let mut totals: Vec<_> = totals.into_iter().collect();
+        totals.sort_by(|(a, _), (b, _)| a.cmp(b));
+        totals
+    }
+}
+
+/// Per-connection delivery state. The counters live in [`ClientStats`],
+/// registered in [`Clients`] for the lifetime of the session.
+pub struct ClientSession {
+    pub paused: bool,
+    pub keyframe_only: bool,
+    pub quality: Quality,
+    quality_since: Instant,
+    /// Drop frames until the next keyframe, so the decoder never gets a delta
+    /// frame whose reference it has not seen.
+    pub wait_keyframe: bool,
+    pub stats: Arc<Mutex<ClientStats>>,
+    /// Conversion of the frames to the payload format requested by the client.
+    pub bitstream: Bitstream,
+    id: u64,
+    clients: Clients,
+}
+
+impl ClientSession {
+    pub fn new(clients: &Clients, transport: &'static str, remote: String, stream: &str) -> Self {
+        let stats = Arc::new(Mutex::new(ClientStats {
+            transport,
+            remote,
+            stream: stream.to_string(),
+            frames_sent: 0,
+            bytes_sent: 0,
+            frames_dropped: 0,
+            server_latency: Latency::default(),
+            client_latency: Latency::default(),
+            connected: Instant::now(),
+        }));
+        let id = clients.register(stats.clone());
+        Self {
+            paused: false,
+            keyframe_only: false,
+            quality: Quality::Full,
+            quality_since: Instant::now(),
+            wait_keyframe: true,
+            stats,
+            bitstream: Bitstream::default(),
+            id,
+            clients: clients.clone(),
+        }
+    }
+
+    pub fn frames_dropped(&self) -> u64 {
+        self.stats.lock().unwrap().frames_dropped
+    }
+
+    /// Record the stream delivered to this client.
+    pub fn set_stream(&self, stream: &str) {
+        self.stats.lock().unwrap().stream = stream.to_string();
+    }
+
+    /// Decide whether a frame should be delivered to this client, counting
+    /// it as dropped otherwise. The caller counts the delivered frames with
+    /// [`ClientSession::sent`] once written.
+    pub fn accept(&mut self, frame: &DataFrame) -> bool {
+        let keyframe = frame.is_keyframe();
+        if keyframe {
+            self.wait_keyframe = false;
+        }
+
+        // configuration changes are always delivered, the decoder needs them
+        // for the next keyframe whatever the delivery state
+        let keyframe_only = self.keyframe_only || self.quality == Quality::KeyframeOnly;
+        // only video frames depend on the previous ones, an audio-only
+        // stream never has a keyframe to wait for
+        let waiting = self.wait_keyframe && frame.metadata["media"] == "video";
+        let accepted = frame.is_config() || (!self.paused && !waiting && (keyframe || !keyframe_only));
+        if !accepted {
+            self.stats.lock().unwrap().frames_dropped += 1;
+        }
+        accepted
+    }
+
+    /// Count a frame once it has been written to the client, see
+    /// [`ClientStats::sent`].
+    pub fn sent(&self, frame: &DataFrame) {
+        self.stats.lock().unwrap().sent(frame);
+    }
+
+    /// Record frames lost because the client lagged behind the broadcast
+    /// channel and return the discontinuity event to send to it. Delta frames
+    /// are dropped until the next keyframe.
+    pub fn lagged(&mut self, n: u64) -> serde_json::Value {
+        self.stats.lock().unwrap().frames_dropped += n;
+        self.wait_keyframe = true;
+        json!({ "type": "discontinuity", "dropped": n })
+    }
+
+    /// Adapt the quality to the bytes `pending` in the send queue of this
+    /// client: reduce it when the queue goes over `DEGRADE_BACKLOG`, and
+    /// restore it once the queue has stayed nearly empty for a while. Returns
+    /// the new quality when it changes; the caller moves the client to the
+    /// substream or back when needed.
+    pub fn adapt(&mut self, pending: u64, has_substream: bool) -> Option<Quality> {
+        let elapsed = self.quality_since.elapsed();
+        let quality = if pending >= DEGRADE_BACKLOG && elapsed >= DEGRADE_DELAY {
+            self.quality.lower(has_substream)
+        } else if pending <= RECOVER_BACKLOG && elapsed >= RECOVER_DELAY {
+            self.quality.higher(has_substream)
+        } else {
+            None
+        }?;
+
+        self.wait_keyframe = true;
+        self.quality = quality;
+        self.quality_since = Instant::now();
+        Some(quality)
+    }
+
+    /// Whether frames should come from the substream rather than the stream.
+    pub fn on_substream(&self, has_substream: bool) -> bool {
+        has_substream && self.quality != Quality::Full
+    }
+
+    /// Event telling the client its current delivery quality.
+    pub fn quality_event(&self) -> serde_json::Value {
+        json!({
+            "type": "quality",
+            "quality": self.quality.as_str(),
+            "reduced": self.quality != Quality::Full,
+        })
+    }
+
+    /// Go back to full quality, e.g. when the client selects another stream.
+    pub fn reset_quality(&mut self) {
+        self.quality = Quality::Full;
+        self.quality_since = Instant::now();
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Resume delivery starting at the next keyframe.
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.wait_keyframe = true;
+    }
+
+    pub fn set_keyframe_only(&mut self, enabled: bool) {
+        if self.keyframe_only && !enabled {
+            self.wait_keyframe = true;
+        }
+        self.keyframe_only = enabled;
+    }
+
+    pub fn stats(&self) -> serde_json::Value {
+        let mut stats = self.stats.lock().unwrap().to_json();
+        stats["paused"] = self.paused.into();
+        stats["keyframe_only"] = self.keyframe_only.into();
+        stats["quality"] = self.quality.as_str().into();
+        stats
+    }
+}
+
+impl Drop for ClientSession {
+    fn drop(&mut self) {
+        self.clients.unregister(self.id);
+    }
+}
+
+/// Reply to a ping: the client time echoed with the server time, from which
+/// the client estimates the clock offset as `server - (t + rtt / 2)`.
+pub fn pong(t: f64) -> serde_json::Value {
+    json!({ "t": t, "server": now_ms() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streamdef::FrameSender;
+    use tokio::sync::broadcast::error::RecvError;
+
+    fn frame(kind: &str, media: &str) -> DataFrame {
+        DataFrame::new(json!({ "type": kind, "media": media }), vec![0; 10])
+    }
+
+    fn session() -> ClientSession {
+        ClientSession::new(&Clients::new(Events::default()), "websocket", "127.0.0.1:50000".to_string(), "/cam")
+    }
+
+    #[test]
+    fn commands_are_parsed_with_their_fields() {
+        assert!(matches!(Command::parse(r#"{"cmd": "pause"}"#), Ok(Command::Pause)));
+        assert!(matches!(Command::parse(r#"{"cmd": "resume"}"#), Ok(Command::Resume)));
+        assert!(matches!(Command::parse(r#"{"cmd": "stats"}"#), Ok(Command::Stats)));
+        // stream names are given with or without their leading slash
+        assert!(matches!(Command::parse(r#"{"cmd": "switch", "stream": "Norwich"}"#), Ok(Command::Switch(s)) if s == "/Norwich"));
+        assert!(matches!(Command::parse(r#"{"cmd": "subscribe", "stream": "/Norwich"}"#), Ok(Command::Subscribe(s)) if s == "/Norwich"));
+        assert!(matches!(Command::parse(r#"{"cmd": "unsubscribe", "stream": "Van"}"#), Ok(Command::Unsubscribe(s)) if s == "/Van"));
+        assert!(matches!(Command::parse(r#"{"cmd": "keyframe-only"}"#), Ok(Command::KeyframeOnly(true))));
+        assert!(matches!(Command::parse(r#"{"cmd": "keyframe-only", "enabled": false}"#), Ok(Command::KeyframeOnly(false))));
+        assert!(matches!(Command::parse(r#"{"cmd": "ping", "t": 1700000000000.5}"#), Ok(Command::Ping(t)) if t == 1_700_000_000_000.5));
+        assert!(matches!(Command::parse(r#"{"cmd": "ping"}"#), Ok(Command::Ping(t)) if t == 0.0));
+        assert!(matches!(
+            Command::parse(r#"{"cmd": "latency", "ms": 180}"#),
+            Ok(Command::Latency { ms, stream: None }) if ms == 180.0
+        ));
+        assert!(matches!(
+            Command::parse(r#"{"cmd": "latency", "ms": 180, "stream": "Van"}"#),
+            Ok(Command::Latency { stream: Some(s), .. }) if s == "/Van"
+        ));
+        for cmd in ["pause", "switch", "keyframe-only", "stats", "ping", "latency"] {
+            let text = json!({ "cmd": cmd, "stream": "x", "ms": 1 }).to_string();
+            assert_eq!(Command::parse(&text).unwrap().name(), cmd);
+        }
+
+        let error = |text: &str| Command::parse(text).err().unwrap().to_string();
+        assert_eq!(error(r#"{"cmd": "rewind"}"#), "unknown command 'rewind'");
+        assert_eq!(error(r#"{"stream": "Van"}"#), "missing field 'cmd'");
+        assert_eq!(error(r#"{"cmd": 1}"#), "missing field 'cmd'");
+        assert_eq!(error(r#"{"cmd": "switch"}"#), "missing field 'stream'");
+        assert_eq!(error(r#"{"cmd": "latency", "ms": "slow"}"#), "missing field 'ms'");
+        assert!(Command::parse(r#"{"cmd": "pause""#).is_err());
+        assert!(Command::parse("").is_err());
+    }
+
+    #[test]
+    fn replies_carry_the_result_or_the_error() {
+        assert_eq!(reply("pause", Ok(serde_json::Value::Null)), json!({ "type": "reply", "cmd": "pause", "status": "ok" }));
+        assert_eq!(
+            reply("switch", Ok(json!({ "stream": "/Van" }))),
+            json!({ "type": "reply", "cmd": "switch", "status": "ok", "result": { "stream": "/Van" } })
+        );
+        assert_eq!(
+            reply("unknown", Err(anyhow!("unknown command 'rewind'"))),
+            json!({ "type": "reply", "cmd": "unknown", "status": "error", "error": "unknown command 'rewind'" })
+        );
+        let pong = pong(12.5);
+        assert_eq!(pong["t"], 12.5);
+        assert!(pong["server"].as_f64().unwrap() > 1_600_000_000_000.0);
+    }
+
+    #[test]
+    fn pause_and_keyframe_only_commands_change_the_delivery() {
+        let mut session = session();
+        assert!(session.accept(&frame("keyframe", "video")));
+
+        session.pause();
+        assert!(!session.accept(&frame("delta", "video")));
+        assert!(!session.accept(&frame("keyframe", "video")));
+        assert!(session.accept(&frame("config", "video")));
+        assert_eq!(session.stats()["paused"], true);
+
+        // delivery resumes at the next keyframe
+        session.resume();
+        assert!(!session.accept(&frame("delta", "video")));
+        assert!(session.accept(&frame("keyframe", "video")));
+        assert!(session.accept(&frame("delta", "video")));
+
+        session.set_keyframe_only(true);
+        assert!(!session.accept(&frame("delta", "video")));
+        assert!(session.accept(&frame("keyframe", "video")));
+        assert_eq!(session.stats()["keyframe_only"], true);
+        session.set_keyframe_only(false);
+        assert!(!session.accept(&frame("delta", "video")));
+        assert!(session.accept(&frame("keyframe", "video")));
+
+        let stats = session.stats();
+        assert_eq!(stats["frames_dropped"], 5);
+        assert_eq!(stats["paused"], false);
+        assert_eq!(stats["quality"], "full");
+    }
+
+    #[tokio::test]
+    async fn lagged_clients_resume_at_the_next_keyframe() {
+        let tx = FrameSender::new(2);
+        let mut rx = tx.receiver();
+        let mut session = session();
+        for kind in ["keyframe", "delta", "delta", "delta", "delta"] {
+            assert!(tx.send(frame(kind, "video")).is_ok());
+        }
+        let Err(RecvError::Lagged(n)) = rx.recv().await else {
+            panic!("the receiver should have lagged");
+        };
+        assert_eq!(session.lagged(n), json!({ "type": "discontinuity", "dropped": 3 }));
+        assert_eq!(session.frames_dropped(), 3);
+
+        // the deltas left refer to the frames lost, configs and audio go through
+        for _ in 0..2 {
+            assert!(!session.accept(&rx.recv().await.unwrap()));
+        }
+        assert!(session.accept(&frame("config", "video")));
+        assert!(session.accept(&frame("audio", "audio")));
+        assert!(session.accept(&frame("keyframe", "video")));
+        assert!(session.accept(&frame("delta", "video")));
+        assert_eq!(session.frames_dropped(), 5);
+    }
+
+    /// Pretend the quality of `session` was set `ago`.
+    fn quality_set(session: &mut ClientSession, ago: Duration) {
+        session.quality_since = Instant::now() - ago;
+    }
+
+    #[test]
+    fn quality_follows_the_send_queue_after_its_delays() {
+        let mut session = session();
+        session.accept(&frame("keyframe", "video"));
+
+        // reduced only over the threshold, and not sooner than the delay
+        quality_set(&mut session, DEGRADE_DELAY);
+        assert_eq!(session.adapt(DEGRADE_BACKLOG - 1, true), None);
+        quality_set(&mut session, DEGRADE_DELAY - Duration::from_millis(100));
+        assert_eq!(session.adapt(DEGRADE_BACKLOG, true), None);
+        quality_set(&mut session, DEGRADE_DELAY);
+        assert_eq!(session.adapt(DEGRADE_BACKLOG, true), Some(Quality::Substream));
+        assert!(session.wait_keyframe);
+        assert!(session.on_substream(true));
+        assert_eq!(session.quality_event(), json!({ "type": "quality", "quality": "substream", "reduced": true }));
+        // the change restarts the delay
+        assert_eq!(session.adapt(DEGRADE_BACKLOG, true), None);
+        quality_set(&mut session, DEGRADE_DELAY);
+        assert_eq!(session.adapt(DEGRADE_BACKLOG, true), Some(Quality::KeyframeOnly));
+        quality_set(&mut session, DEGRADE_DELAY);
+        assert_eq!(session.adapt(DEGRADE_BACKLOG, true), None);
+
+        // keyframes only, of the substream
+        session.accept(&frame("keyframe", "video"));
+        assert!(!session.accept(&frame("delta", "video")));
+        assert!(session.on_substream(true));
+
+        // restored only under the threshold, after the longer delay
+        quality_set(&mut session, RECOVER_DELAY);
+        assert_eq!(session.adapt(RECOVER_BACKLOG + 1, true), None);
+        quality_set(&mut session, RECOVER_DELAY - Duration::from_millis(100));
+        assert_eq!(session.adapt(RECOVER_BACKLOG, true), None);
+        quality_set(&mut session, RECOVER_DELAY);
+        assert_eq!(session.adapt(RECOVER_BACKLOG, true), Some(Quality::Substream));
+        quality_set(&mut session, RECOVER_DELAY);
+        assert_eq!(session.adapt(0, true), Some(Quality::Full));
+        assert!(!session.on_substream(true));
+        assert_eq!(session.quality_event(), json!({ "type": "quality", "quality": "full", "reduced": false }));
+        quality_set(&mut session, RECOVER_DELAY);
+        assert_eq!(session.adapt(0, true), None);
+    }
+
+    #[test]
+    fn quality_skips_the_substream_when_there_is_none() {
+        let mut session = session();
+        quality_set(&mut session, DEGRADE_DELAY);
+        assert_eq!(session.adapt(DEGRADE_BACKLOG, false), Some(Quality::KeyframeOnly));
+        assert!(!session.on_substream(false));
+        assert_eq!(session.stats()["quality"], "keyframe-only");
+
+        quality_set(&mut session, RECOVER_DELAY);
+        assert_eq!(session.adapt(RECOVER_BACKLOG, false), Some(Quality::Full));
+
+        // a backlog between the thresholds keeps the quality
+        quality_set(&mut session, RECOVER_DELAY);
+        assert_eq!(session.adapt(RECOVER_BACKLOG + 1, false), None);
+        session.quality = Quality::KeyframeOnly;
+        assert_eq!(session.adapt(DEGRADE_BACKLOG - 1, false), None);
+        session.reset_quality();
+        assert_eq!(session.quality, Quality::Full);
+    }
+
+    #[test]
+    fn latency_percentiles_cover_the_most_recent_samples() {
+        let mut latency = Latency::default();
+        assert_eq!(latency.percentiles(), None);
+        assert_eq!(latency.to_json(), serde_json::Value::Null);
+        latency.record(40.0);
+        assert_eq!(latency.percentiles(), Some([40.0; 3]));
+
+        // the first 44 samples leave the window, the totals keep them
+        let mut latency = Latency::default();
+        for ms in 1..=300 {
+            latency.record(ms as f64);
+        }
+        assert_eq!(latency.percentiles(), Some([173.0, 275.0, 297.0]));
+        assert_eq!(latency.to_json(), json!({ "p50": 173.0, "p90": 275.0, "p99": 297.0, "samples": LATENCY_SAMPLES }));
+        assert_eq!((latency.sum, latency.count), (45150.0, 300));
+
+        // the samples are not recorded in order
+        let mut shuffled = Latency::default();
+        for ms in (1..=100).rev() {
+            shuffled.record(ms as f64);
+        }
+        assert_eq!(shuffled.percentiles(), Some([51.0, 90.0, 99.0]));
+
+        // totals merge the windows of their clients
+        let mut slow = Latency::default();
+        slow.record(1000.0);
+        latency.merge(&slow);
+        assert_eq!(latency.percentiles(), Some([173.0, 275.0, 298.0]));
+        assert_eq!(latency.to_json()["samples"], LATENCY_SAMPLES + 1);
+        assert_eq!((latency.sum, latency.count), (46150.0, 301));
+    }
+}
@@ -0,0 +1,635 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use actix_web::{get, http::header, web, HttpResponse};
+use anyhow::{anyhow, Error};
+use bytes::Bytes;
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+
+use crate::appcontext::AppContext;
+use crate::bitstream::{avc_config, nal_units, sps_dimensions};
+use crate::clientsession::ClientSession;
+use crate::dataframe::{iso8601, now_ms};
+use crate::fmp4::{self, Sample, Track};
+use crate::mpegts;
+use crate::streamdef::{DataFrame, StreamsDef, Viewer};
+
+/// Segments kept in memory for the players joining or lagging behind.
+const SEGMENTS: usize = 8;
+
+/// Time a packager keeps pulling its stream without requests.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time a request waits for the first segments of a packager, or for the
+/// segment it asks for to be started.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Latency the players aim at, in milliseconds.
+const TARGET_LATENCY: u32 = 2000;
+
+/// Gap between AAC frames, in milliseconds, above which the audio decode time
+/// follows the frame timestamps again.
+const AUDIO_RESYNC: f64 = 100.0;
+
+/// Samples of an AAC frame.
+const AAC_FRAME: u64 = 1024;
+
+struct Segment {
+    number: u64,
+    /// Decode time of the first sample, in the track timescale.
+    start: u64,
+    /// Set once the next segment has started.
+    duration: Option<u64>,
+    /// CMAF chunks, a `moof`/`mdat` fragment per frame.
+    chunks: Vec<Bytes>,
+}
+
+/// Initialization segment and the last segments of a track.
+struct Output {
+    track: Track,
+    codec: String,
+    init: Bytes,
+    segments: VecDeque<Segment>,
+    sequence: u32,
+}
+
+impl Output {
+    fn new(track: Track, codec: String) -> Self {
+        let init = track.init_segment().into();
+        Self { track, codec, init, segments: VecDeque::new(), sequence: 0 }
+    }
+
+    /// Complete the current segment and start segment `number` at `start`.
+    fn start_segment(&mut self, number: u64, start: u64) {
+        if let Some(segment) = self.segments.back_mut().filter(|segment| segment.duration.is_none()) {
+            segment.duration = Some(start.saturating_sub(segment.start));
+        }
+        self.segments.push_back(Segment { number, start, duration: None, chunks: vec![] });
+        while self.segments.len() > SEGMENTS {
+            self.segments.pop_front();
+        }
+    }
+
+    fn push_chunk(&mut self, decode_time: u64, samples: &[Sample]) {
+        self.sequence += 1;
+        let chunk = fmp4::fragment(self.sequence, decode_time, samples);
+        if let Some(segment) = self.segments.back_mut() {
+            segment.chunks.push(chunk.into());
+        }
+    }
+
+    fn segment(&self, number: u64) -> Option<&Segment> {
+        self.segments.iter().find(|segment| segment.number == number)
+    }
+
+    fn complete_segments(&self) -> impl Iterator<Item = (&Segment, u64)> {
+        self.segments.iter().filter_map(|segment| Some((segment, segment.duration?)))
+    }
+
+    /// Bit rate of the complete segments.
+    fn bandwidth(&self) -> u64 {
+        let (bytes, duration) = self.complete_segments().fold((0, 0), |(bytes, total), (segment, duration)| {
+            (bytes + segment.chunks.iter().map(|chunk| chunk.len() as u64).sum::<u64>(), total + duration)
+        });
+        match duration {
+            0 => 0,
+            _ => bytes * 8 * u64::from(self.track.timescale()) / duration,
+        }
+    }
+
+    /// Representation of the track in the manifest.
+    fn write_representation(&self, mpd: &mut String, id: u32, kind: &str) {
+        let timescale = self.track.timescale();
+        let max_duration = self.complete_segments().map(|(_, duration)| duration).max().unwrap_or_default();
+        let start_number = self.segments.front().map(|segment| segment.number).unwrap_or(1);
+        let (content_type, attributes) = match &self.track {
+            Track::Avc { width, height, .. } => ("video", format!(r#"width="{width}" height="{height}""#)),
+            Track::Aac { sample_rate, .. } => ("audio", format!(r#"audioSamplingRate="{sample_rate}""#)),
+        };
+        let _ = writeln!(
+            mpd,
+            r#"    <AdaptationSet id="{id}" contentType="{content_type}" mimeType="{content_type}/mp4" segmentAlignment="true" startWithSAP="1">"#
+        );
+        let _ = writeln!(
+            mpd,
+            r#"      <Representation id="{kind}" codecs="{}" bandwidth="{}" {attributes}>"#,
+            self.codec,
+            self.bandwidth()
+        );
+        if let Track::Aac { channels, .. } = &self.track {
+            let _ = writeln!(
+                mpd,
+                r#"        <AudioChannelConfiguration schemeIdUri="urn:mpeg:dash:23003:3:audio_channel_configuration:2011" value="{channels}"/>"#
+            );
+        }
+        // the segment being produced can be requested as soon as it starts,
+        // players only requesting the segments of the timeline it is listed
+        // with the duration of the previous one, corrected by the next updates
+        let _ = writeln!(
+            mpd,
+            r#"        <SegmentTemplate timescale="{timescale}" initialization="{kind}/init.mp4" media="{kind}/$Number$.m4s" startNumber="{start_number}" availabilityTimeOffset="{:.3}" availabilityTimeComplete="false">"#,
+            max_duration as f64 / f64::from(timescale)
+        );
+        let _ = writeln!(mpd, "          <SegmentTimeline>");
+        let mut previous = None;
+        for segment in &self.segments {
+            let Some(duration) = segment.duration.or(previous) else { continue };
+            let _ = writeln!(mpd, r#"            <S t="{}" d="{duration}"/>"#, segment.start);
+            previous = Some(duration);
+        }
+        let _ = writeln!(mpd, "          </SegmentTimeline>");
+        let _ = writeln!(mpd, "        </SegmentTemplate>");
+        let _ = writeln!(mpd, "      </Representation>");
+        let _ = writeln!(mpd, "    </AdaptationSet>");
+    }
+}
+
+fn duration(seconds: f64) -> String {
+    format!("PT{:.3}S", seconds)
+}
+
+#[derive(Default)]
+struct State {
+    video: Option<Output>,
+    audio: Option<Output>,
+    /// Wall clock time of the media time 0, in milliseconds since the Unix epoch.
+    availability_start: f64,
+    /// Codec the stream cannot be packaged with.
+    error: Option<String>,
+    last_request: Option<Instant>,
+}
+
+impl State {
+    fn output(&self, kind: &str) -> Option<&Output> {
+        match kind {
+            "video" => self.video.as_ref(),
+            "audio" => self.audio.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Manifest, once the first segments are complete. The audio track is
+    /// listed if it started within the first video segment.
+    fn manifest(&self) -> Option<String> {
+        let video = self.video.as_ref()?;
+        video.complete_segments().next()?;
+        if let Some(audio) = &self.audio {
+            audio.complete_segments().next()?;
+        }
+        let outputs = [Some(video), self.audio.as_ref()];
+        let depth = video.complete_segments().map(|(_, duration)| duration).sum::<u64>() as f64 / 90000.0;
+        let max_duration = video.complete_segments().map(|(_, duration)| duration).max().unwrap_or_default() as f64 / 90000.0;
+        let now = now_ms();
+
+        let mut mpd = String::new();
+        let _ = writeln!(mpd, r#"<?xml version="1.0" encoding="utf-8"?>"#);
+        let _ = writeln!(
+            mpd,
+            r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-live:2011" type="dynamic" availabilityStartTime="{}" publishTime="{}" minimumUpdatePeriod="PT1S" minBufferTime="PT1S" timeShiftBufferDepth="{}" maxSegmentDuration="{}">"#,
+            iso8601(self.availability_start),
+            iso8601(now),
+            duration(depth),
+            duration(max_duration)
+        );
+        let _ = writeln!(mpd, r#"  <ServiceDescription id="0">"#);
+        let _ = writeln!(mpd, r#"    <Latency target="{TARGET_LATENCY}" min="{}" max="{}"/>"#, TARGET_LATENCY / 2, TARGET_LATENCY * 3);
+        let _ = writeln!(mpd, r#"    <PlaybackRate min="0.96" max="1.04"/>"#);
+        let _ = writeln!(mpd, "  </ServiceDescription>");
+        let _ = writeln!(mpd, r#"  <Period id="0" start="PT0S">"#);
+        for (id, output) in outputs.into_iter().flatten().enumerate() {
+            let kind = if std::ptr::eq(output, video) { "video" } else { "audio" };
+            output.write_representation(&mut mpd, id as u32, kind);
+        }
+        let _ = writeln!(mpd, "  </Period>");
+        let _ = writeln!(mpd, r#"  <UTCTiming schemeIdUri="urn:mpeg:dash:utc:direct:2014" value="{}"/>"#, iso8601(now));
+        let _ = writeln!(mpd, "</MPD>");
+        Some(mpd)
+    }
+}
+
+/// Video frame waiting for the next one, which gives its duration.
+struct PendingFrame {
+    /// Decode time, in 90 kHz units.
+    time: u64,
+    keyframe: bool,
+    data: Vec<u8>,
+}
+
+/// Packaging of the frames of a stream into the segments served from memory,
+/// started by the first request for the stream.
+pub struct Packager {
+    state: Mutex<State>,
+    /// Bumped when a chunk is added, waking the requests waiting for it.
+    updated: watch::Sender<u64>,
+}
+
+impl Packager {
+    fn new() -> Self {
+        let (updated, _) = watch::channel(0);
+        Self { state: Mutex::new(State::default()), updated }
+    }
+
+    fn touch(&self) {
+        self.state.lock().unwrap().last_request = Some(Instant::now());
+    }
+
+    /// Wait for `ready` to give a value, until the timeout.
+    async fn wait_for<T>(&self, mut ready: impl FnMut(&State) -> Option<T>) -> Option<T> {
+        let mut updated = self.updated.subscribe();
+        let deadline = tokio::time::Instant::now() + WAIT_TIMEOUT;
+        loop {
+            if let Some(value) = ready(&self.state.lock().unwrap()) {
+                return Some(value);
+            }
+            tokio::time::timeout_at(deadline, updated.changed()).await.ok()?.ok()?;
+        }
+    }
+}
+
+/// State of the packaging of a stream's frames.
+#[derive(Default)]
+struct Packetizer {
+    pending: Option<PendingFrame>,
+    /// Number of the current video segment.
+    number: u64,
+    /// Decode time of the next AAC frame, in the audio timescale.
+    audio_time: Option<u64>,
+}
+
+impl Packetizer {
+    fn push(&mut self, state: &mut State, frame: &DataFrame) -> Result<(), Error> {
+        if frame.is_config() {
+            return Ok(());
+        }
+        let ts = frame.metadata["ts"].as_f64().unwrap_or_default().max(0.0);
+        let codec = frame.metadata["codec"].as_str().unwrap_or_default();
+        match frame.metadata["media"].as_str() {
+            Some("video") => {
+                if !codec.starts_with("avc1") {
+                    return Err(anyhow!("codec {} cannot be packaged for DASH", codec));
+                }
+                let keyframe = frame.is_keyframe();
+                let payload = frame.payload();
+                let nals = nal_units(&payload);
+                if keyframe {
+                    let sps = nals.iter().find(|nal| nal.first().is_some_and(|h| h & 0x1f == 7));
+                    let pps = nals.iter().find(|nal| nal.first().is_some_and(|h| h & 0x1f == 8));
+                    if let (Some(sps), Some(pps)) = (sps, pps) {
+                        let config = avc_config(sps, pps);
+                        let changed = match &state.video {
+                            Some(Output { track: Track::Avc { config: current, .. }, .. }) => Some(current) != config.as_ref(),
+                            _ => true,
+                        };
+                        if let (true, Some(config), Some((width, height))) = (changed, config, sps_dimensions(sps)) {
+                            let track = Track::Avc { width, height, config };
+                            let mut output = Output::new(track, codec.to_string());
+                            if let Some(previous) = state.video.take() {
+                                output.segments = previous.segments;
+                                output.sequence = previous.sequence;
+                            }
+                            state.video = Some(output);
+                        }
+                    }
+                }
+                let Some(video) = state.video.as_mut() else {
+                    return Ok(());
+                };
+                let time = (ts * 90.0) as u64;
+                if let Some(pending) = self.pending.take() {
+                    let sample = Sample {
+                        duration: time.saturating_sub(pending.time).max(1) as u32,
+                        keyframe: pending.keyframe,
+                        data: pending.data,
+                    };
+                    video.push_chunk(pending.time, &[sample]);
+                }
+                if keyframe {
+                    if self.number == 0 {
+                        state.availability_start = now_ms() - ts;
+                    }
+                    self.number += 1;
+                    video.start_segment(self.number, time);
+                }
+                if self.number == 0 {
+                    return Ok(());
+                }
+                let mut data = Vec::with_capacity(frame.data.len());
+                for nal in nals {
+                    if nal.first().is_none_or(|h| matches!(h & 0x1f, 7..=9)) {
+                        continue;
+                    }
+                    data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                    data.extend_from_slice(nal);
+                }
+                self.pending = Some(PendingFrame { time, keyframe, data });
+            }
+            // AAC frames are ADTS framed by retina
+            Some("audio") if self.number > 0 && codec.starts_with("mp4a.40.") => {
+                let Some(video_start) = state.video.as_ref().and_then(|video| video.segments.back()).map(|segment| segment.start) else {
+                    return Ok(());
+                };
+                for (i, aac) in mpegts::adts_frames(&frame.data).iter().enumerate() {
+                    let rate = u64::from(aac.sample_rate);
+                    let changed = match &state.audio {
+                        Some(Output { track: Track::Aac { config, .. }, .. }) => *config != aac.config,
+                        _ => true,
+                    };
+                    if changed {
+                        let track = Track::Aac { sample_rate: aac.sample_rate, channels: aac.channels, config: aac.config };
+                        let codec = format!("mp4a.40.{}", aac.config[0] >> 3);
+                        state.audio = Some(Output::new(track, codec));
+                        self.audio_time = None;
+                    }
+                    let Some(audio) = state.audio.as_mut() else { continue };
+                    let time = ((ts * rate as f64 / 1000.0) as u64) + i as u64 * AAC_FRAME;
+                    let time = match self.audio_time {
+                        Some(next) if next.abs_diff(time) as f64 * 1000.0 / (rate as f64) < AUDIO_RESYNC => next,
+                        _ => time,
+                    };
+                    // audio segments start with the first frame of each video segment
+                    let current = audio.segments.back().map(|segment| segment.number);
+                    if current.is_none_or(|number| number < self.number) && time * 90000 >= video_start * rate {
+                        audio.start_segment(self.number, time);
+                    }
+                    if audio.segments.is_empty() {
+                        continue;
+                    }
+                    let sample = Sample { duration: AAC_FRAME as u32, keyframe: true, data: aac.data.to_vec() };
+                    audio.push_chunk(time, &[sample]);
+                    self.audio_time = Some(time + AAC_FRAME);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Package the frames of the stream at `path` until it has no requests for
+/// [`IDLE_TIMEOUT`].
+async fn run(app_context: AppContext, path: String, stream_def: Arc<Mutex<StreamsDef>>, packager: Arc<Packager>) {
+    info!("DASH packaging of {} started", path);
+    // the packager counts as a viewer of the stream until it stops
+    let mut viewer = Viewer::new(stream_def, &path);
+    let mut client = ClientSession::new(&app_context.clients, "dash", "packager".to_string(), &path);
+    let mut packetizer = Packetizer::default();
+    let mut idle = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            frame = viewer.rx.recv() => match frame {
+                Ok(frame) => {
+                    if !client.accept(&frame) {
+                        continue;
+                    }
+                    let mut state = packager.state.lock().unwrap();
+                    if state.error.is_some() {
+                        continue;
+                    }
+                    if let Err(e) = packetizer.push(&mut state, &frame) {
+                        warn!("DASH packaging of {} failed: {}", path, e);
+                        state.error = Some(e.to_string());
+                    }
+                    drop(state);
+                    client.sent(&frame);
+                    packager.updated.send_modify(|n| *n += 1);
+                }
+                Err(RecvError::Lagged(n)) => {
+                    warn!("DASH packaging of {} lagged {} frames, waiting for next keyframe", path, n);
+                    client.lagged(n);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = idle.tick() => {
+                let last_request = packager.state.lock().unwrap().last_request;
+                if last_request.is_none_or(|last| last.elapsed() > IDLE_TIMEOUT) {
+                    break;
+                }
+            }
+        }
+    }
+    app_context.dash.remove(&path, &packager);
+    info!("DASH packaging of {} stopped", path);
+}
+
+/// Registry of the running packagers, by stream path.
+#[derive(Clone, Default)]
+pub struct Packagers(Arc<Mutex<HashMap<String, Arc<Packager>>>>);
+
+impl Packagers {
+    /// Packager of the stream at `path`, started if it is not running.
+    fn get(&self, app_context: &AppContext, path: &str) -> Option<Arc<Packager>> {
+        let mut packagers = self.0.lock().unwrap();
+        if let Some(packager) = packagers.get(path) {
+            packager.touch();
+            return Some(packager.clone());
+        }
+        let stream_def = app_context.stream(path)?;
+        let packager = Arc::new(Packager::new());
+        packager.touch();
+        packagers.insert(path.to_string(), packager.clone());
+        tokio::spawn(run(app_context.clone(), path.to_string(), stream_def, packager.clone()));
+        Some(packager)
+    }
+
+    fn remove(&self, path: &str, packager: &Arc<Packager>) {
+        let mut packagers = self.0.lock().unwrap();
+        if packagers.get(path).is_some_and(|current| Arc::ptr_eq(current, packager)) {
+            packagers.remove(path);
+        }
+    }
+}
+
+/// Response streaming the chunks of a segment as they are produced.
+fn segment_response(packager: Arc<Packager>, kind: String, number: u64) -> HttpResponse {
+    let updated = packager.updated.subscribe();
+    let body = futures::stream::unfold((packager, updated, 0), move |(packager, mut updated, sent)| {
+        let kind = kind.clone();
+        async move {
+            loop {
+                {
+                    let state = packager.state.lock().unwrap();
+                    let segment = state.output(&kind).and_then(|output| output.segment(number))?;
+                    if sent < segment.chunks.len() {
+                        let chunk = Bytes::from(segment.chunks[sent..].concat());
+                        let sent = segment.chunks.len();
+                        drop(state);
+                        return Some((Ok::<_, actix_web::Error>(chunk), (packager, updated, sent)));
+                    }
+                    if segment.duration.is_some() {
+                        return None;
+                    }
+                }
+                tokio::time::timeout(WAIT_TIMEOUT, updated.changed()).await.ok()?.ok()?;
+            }
+        }
+    });
+    HttpResponse::Ok().content_type("video/mp4").streaming(body)
+}
+
+#[utoipa::path(
+    get,
+    path = "/dash/{name}/{file}",
+    params(
+        ("name" = String, Path, description = "Stream to play"),
+        ("file" = String, Path, description = "manifest.mpd, {video|audio}/init.mp4 or {video|audio}/{number}.m4s")
+    ),
+    responses(
+        (status = 200, description = "DASH manifest, initialization segment or segment, the segment being produced sent with chunked transfer encoding"),
+        (status = 404, description = "Unknown stream or segment"),
+        (status = 415, description = "Stream codec cannot be packaged"),
+        (status = 503, description = "Stream not producing segments")
+    )
+)]
+#[get("/dash/{path:.*}")]
+pub async fn serve(path: web::Path<String>, data: web::Data<AppContext>) -> HttpResponse {
+    let app_context = data.get_ref();
+    let path = path.into_inner();
+    // stream names may contain slashes, the file is at the end of the path
+    let (name, kind, file) = match path.strip_suffix("/manifest.mpd") {
+        Some(name) => (name, "", "manifest.mpd"),
+        None => {
+            let mut parts = path.rsplitn(3, '/');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(file), Some(kind @ ("video" | "audio")), Some(name)) => (name, kind, file),
+                _ => return HttpResponse::NotFound().finish(),
+            }
+        }
+    };
+    let stream = format!("/{}", name.trim_matches('/'));
+    let Some(packager) = app_context.dash.get(app_context, &stream) else {
+        return HttpResponse::NotFound().body(format!("unknown stream {stream}"));
+    };
+    if let Some(error) = packager.state.lock().unwrap().error.clone() {
+        return HttpResponse::UnsupportedMediaType().body(error);
+    }
+
+    if file == "manifest.mpd" {
+        return match packager.wait_for(State::manifest).await {
+            Some(mpd) => HttpResponse::Ok()
+                .content_type("application/dash+xml")
+                .insert_header((header::CACHE_CONTROL, "no-cache"))
+                .body(mpd),
+            None => HttpResponse::ServiceUnavailable().body(format!("stream {stream} is not producing segments")),
+        };
+    }
+    if file == "init.mp4" {
+        return match packager.wait_for(|state| state.output(kind).map(|output| output.init.clone())).await {
+            Some(init) => HttpResponse::Ok().content_type("video/mp4").body(init),
+            None => HttpResponse::NotFound().finish(),
+        };
+    }
+    let Some(number) = file.strip_suffix(".m4s").and_then(|number| number.parse::<u64>().ok()) else {
+        return HttpResponse::NotFound().finish();
+    };
+    // segments are available from their start, the next one is waited for
+    let available = packager
+        .wait_for(|state| {
+            let output = state.output(kind)?;
+            let last = output.segments.back().map(|segment| segment.number).unwrap_or_default();
+            match output.segment(number) {
+                Some(segment) => Some(Some(segment.duration.map(|_| Bytes::from(segment.chunks.concat())))),
+                None if number > last + 1 || number < last => Some(None),
+                None => None,
+            }
+        })
+        .await
+        .flatten();
+    match available {
+        Some(Some(segment)) => HttpResponse::Ok().content_type("video/mp4").body(segment),
+        Some(None) => segment_response(packager, kind.to_string(), number),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SPS: &[u8] = &[0x67, 0x64, 0x00, 0x1e, 0xac, 0xd9, 0x40, 0xa0, 0x2f, 0xf9, 0x61, 0x00, 0x00, 0x03, 0x00, 0x01, 0x00, 0x00, 0x03, 0x00, 0x3c, 0x8f, 0x16, 0x2d, 0x96];
+    const PPS: &[u8] = &[0x68, 0xeb, 0xec, 0xb2, 0x2c];
+
+    /// Video frame as broadcast, the keyframes with the avcC record and the
+    /// parameter sets inserted by retina.
+    fn video(ts: f64, keyframe: bool) -> DataFrame {
+        let slice: &[u8] = if keyframe { &[0x65, 0x88, 0x84] } else { &[0x41, 0x9a] };
+        if !keyframe {
+            return DataFrame::new(json!({ "media": "video", "codec": "avc1.64001E", "ts": ts }), [&[0, 0, 0, 1], slice].concat());
+        }
+        let record = avc_config(SPS, PPS).unwrap();
+        let data = [&record[..], &[0, 0, 0, 1], SPS, &[0, 0, 0, 1], PPS, &[0, 0, 0, 1], slice].concat();
+        DataFrame::new(json!({ "type": "keyframe", "media": "video", "codec": "avc1.64001E", "ts": ts }), data).with_config_len(record.len())
+    }
+
+    /// AAC-LC frame at 48 kHz mono, ADTS framed.
+    fn audio(ts: f64) -> DataFrame {
+        let adts = [0xff, 0xf1, 0x4c, 0x40, 0x01, 0x3f, 0xfc, 0x21, 0x10];
+        DataFrame::new(json!({ "media": "audio", "codec": "mp4a.40.2", "ts": ts }), adts.to_vec())
+    }
+
+    /// State after two GOPs of 3 frames at 25 fps with 48 kHz audio, the
+    /// second one still running.
+    fn packaged() -> State {
+        let mut state = State::default();
+        let mut packetizer = Packetizer::default();
+        let mut frames: Vec<_> = (0..5).map(|i| video(i as f64 * 40.0, i % 3 == 0)).collect();
+        frames.extend((0..10).map(|i| audio(i as f64 * 1024.0 / 48.0)));
+        frames.sort_by(|a, b| a.metadata["ts"].as_f64().partial_cmp(&b.metadata["ts"].as_f64()).unwrap());
+        for frame in frames {
+            packetizer.push(&mut state, &frame).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn segments_are_gops_of_a_chunk_per_frame() {
+        let state = packaged();
+        let video = state.video.as_ref().unwrap();
+        assert_eq!(video.init, Track::Avc { width: 640, height: 360, config: avc_config(SPS, PPS).unwrap() }.init_segment());
+        let segments: Vec<_> = video.segments.iter().map(|s| (s.number, s.start, s.duration, s.chunks.len())).collect();
+        // the last frame waits for the next one to know its duration
+        assert_eq!(segments, [(1, 0, Some(10800), 3), (2, 10800, None, 1)]);
+
+        // the keyframe chunk, with only the IDR slice length-prefixed
+        let chunk = &video.segments[0].chunks[0];
+        let expected = fmp4::fragment(1, 0, &[Sample { duration: 3600, keyframe: true, data: vec![0, 0, 0, 3, 0x65, 0x88, 0x84] }]);
+        assert_eq!(chunk[..], expected);
+
+        let audio = state.audio.as_ref().unwrap();
+        let segments: Vec<_> = audio.segments.iter().map(|s| (s.number, s.start, s.duration, s.chunks.len())).collect();
+        // audio segments start with the first frame after the video ones
+        assert_eq!(segments, [(1, 0, Some(6144), 6), (2, 6144, None, 4)]);
+        assert_eq!(audio.segments[1].chunks[0][..], fmp4::fragment(7, 6144, &[Sample { duration: 1024, keyframe: true, data: vec![0x21, 0x10] }]));
+    }
+
+    #[test]
+    fn manifest_lists_the_running_segment() {
+        // nothing to play before the first segment is complete
+        let mut state = State::default();
+        Packetizer::default().push(&mut state, &video(0.0, true)).unwrap();
+        assert!(state.manifest().is_none());
+
+        let mpd = packaged().manifest().unwrap();
+        let video = &mpd[mpd.find(r#"contentType="video""#).unwrap()..mpd.find(r#"contentType="audio""#).unwrap()];
+        assert!(video.contains(r#"width="640" height="360""#));
+        assert!(video.contains(r#"timescale="90000" initialization="video/init.mp4" media="video/$Number$.m4s" startNumber="1" availabilityTimeOffset="0.120""#));
+        assert!(video.contains("<SegmentTimeline>\n            <S t=\"0\" d=\"10800\"/>\n            <S t=\"10800\" d=\"10800\"/>\n          </SegmentTimeline>"));
+        let audio = &mpd[mpd.find(r#"contentType="audio""#).unwrap()..];
+        assert!(audio.contains(r#"codecs="mp4a.40.2""#));
+        assert!(audio.contains(r#"timescale="48000""#));
+        assert!(audio.contains("<S t=\"0\" d=\"6144\"/>\n            <S t=\"6144\" d=\"6144\"/>\n"));
+        assert!(mpd.contains(r#"maxSegmentDuration="PT0.120S""#));
+    }
+}
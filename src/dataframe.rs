@@ -0,0 +1,178 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use bytes::Bytes;
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Current wall-clock time in milliseconds since the Unix epoch.
+pub fn now_ms() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64() * 1000.0)
+        .unwrap_or_default()
+}
+
+/// ISO 8601 UTC time of milliseconds since the Unix epoch.
+pub fn iso8601(ms: f64) -> String {
+    let ms = ms as i64;
+    let (days, ms_of_day) = (ms.div_euclid(86_400_000), ms.rem_euclid(86_400_000));
+    // civil date of a day count, from Howard Hinnant's algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        ms_of_day / 3_600_000,
+        ms_of_day / 60_000 % 60,
+        ms_of_day / 1000 % 60,
+        ms_of_day % 1000
+    )
+}
+
+/// A frame broadcast to every viewer of a stream.
+///
+/// Cloning is cheap: the payload and the serialized metadata are
+/// reference-counted and shared by all subscribers, and the metadata is
+/// serialized once when the frame is produced, the times of each client being
+/// written after it. So is the payload converted
+/// for the clients asking for another format.
+#[derive(Clone)]
+pub struct DataFrame {
+    pub metadata: Arc<serde_json::Value>,
+    pub json: Bytes,
+    pub data: Bytes,
+    /// Length of the decoder configuration record prepended to a keyframe.
+    config_len: usize,
+    avcc: Arc<OnceLock<Bytes>>,
+    /// Time the frame was handed to the viewers, see [`DataFrame::stamped`].
+    broadcast: Option<f64>,
+}
+
+impl DataFrame {
+    pub fn new(metadata: serde_json::Value, data: impl Into<Bytes>) -> Self {
+        let json = Bytes::from(serde_json::to_vec(&metadata).unwrap_or_default());
+        Self {
+            metadata: Arc::new(metadata),
+            json,
+            data: data.into(),
+            config_len: 0,
+            avcc: Arc::default(),
+            broadcast: None,
+        }
+    }
+
+    /// Same frame stamped with the current time as its broadcast time, when
+    /// it is handed to the viewers.
+    pub fn stamped(mut self) -> Self {
+        self.broadcast = Some(now_ms());
+        self
+    }
+
+    /// Mark the first `len` bytes of the payload as the decoder configuration
+    /// record.
+    pub fn with_config_len(mut self, len: usize) -> Self {
+        self.config_len = len.min(self.data.len());
+        self
+    }
+
+    /// Same frame with another payload.
+    pub fn with_data(&self, data: impl Into<Bytes>) -> Self {
+        Self {
+            metadata: self.metadata.clone(),
+            json: self.json.clone(),
+            data: data.into(),
+            config_len: 0,
+            avcc: Arc::default(),
+            broadcast: self.broadcast,
+        }
+    }
+
+    /// Serialized metadata written to one client right away, in two parts to
+    /// write one after the other: the metadata shared by every client without
+    /// its closing brace, then the `broadcast` and `sent` times of the client.
+    pub fn json_parts(&self) -> [Bytes; 2] {
+        let Some(fields) = self.json.strip_suffix(b"}") else {
+            return [self.json.clone(), Bytes::new()];
+        };
+        let mut times = String::new();
+        if let Some(broadcast) = self.broadcast {
+            times.push_str(&format!(",\"broadcast\":{broadcast}"));
+        }
+        times.push_str(&format!(",\"sent\":{}}}", now_ms()));
+        if fields.len() == 1 {
+            times.remove(0);
+        }
+        [self.json.slice(..fields.len()), times.into()]
+    }
+
+    /// Decoder configuration record prepended to a keyframe, empty otherwise.
+    pub fn config(&self) -> Bytes {
+        self.data.slice(..self.config_len)
+    }
+
+    /// Frame payload without the decoder configuration record.
+    pub fn payload(&self) -> Bytes {
+        self.data.slice(self.config_len..)
+    }
+
+    /// Payload converted to AVCC by `convert`, once for all the clients.
+    pub fn avcc_payload(&self, convert: impl FnOnce(&[u8]) -> Vec<u8>) -> Bytes {
+        self.avcc.get_or_init(|| convert(&self.payload()).into()).clone()
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        self.metadata["type"] == "keyframe"
+    }
+
+    /// Decoder configuration event, sent when the stream parameters change.
+    pub fn is_config(&self) -> bool {
+        self.metadata["type"] == "config"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joined(frame: &DataFrame) -> serde_json::Value {
+        let [json, times] = frame.json_parts();
+        serde_json::from_slice(&[json, times].concat()).unwrap()
+    }
+
+    #[test]
+    fn times_are_written_after_the_shared_metadata() {
+        let frame = DataFrame::new(serde_json::json!({ "ts": 40.0, "media": "video" }), vec![1, 2, 3]);
+        let metadata = joined(&frame);
+        assert_eq!(metadata["ts"], 40.0);
+        assert!(metadata.get("broadcast").is_none());
+        assert!(metadata["sent"].as_f64().is_some());
+
+        let before = now_ms();
+        let frame = frame.stamped();
+        let [json, _] = frame.json_parts();
+        assert_eq!(json.as_ptr(), frame.json.as_ptr());
+        let metadata = joined(&frame);
+        let broadcast = metadata["broadcast"].as_f64().unwrap();
+        assert!(broadcast >= before && broadcast <= metadata["sent"].as_f64().unwrap());
+        assert_eq!(joined(&frame.with_data(vec![4]))["broadcast"], broadcast);
+
+        let metadata = joined(&DataFrame::new(serde_json::json!({}), vec![]));
+        assert_eq!(metadata.as_object().unwrap().len(), 1);
+    }
+}
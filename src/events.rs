@@ -0,0 +1,86 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use actix_web::{get, http::header, web, HttpResponse};
+use bytes::Bytes;
+use log::debug;
+use serde_json::json;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::appcontext::AppContext;
+use crate::dataframe::now_ms;
+
+/// Events buffered for the subscribers, those lagging further behind are told
+/// how many they missed.
+const CAPACITY: usize = 256;
+
+/// Interval of the comments keeping idle SSE connections open through proxies.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Bus of the stream and server events, serialized once as JSON objects with
+/// their `type` and `time` in milliseconds since the Unix epoch.
+#[derive(Clone)]
+pub struct Events(broadcast::Sender<Bytes>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+}
+
+impl Events {
+    pub fn emit(&self, kind: &str, mut event: serde_json::Value) {
+        event["type"] = kind.into();
+        event["time"] = now_ms().into();
+        debug!("event {}", event);
+        // nobody may be listening
+        let _ = self.0.send(Bytes::from(serde_json::to_vec(&event).unwrap_or_default()));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Bytes> {
+        self.0.subscribe()
+    }
+}
+
+/// Server-Sent Event of a serialized event.
+fn message(event: &[u8]) -> Bytes {
+    let mut message = b"data: ".to_vec();
+    message.extend_from_slice(event);
+    message.extend_from_slice(b"\n\n");
+    message.into()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/events",
+    responses(
+        (status = 200, description = "Server-Sent Events, each a JSON object with its `type`: stream_connecting, stream_playing, stream_error, stream_stopped, viewer_connected, viewer_disconnected, config_reloaded, config_reload_failed, recording_started, recording_stopped, recording_failed", content_type = "text/event-stream")
+    )
+)]
+#[get("/api/events")]
+pub async fn events(data: web::Data<AppContext>) -> HttpResponse {
+    let rx = data.events.subscribe();
+    let body = futures::stream::unfold(rx, |mut rx| async move {
+        let chunk = match tokio::time::timeout(KEEPALIVE, rx.recv()).await {
+            Ok(Ok(event)) => message(&event),
+            Ok(Err(RecvError::Lagged(n))) => {
+                let event = json!({ "type": "lagged", "time": now_ms(), "dropped": n });
+                message(&serde_json::to_vec(&event).unwrap_or_default())
+            }
+            Ok(Err(RecvError::Closed)) => return None,
+            Err(_) => Bytes::from_static(b": keepalive\n\n"),
+        };
+        Some((Ok::<_, actix_web::Error>(chunk), rx))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(body)
+}
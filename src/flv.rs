@@ -0,0 +1,157 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use anyhow::Error;
+use bytes::Bytes;
+use std::fmt;
+
+use crate::bitstream::{avc_config, nal_units};
+use crate::mpegts;
+use crate::streamdef::DataFrame;
+
+pub const TAG_AUDIO: u8 = 8;
+pub const TAG_VIDEO: u8 = 9;
+
+/// FLV file header announcing video, and audio when the stream has some,
+/// followed by the size of the absent previous tag.
+pub fn header(audio: bool) -> [u8; 13] {
+    let flags = if audio { 0x05 } else { 0x01 };
+    [b'F', b'L', b'V', 1, flags, 0, 0, 0, 9, 0, 0, 0, 0]
+}
+
+/// FLV video codec id of H.264.
+pub const CODEC_AVC: u8 = 7;
+/// FLV sound format of AAC.
+pub const SOUND_FORMAT_AAC: u8 = 10;
+
+/// FLV audio tag header of AAC, whose actual format is given by its
+/// AudioSpecificConfig.
+const AAC_TAG: u8 = SOUND_FORMAT_AAC << 4 | 0x0f;
+
+/// Video codec that FLV cannot carry, reported by [`Remuxer::remux`] from
+/// its configuration.
+#[derive(Debug)]
+pub struct UnsupportedCodec(pub String);
+
+impl fmt::Display for UnsupportedCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "codec {} cannot be carried in FLV", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedCodec {}
+
+/// Conversion of the broadcast frames to FLV video and audio tags. Nothing
+/// is sent before the first keyframe.
+#[derive(Default)]
+pub struct Remuxer {
+    started: bool,
+    /// AVCDecoderConfigurationRecord last sent.
+    avc_config: Option<Vec<u8>>,
+    /// AudioSpecificConfig last sent.
+    aac_config: Option<[u8; 2]>,
+    /// Whether an AAC configuration was received, the stream having audio.
+    audio: bool,
+}
+
+pub struct Tag {
+    pub tag_type: u8,
+    pub data: Bytes,
+    /// Time in milliseconds.
+    pub ts: u32,
+}
+
+impl Tag {
+    /// Write the tag followed by its size, as in an FLV file.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.tag_type);
+        out.extend_from_slice(&(self.data.len() as u32).to_be_bytes()[1..]);
+        out.extend_from_slice(&self.ts.to_be_bytes()[1..]);
+        out.push((self.ts >> 24) as u8);
+        out.extend_from_slice(&[0, 0, 0]);
+        out.extend_from_slice(&self.data);
+        out.extend_from_slice(&(self.data.len() as u32 + 11).to_be_bytes());
+    }
+}
+
+impl Remuxer {
+    /// Tags of a frame, an error for codecs that FLV cannot carry.
+    pub fn remux(&mut self, frame: &DataFrame) -> Result<Vec<Tag>, Error> {
+        let mut tags = vec![];
+        let codec = frame.metadata["codec"].as_str().unwrap_or_default();
+        if frame.metadata["media"] == "video" && !codec.starts_with("avc1") {
+            return Err(UnsupportedCodec(codec.to_string()).into());
+        }
+        if frame.is_config() {
+            self.audio |= frame.metadata["media"] == "audio" && codec.starts_with("mp4a.40.");
+            return Ok(tags);
+        }
+        let ts = frame.metadata["ts"].as_f64().unwrap_or_default().max(0.0);
+        match frame.metadata["media"].as_str() {
+            Some("video") => {
+                let keyframe = frame.is_keyframe();
+                if !self.started && !keyframe {
+                    return Ok(tags);
+                }
+                // the parameter sets are inserted by retina before the
+                // keyframe, after the prepended configuration record
+                let payload = frame.payload();
+                let nals = nal_units(&payload);
+                if keyframe {
+                    let sps = nals.iter().find(|nal| nal.first().is_some_and(|h| h & 0x1f == 7));
+                    let pps = nals.iter().find(|nal| nal.first().is_some_and(|h| h & 0x1f == 8));
+                    let config = sps.zip(pps).and_then(|(sps, pps)| avc_config(sps, pps));
+                    if config.is_some() && config != self.avc_config {
+                        let mut tag = vec![0x17, 0, 0, 0, 0];
+                        tag.extend(config.iter().flatten());
+                        tags.push(Tag { tag_type: TAG_VIDEO, data: tag.into(), ts: ts as u32 });
+                        self.avc_config = config;
+                    }
+                    if self.avc_config.is_none() {
+                        return Ok(tags);
+                    }
+                    self.started = true;
+                }
+                let mut tag = vec![if keyframe { 0x17 } else { 0x27 }, 1, 0, 0, 0];
+                for nal in nals {
+                    if nal.first().is_none_or(|h| matches!(h & 0x1f, 7..=9)) {
+                        continue;
+                    }
+                    tag.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                    tag.extend_from_slice(nal);
+                }
+                tags.push(Tag { tag_type: TAG_VIDEO, data: tag.into(), ts: ts as u32 });
+            }
+            // AAC frames are ADTS framed by retina
+            Some("audio") if self.started && codec.starts_with("mp4a.40.") => {
+                let mut ts = ts;
+                for aac in mpegts::adts_frames(&frame.data) {
+                    if self.aac_config != Some(aac.config) {
+                        let mut tag = vec![AAC_TAG, 0];
+                        tag.extend_from_slice(&aac.config);
+                        tags.push(Tag { tag_type: TAG_AUDIO, data: tag.into(), ts: ts as u32 });
+                        self.aac_config = Some(aac.config);
+                    }
+                    let mut tag = vec![AAC_TAG, 1];
+                    tag.extend_from_slice(aac.data);
+                    tags.push(Tag { tag_type: TAG_AUDIO, data: tag.into(), ts: ts as u32 });
+                    ts += 1024.0 * 1000.0 / f64::from(aac.sample_rate);
+                }
+            }
+            _ => {}
+        }
+        Ok(tags)
+    }
+
+    /// Whether the stream has audio, known from the configurations received
+    /// before its first keyframe.
+    pub fn has_audio(&self) -> bool {
+        self.audio
+    }
+}
@@ -0,0 +1,333 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+/// Identifier of the track in the initialization segment and fragments.
+const TRACK_ID: u32 = 1;
+
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// Sample flags of a sync sample, and of a sample depending on others.
+const SYNC_SAMPLE: u32 = 0x0200_0000;
+const NON_SYNC_SAMPLE: u32 = 0x0101_0000;
+
+/// Track of a fragmented MP4 (CMAF) stream, described by its initialization
+/// segment and carried by `moof`/`mdat` fragments.
+pub enum Track {
+    /// H.264 video, with its AVCDecoderConfigurationRecord.
+    Avc { width: u32, height: u32, config: Vec<u8> },
+    /// AAC audio, with its AudioSpecificConfig.
+    Aac { sample_rate: u32, channels: u16, config: [u8; 2] },
+}
+
+pub struct Sample {
+    pub duration: u32,
+    pub keyframe: bool,
+    pub data: Vec<u8>,
+}
+
+/// Append a box whose content is written by `content`.
+fn write_box(out: &mut Vec<u8>, name: &[u8; 4], content: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0, 0, 0, 0]);
+    out.extend_from_slice(name);
+    content(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box(out: &mut Vec<u8>, name: &[u8; 4], version: u8, flags: u32, content: impl FnOnce(&mut Vec<u8>)) {
+    write_box(out, name, |out| {
+        out.extend_from_slice(&(u32::from(version) << 24 | flags).to_be_bytes());
+        content(out);
+    });
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+/// MPEG-4 descriptor with a single byte length.
+fn write_descriptor(out: &mut Vec<u8>, tag: u8, content: impl FnOnce(&mut Vec<u8>)) {
+    let mut body = vec![];
+    content(&mut body);
+    out.push(tag);
+    out.push(body.len() as u8);
+    out.extend_from_slice(&body);
+}
+
+impl Track {
+    pub fn timescale(&self) -> u32 {
+        match self {
+            Track::Avc { .. } => 90000,
+            Track::Aac { sample_rate, .. } => *sample_rate,
+        }
+    }
+
+    fn write_sample_entry(&self, out: &mut Vec<u8>) {
+        match self {
+            Track::Avc { width, height, config } => write_box(out, b"avc1", |out| {
+                out.extend_from_slice(&[0; 6]);
+                put_u16(out, 1);
+                out.extend_from_slice(&[0; 16]);
+                put_u16(out, *width as u16);
+                put_u16(out, *height as u16);
+                put_u32(out, 0x0048_0000);
+                put_u32(out, 0x0048_0000);
+                put_u32(out, 0);
+                put_u16(out, 1);
+                out.extend_from_slice(&[0; 32]);
+                put_u16(out, 0x18);
+                put_u16(out, 0xffff);
+                write_box(out, b"avcC", |out| out.extend_from_slice(config));
+            }),
+            Track::Aac { sample_rate, channels, config } => write_box(out, b"mp4a", |out| {
+                out.extend_from_slice(&[0; 6]);
+                put_u16(out, 1);
+                out.extend_from_slice(&[0; 8]);
+                put_u16(out, *channels);
+                put_u16(out, 16);
+                put_u32(out, 0);
+                put_u32(out, sample_rate.min(&0xffff) << 16);
+                write_full_box(out, b"esds", 0, 0, |out| {
+                    write_descriptor(out, 0x03, |out| {
+                        put_u16(out, TRACK_ID as u16);
+                        out.push(0);
+                        write_descriptor(out, 0x04, |out| {
+                            // MPEG-4 audio, audio stream
+                            out.extend_from_slice(&[0x40, 0x15, 0, 0, 0]);
+                            put_u32(out, 0);
+                            put_u32(out, 0);
+                            write_descriptor(out, 0x05, |out| out.extend_from_slice(config));
+                        });
+                        write_descriptor(out, 0x06, |out| out.push(0x02));
+                    });
+                });
+            }),
+        }
+    }
+
+    /// Initialization segment: `ftyp` and a `moov` without samples.
+    pub fn init_segment(&self) -> Vec<u8> {
+        let video = matches!(self, Track::Avc { .. });
+        let mut out = vec![];
+        write_box(&mut out, b"ftyp", |out| {
+            out.extend_from_slice(b"iso6");
+            put_u32(out, 0);
+            out.extend_from_slice(b"iso6cmfcdashmp41");
+        });
+        write_box(&mut out, b"moov", |out| {
+            write_full_box(out, b"mvhd", 0, 0, |out| {
+                put_u32(out, 0);
+                put_u32(out, 0);
+                put_u32(out, 1000);
+                put_u32(out, 0);
+                put_u32(out, 0x0001_0000);
+                put_u16(out, 0x0100);
+                out.extend_from_slice(&[0; 10]);
+                MATRIX.iter().for_each(|value| put_u32(out, *value));
+                out.extend_from_slice(&[0; 24]);
+                put_u32(out, TRACK_ID + 1);
+            });
+            write_box(out, b"trak", |out| {
+                write_full_box(out, b"tkhd", 0, 0x3, |out| {
+                    put_u32(out, 0);
+                    put_u32(out, 0);
+                    put_u32(out, TRACK_ID);
+                    put_u32(out, 0);
+                    put_u32(out, 0);
+                    out.extend_from_slice(&[0; 8]);
+                    put_u16(out, 0);
+                    put_u16(out, 0);
+                    put_u16(out, if video { 0 } else { 0x0100 });
+                    put_u16(out, 0);
+                    MATRIX.iter().for_each(|value| put_u32(out, *value));
+                    let (width, height) = match self {
+                        Track::Avc { width, height, .. } => (*width, *height),
+                        Track::Aac { .. } => (0, 0),
+                    };
+                    put_u32(out, width << 16);
+                    put_u32(out, height << 16);
+                });
+                write_box(out, b"mdia", |out| {
+                    write_full_box(out, b"mdhd", 0, 0, |out| {
+                        put_u32(out, 0);
+                        put_u32(out, 0);
+                        put_u32(out, self.timescale());
+                        put_u32(out, 0);
+                        // undetermined language
+                        put_u16(out, 0x55c4);
+                        put_u16(out, 0);
+                    });
+                    write_full_box(out, b"hdlr", 0, 0, |out| {
+                        put_u32(out, 0);
+                        out.extend_from_slice(if video { b"vide" } else { b"soun" });
+                        out.extend_from_slice(&[0; 12]);
+                        out.extend_from_slice(if video { b"VideoHandler\0" } else { b"SoundHandler\0" });
+                    });
+                    write_box(out, b"minf", |out| {
+                        if video {
+                            write_full_box(out, b"vmhd", 0, 1, |out| out.extend_from_slice(&[0; 8]));
+                        } else {
+                            write_full_box(out, b"smhd", 0, 0, |out| put_u32(out, 0));
+                        }
+                        write_box(out, b"dinf", |out| {
+                            write_full_box(out, b"dref", 0, 0, |out| {
+                                put_u32(out, 1);
+                                write_full_box(out, b"url ", 0, 1, |_| {});
+                            });
+                        });
+                        write_box(out, b"stbl", |out| {
+                            write_full_box(out, b"stsd", 0, 0, |out| {
+                                put_u32(out, 1);
+                                self.write_sample_entry(out);
+                            });
+                            write_full_box(out, b"stts", 0, 0, |out| put_u32(out, 0));
+                            write_full_box(out, b"stsc", 0, 0, |out| put_u32(out, 0));
+                            write_full_box(out, b"stsz", 0, 0, |out| out.extend_from_slice(&[0; 8]));
+                            write_full_box(out, b"stco", 0, 0, |out| put_u32(out, 0));
+                        });
+                    });
+                });
+            });
+            write_box(out, b"mvex", |out| {
+                write_full_box(out, b"trex", 0, 0, |out| {
+                    put_u32(out, TRACK_ID);
+                    put_u32(out, 1);
+                    put_u32(out, 0);
+                    put_u32(out, 0);
+                    put_u32(out, 0);
+                });
+            });
+        });
+        out
+    }
+}
+
+/// Fragment of `samples` starting at `decode_time`, in the track timescale.
+pub fn fragment(sequence: u32, decode_time: u64, samples: &[Sample]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut data_offset = 0;
+    write_box(&mut out, b"moof", |out| {
+        write_full_box(out, b"mfhd", 0, 0, |out| put_u32(out, sequence));
+        write_box(out, b"traf", |out| {
+            // default-base-is-moof
+            write_full_box(out, b"tfhd", 0, 0x02_0000, |out| put_u32(out, TRACK_ID));
+            write_full_box(out, b"tfdt", 1, 0, |out| out.extend_from_slice(&decode_time.to_be_bytes()));
+            // data offset, sample duration, size and flags
+            write_full_box(out, b"trun", 0, 0x701, |out| {
+                put_u32(out, samples.len() as u32);
+                data_offset = out.len();
+                put_u32(out, 0);
+                for sample in samples {
+                    put_u32(out, sample.duration);
+                    put_u32(out, sample.data.len() as u32);
+                    put_u32(out, if sample.keyframe { SYNC_SAMPLE } else { NON_SYNC_SAMPLE });
+                }
+            });
+        });
+    });
+    // the samples follow the mdat header
+    let offset = out.len() as u32 + 8;
+    out[data_offset..data_offset + 4].copy_from_slice(&offset.to_be_bytes());
+    write_box(&mut out, b"mdat", |out| {
+        for sample in samples {
+            out.extend_from_slice(&sample.data);
+        }
+    });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Name and content of the boxes of `data`, which they fill exactly.
+    fn boxes(mut data: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut boxes = vec![];
+        while !data.is_empty() {
+            let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            assert!(size >= 8 && size <= data.len(), "box size {size} of {} bytes", data.len());
+            boxes.push((&data[4..8], &data[8..size]));
+            data = &data[size..];
+        }
+        boxes
+    }
+
+    fn names<'a>(boxes: &[(&'a [u8], &[u8])]) -> Vec<&'a [u8]> {
+        boxes.iter().map(|(name, _)| *name).collect()
+    }
+
+    /// Content of the box at `path` of `data`.
+    fn find<'a>(data: &'a [u8], path: &[&[u8]]) -> &'a [u8] {
+        path.iter().fold(data, |data, name| {
+            let (_, content) = boxes(data).into_iter().find(|(box_name, _)| box_name == name).unwrap();
+            content
+        })
+    }
+
+    #[test]
+    fn init_segment_boxes_nest_with_their_sizes() {
+        let config = vec![0x01, 0x42, 0x00, 0x1e, 0xff, 0xe1, 0x00, 0x02, 0x67, 0x42, 0x01, 0x00, 0x01, 0x68];
+        let init = Track::Avc { width: 640, height: 360, config: config.clone() }.init_segment();
+        assert_eq!(names(&boxes(&init)), [b"ftyp", b"moov"]);
+        assert_eq!(names(&boxes(find(&init, &[b"moov"]))), [b"mvhd", b"trak", b"mvex"]);
+        assert_eq!(names(&boxes(find(&init, &[b"moov", b"trak"]))), [b"tkhd", b"mdia"]);
+        let stbl = find(&init, &[b"moov", b"trak", b"mdia", b"minf", b"stbl"]);
+        assert_eq!(names(&boxes(stbl)), [b"stsd", b"stts", b"stsc", b"stsz", b"stco"]);
+        // the sample entry follows the version, flags and entry count
+        let avc1 = find(&find(stbl, &[b"stsd"])[8..], &[b"avc1"]);
+        assert_eq!(avc1[24..28], [0x02, 0x80, 0x01, 0x68]);
+        assert_eq!(find(&avc1[78..], &[b"avcC"]), config);
+        let mdhd = find(&init, &[b"moov", b"trak", b"mdia", b"mdhd"]);
+        assert_eq!(mdhd[12..16], 90000u32.to_be_bytes());
+        let tkhd = find(&init, &[b"moov", b"trak", b"tkhd"]);
+        assert_eq!(tkhd[76..84], [0x02, 0x80, 0, 0, 0x01, 0x68, 0, 0]);
+
+        let init = Track::Aac { sample_rate: 44100, channels: 2, config: [0x12, 0x10] }.init_segment();
+        let stsd = find(&init, &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"]);
+        let mp4a = find(&stsd[8..], &[b"mp4a"]);
+        assert_eq!(mp4a[16..18], 2u16.to_be_bytes());
+        assert_eq!(mp4a[24..26], 44100u16.to_be_bytes());
+        let esds = find(&mp4a[28..], &[b"esds"]);
+        // ES descriptor, holding the decoder configuration ending with the AudioSpecificConfig
+        assert_eq!(esds[4..6], [0x03, esds.len() as u8 - 6]);
+        assert!(esds.windows(4).any(|w| w == [0x05, 2, 0x12, 0x10]));
+        assert!(names(&boxes(find(&init, &[b"moov", b"trak", b"mdia", b"minf"]))).contains(&&b"smhd"[..]));
+    }
+
+    #[test]
+    fn fragment_data_offset_points_at_the_samples() {
+        let samples = [
+            Sample { duration: 3600, keyframe: true, data: vec![0, 0, 0, 2, 0x65, 0x88] },
+            Sample { duration: 3000, keyframe: false, data: vec![0, 0, 0, 1, 0x41] },
+        ];
+        let fragment = fragment(7, 1 << 33, &samples);
+        let top = boxes(&fragment);
+        assert_eq!(names(&top), [b"moof", b"mdat"]);
+        assert_eq!(top[1].1, [&samples[0].data[..], &samples[1].data].concat());
+
+        assert_eq!(find(&fragment, &[b"moof", b"mfhd"])[4..], 7u32.to_be_bytes());
+        let tfdt = find(&fragment, &[b"moof", b"traf", b"tfdt"]);
+        assert_eq!(tfdt[0], 1);
+        assert_eq!(tfdt[4..], (1u64 << 33).to_be_bytes());
+        let trun = find(&fragment, &[b"moof", b"traf", b"trun"]);
+        assert_eq!(trun[..4], 0x701u32.to_be_bytes());
+        assert_eq!(trun[4..8], 2u32.to_be_bytes());
+        // the offset is relative to the start of the moof
+        let offset = u32::from_be_bytes(trun[8..12].try_into().unwrap()) as usize;
+        assert_eq!(offset, top[0].1.len() + 8 + 8);
+        assert_eq!(fragment[offset..offset + 6], samples[0].data);
+        let entries: Vec<u32> = trun[12..].chunks(4).map(|v| u32::from_be_bytes(v.try_into().unwrap())).collect();
+        assert_eq!(entries, [3600, 6, SYNC_SAMPLE, 3000, 5, NON_SYNC_SAMPLE]);
+    }
+}
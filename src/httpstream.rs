@@ -0,0 +1,283 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use actix_web::{get, http::header, web, HttpRequest, HttpResponse};
+use anyhow::Error;
+use bytes::Bytes;
+use futures::StreamExt;
+use log::{debug, info, warn};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::appcontext::AppContext;
+use crate::clientsession::ClientSession;
+use crate::flv;
+use crate::mpegts;
+use crate::streamdef::{DataFrame, Viewer};
+
+enum Remuxer {
+    /// FLV tags, after the file header written with the first of them.
+    Flv { remuxer: flv::Remuxer, header_sent: bool },
+    Ts(mpegts::Remuxer),
+}
+
+impl Remuxer {
+    fn remux(&mut self, frame: DataFrame) -> Result<Vec<u8>, Error> {
+        match self {
+            Remuxer::Flv { remuxer, header_sent } => {
+                let mut out = vec![];
+                for tag in remuxer.remux(&frame)? {
+                    if !*header_sent {
+                        out.extend_from_slice(&flv::header(remuxer.has_audio()));
+                        *header_sent = true;
+                    }
+                    tag.write(&mut out);
+                }
+                Ok(out)
+            }
+            Remuxer::Ts(remuxer) => Ok(remuxer.remux(frame)),
+        }
+    }
+}
+
+/// An HTTP client, counting as a viewer of its stream until its response is
+/// dropped.
+struct Client {
+    viewer: Viewer,
+    client: ClientSession,
+    remuxer: Remuxer,
+    remote: String,
+}
+
+impl Client {
+    /// Next chunk of the response, `None` at the end of the stream.
+    async fn next(&mut self) -> Option<Bytes> {
+        loop {
+            match self.viewer.rx.recv().await {
+                Ok(frame) => {
+                    if !self.client.accept(&frame) {
+                        continue;
+                    }
+                    match self.remuxer.remux(frame.clone()) {
+                        Ok(out) if out.is_empty() => self.client.sent(&frame),
+                        Ok(out) => {
+                            self.client.sent(&frame);
+                            return Some(out.into());
+                        }
+                        Err(e) => {
+                            warn!("HTTP client {} error: {}", self.remote, e);
+                            return None;
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(n)) => {
+                    warn!("HTTP client {} lagged {} frames, waiting for next keyframe", self.remote, n);
+                    self.client.lagged(n);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        debug!("HTTP client {} disconnected", self.remote);
+    }
+}
+
+/// Chunked response remuxing the frames of the stream `name` from the next
+/// keyframe.
+fn serve(req: &HttpRequest, name: &str, app_context: &AppContext, transport: &'static str, remuxer: Remuxer) -> HttpResponse {
+    let path = format!("/{}", name.trim_matches('/'));
+    let Some(stream_def) = app_context.stream(&path) else {
+        return HttpResponse::NotFound().body(format!("unknown stream {path}"));
+    };
+    let remote = req.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+    info!("HTTP client {} playing {} as {}", remote, path, transport);
+    let content_type = match remuxer {
+        Remuxer::Flv { .. } => "video/x-flv",
+        Remuxer::Ts(_) => "video/mp2t",
+    };
+    let viewer = Viewer::new(stream_def, &path);
+    let mut client = ClientSession::new(&app_context.clients, transport, remote.clone(), &path);
+    client.wait_keyframe = true;
+    let client = Client { viewer, client, remuxer, remote };
+
+    let body = futures::stream::unfold(client, |mut client| async move {
+        client.next().await.map(|chunk| (chunk, client))
+    });
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(body.map(Ok::<_, actix_web::Error>))
+}
+
+#[utoipa::path(
+    get,
+    path = "/live/{name}.flv",
+    params(("name" = String, Path, description = "Stream to play")),
+    responses(
+        (status = 200, description = "H.264/AAC stream as FLV, from the next keyframe", content_type = "video/x-flv"),
+        (status = 404, description = "Unknown stream")
+    )
+)]
+#[get("/live/{name:.*}.flv")]
+pub async fn play_flv(req: HttpRequest, name: web::Path<String>, data: web::Data<AppContext>) -> HttpResponse {
+    serve(&req, &name, data.get_ref(), "http-flv", Remuxer::Flv { remuxer: flv::Remuxer::default(), header_sent: false })
+}
+
+#[utoipa::path(
+    get,
+    path = "/live/{name}.ts",
+    params(("name" = String, Path, description = "Stream to play")),
+    responses(
+        (status = 200, description = "H.264/H.265/AAC stream as MPEG-TS, from the next keyframe", content_type = "video/mp2t"),
+        (status = 404, description = "Unknown stream")
+    )
+)]
+#[get("/live/{name:.*}.ts")]
+pub async fn play_ts(req: HttpRequest, name: web::Path<String>, data: web::Data<AppContext>) -> HttpResponse {
+    serve(&req, &name, data.get_ref(), "http-ts", Remuxer::Ts(mpegts::Remuxer::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstream::avc_config;
+    use serde_json::json;
+
+    const SPS: &[u8] = &[0x67, 0x42, 0x00, 0x1e, 0xab, 0x40, 0x50, 0x1e, 0xd0, 0x0f, 0x08, 0x84, 0x6a];
+    const PPS: &[u8] = &[0x68, 0xce, 0x3c, 0x80];
+    const IDR: &[u8] = &[0x65, 0x88, 0x84, 0x00, 0x33];
+    const SLICE: &[u8] = &[0x41, 0x9a, 0x02, 0x2c];
+    const AAC: &[u8] = &[0x21, 0x10, 0x05, 0x00];
+
+    fn video_config() -> DataFrame {
+        DataFrame::new(json!({ "type": "config", "media": "video", "codec": "avc1.42001E" }), avc_config(SPS, PPS).unwrap())
+    }
+
+    fn audio_config() -> DataFrame {
+        DataFrame::new(json!({ "type": "config", "media": "audio", "codec": "mp4a.40.2" }), vec![])
+    }
+
+    /// Keyframe as broadcast: the avcC record, then the Annex B parameter
+    /// sets inserted by retina and the IDR slice.
+    fn keyframe(ts: f64) -> DataFrame {
+        let record = avc_config(SPS, PPS).unwrap();
+        let data = [&record[..], &[0, 0, 0, 1], SPS, &[0, 0, 0, 1], PPS, &[0, 0, 0, 1], IDR].concat();
+        DataFrame::new(json!({ "type": "keyframe", "media": "video", "codec": "avc1.42001E", "ts": ts }), data).with_config_len(record.len())
+    }
+
+    fn delta(ts: f64) -> DataFrame {
+        DataFrame::new(json!({ "media": "video", "codec": "avc1.42001E", "ts": ts }), [&[0, 0, 0, 1], SLICE].concat())
+    }
+
+    /// AAC-LC frame at 44.1 kHz stereo, ADTS framed as by retina.
+    fn adts() -> Vec<u8> {
+        let len = 7 + AAC.len();
+        let header = [0xff, 0xf1, 0x50, 0x80 | (len >> 11) as u8, (len >> 3) as u8, (len << 5) as u8 | 0x1f, 0xfc];
+        [&header[..], AAC].concat()
+    }
+
+    fn audio(ts: f64) -> DataFrame {
+        DataFrame::new(json!({ "media": "audio", "codec": "mp4a.40.2", "ts": ts }), adts())
+    }
+
+    /// First bytes of the FLV response to a stream with the `configs`.
+    fn flv_start(configs: &[serde_json::Value]) -> Vec<u8> {
+        let mut remuxer = Remuxer::Flv { remuxer: flv::Remuxer::default(), header_sent: false };
+        for config in configs {
+            assert!(remuxer.remux(DataFrame::new(config.clone(), vec![])).unwrap().is_empty());
+        }
+        let keyframe = [&[0, 0, 0, 1, 0x67, 0x42, 0x00, 0x1e][..], &[0, 0, 0, 1, 0x68, 0xce], &[0, 0, 0, 1, 0x65, 0x88]].concat();
+        let metadata = json!({ "type": "keyframe", "media": "video", "codec": "avc1.42001E", "ts": 0.0 });
+        remuxer.remux(DataFrame::new(metadata, keyframe)).unwrap()
+    }
+
+    /// Type, time and data of the tags of an FLV body, after its header.
+    fn flv_tags(mut body: &[u8]) -> Vec<(u8, u32, Vec<u8>)> {
+        let mut tags = vec![];
+        while body.len() >= 15 {
+            let size = u32::from_be_bytes([0, body[1], body[2], body[3]]) as usize;
+            let ts = u32::from_be_bytes([body[7], body[4], body[5], body[6]]);
+            tags.push((body[0], ts, body[11..11 + size].to_vec()));
+            assert_eq!(body[11 + size..15 + size], ((11 + size) as u32).to_be_bytes());
+            body = &body[15 + size..];
+        }
+        assert!(body.is_empty());
+        tags
+    }
+
+    #[test]
+    fn flv_header_announces_the_configured_audio() {
+        let video = json!({ "type": "config", "media": "video", "codec": "avc1.42001E" });
+        let aac = json!({ "type": "config", "media": "audio", "codec": "mp4a.40.2" });
+        let opus = json!({ "type": "config", "media": "audio", "codec": "opus" });
+
+        assert_eq!(flv_start(std::slice::from_ref(&video))[..13], flv::header(false));
+        assert_eq!(flv_start(&[video.clone(), opus])[..13], flv::header(false));
+        let out = flv_start(&[video, aac]);
+        assert_eq!(out[..13], flv::header(true));
+        assert_eq!(out[4], 0x05);
+        // then the AVC sequence header
+        assert_eq!(out[13], flv::TAG_VIDEO);
+    }
+
+    #[test]
+    fn flv_tags_carry_the_nal_units_without_the_record() {
+        let mut remuxer = Remuxer::Flv { remuxer: flv::Remuxer::default(), header_sent: false };
+        let mut body = vec![];
+        for frame in [video_config(), audio_config(), audio(0.0), keyframe(40.0), audio(50.0), delta(80.0)] {
+            body.extend(remuxer.remux(frame).unwrap());
+        }
+        assert_eq!(body[..13], flv::header(true));
+
+        let length_prefixed = |nal: &[u8]| [&(nal.len() as u32).to_be_bytes()[..], nal].concat();
+        let record = avc_config(SPS, PPS).unwrap();
+        let tags = flv_tags(&body[13..]);
+        // the audio before the first keyframe is dropped
+        assert_eq!(
+            tags,
+            vec![
+                (flv::TAG_VIDEO, 40, [&[0x17, 0, 0, 0, 0][..], &record].concat()),
+                (flv::TAG_VIDEO, 40, [&[0x17, 1, 0, 0, 0][..], &length_prefixed(IDR)].concat()),
+                (flv::TAG_AUDIO, 50, vec![0xaf, 0, 0x12, 0x10]),
+                (flv::TAG_AUDIO, 50, [&[0xaf, 1][..], AAC].concat()),
+                (flv::TAG_VIDEO, 80, [&[0x27, 1, 0, 0, 0][..], &length_prefixed(SLICE)].concat()),
+            ]
+        );
+    }
+
+    #[test]
+    fn ts_packets_carry_annexb_video_and_adts_audio() {
+        let mut remuxer = Remuxer::Ts(mpegts::Remuxer::default());
+        let mut body = vec![];
+        for frame in [video_config(), audio_config(), audio(0.0), keyframe(40.0), audio(50.0), delta(80.0), keyframe(120.0)] {
+            body.extend(remuxer.remux(frame).unwrap());
+        }
+        assert_eq!(body.len() % 188, 0);
+        assert!(body.chunks(188).all(|packet| packet[0] == 0x47));
+
+        let pes = mpegts::Demuxer::default().push(&body);
+        let video: Vec<_> = pes.iter().filter(|pes| pes.stream_type == mpegts::STREAM_TYPE_H264).collect();
+        let audio: Vec<_> = pes.iter().filter(|pes| pes.stream_type == mpegts::STREAM_TYPE_AAC).collect();
+        // each access unit starts with a delimiter, the keyframe repeats the
+        // parameter sets in Annex B and nothing of the record
+        let aud = [0, 0, 0, 1, 0x09, 0xf0];
+        let annexb = |nals: &[&[u8]]| nals.iter().flat_map(|nal| [&[0, 0, 0, 1][..], nal].concat()).collect::<Vec<_>>();
+        assert_eq!(video.len(), 2);
+        assert_eq!(video[0].data, [&aud[..], &annexb(&[SPS, PPS, IDR])].concat());
+        assert_eq!(video[1].data, [&aud[..], &annexb(&[SLICE])].concat());
+        assert_eq!(video[1].pts.unwrap() - video[0].pts.unwrap(), 40 * 90);
+        // the audio before the first keyframe is dropped
+        assert_eq!(audio.len(), 1);
+        assert_eq!(audio[0].data, adts());
+        assert_eq!(audio[0].pts.unwrap() - video[0].pts.unwrap(), 10 * 90);
+    }
+}
@@ -0,0 +1,315 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use anyhow::{anyhow, Error};
+use base64::Engine;
+use futures::FutureExt;
+use log::debug;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::future::Future;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpStream;
+
+use crate::tls::TlsOptions;
+
+/// Default port of the HTTP tunnel when the URL does not set one.
+pub const HTTP_PORT: u16 = 80;
+/// Default port of the HTTPS tunnel when the URL does not set one.
+pub const HTTPS_PORT: u16 = 443;
+
+/// Largest HTTP response header accepted on the GET connection.
+const MAX_HEADER: usize = 8192;
+
+/// Announced length of each POST body. Once it is used up, the POST is closed
+/// and the client to server direction goes on with a new one, as the scheme
+/// allows. A multiple of 4, so that the base64 chunks end on its boundary.
+const POST_LENGTH: usize = 32768;
+
+/// How the tunnel reaches the camera, from the "tunnel" field of a stream
+/// config.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tunnel {
+    Http,
+    /// The GET and POST connections use TLS.
+    Https,
+}
+
+impl Tunnel {
+    pub fn default_port(self) -> u16 {
+        match self {
+            Tunnel::Http => HTTP_PORT,
+            Tunnel::Https => HTTPS_PORT,
+        }
+    }
+}
+
+fn session_cookie() -> Result<String, Error> {
+    let mut bytes = [0u8; 12];
+    SystemRandom::new().fill(&mut bytes).map_err(|_| anyhow!("no random source"))?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Open an RTSP-over-HTTP tunnel (Apple QuickTime scheme) to `host:port`,
+/// over TLS when `tls` is set.
+///
+/// The server to client direction is the body of a GET request, the client to
+/// server direction is the base64-encoded body of POST requests, all bound by
+/// the same `x-sessioncookie`. The tunnel is returned as one duplex stream
+/// carrying plain RTSP.
+pub async fn connect(host: &str, port: u16, path: &str, tls: Option<TlsOptions>) -> Result<DuplexStream, Error> {
+    let target = Target { host: host.to_string(), port, path: path.to_string(), cookie: session_cookie()? };
+    match tls {
+        None => target.open(|host, port| async move { Ok(TcpStream::connect((host.as_str(), port)).await?) }).await,
+        Some(tls) => {
+            target
+                .open(move |host, port| {
+                    let tls = tls.clone();
+                    async move { tls.connect(&host, port).await }
+                })
+                .await
+        }
+    }
+}
+
+/// Endpoint and session of a tunnel.
+struct Target {
+    host: String,
+    port: u16,
+    path: String,
+    cookie: String,
+}
+
+impl Target {
+    fn request(&self, method: &str, headers: &str) -> String {
+        format!(
+            "{method} {} HTTP/1.0\r\n\
+             Host: {}:{}\r\n\
+             x-sessioncookie: {}\r\n\
+             {headers}\
+             Pragma: no-cache\r\n\
+             Cache-Control: no-cache\r\n\r\n",
+            self.path, self.host, self.port, self.cookie
+        )
+    }
+
+    async fn post<S: AsyncWrite + Unpin>(&self, connection: Result<S, Error>) -> Result<S, Error> {
+        let mut post = connection?;
+        let headers = format!(
+            "Content-Type: application/x-rtsp-tunnelled\r\n\
+             Content-Length: {POST_LENGTH}\r\n\
+             Expires: Sun, 9 Jan 1972 00:00:00 GMT\r\n"
+        );
+        post.write_all(self.request("POST", &headers).as_bytes()).await?;
+        Ok(post)
+    }
+
+    async fn open<Connect, Fut, S>(self, connect: Connect) -> Result<DuplexStream, Error>
+    where
+        Connect: Fn(String, u16) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<S, Error>> + Send,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut get = connect(self.host.clone(), self.port).await?;
+        get.write_all(self.request("GET", "Accept: application/x-rtsp-tunnelled\r\n").as_bytes()).await?;
+
+        let mut header = Vec::new();
+        let body_start = loop {
+            let mut chunk = [0u8; 1024];
+            let n = get.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(anyhow!("tunnel closed before the GET response"));
+            }
+            header.extend_from_slice(&chunk[..n]);
+            if let Some(end) = header.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end + 4;
+            }
+            if header.len() > MAX_HEADER {
+                return Err(anyhow!("tunnel GET response header too large"));
+            }
+        };
+        let status_line = header.split(|b| *b == b'\n').next().unwrap_or_default();
+        let status_line = String::from_utf8_lossy(status_line).trim().to_string();
+        if status_line.split_whitespace().nth(1) != Some("200") {
+            return Err(anyhow!("tunnel GET refused: {}", status_line));
+        }
+        let body = header.split_off(body_start);
+
+        let mut post = self.post(connect(self.host.clone(), self.port).await).await?;
+
+        let (local, remote) = tokio::io::duplex(64 * 1024);
+        let (mut remote_read, mut remote_write) = tokio::io::split(remote);
+
+        tokio::spawn(async move {
+            let result = async {
+                remote_write.write_all(&body).await?;
+                tokio::io::copy(&mut get, &mut remote_write).await?;
+                Ok::<(), Error>(())
+            }
+            .await;
+            if let Err(e) = result {
+                debug!("HTTP tunnel GET closed: {}", e);
+            }
+        });
+
+        tokio::spawn(async move {
+            let result = async {
+                let engine = base64::engine::general_purpose::STANDARD;
+                let mut buf = vec![0u8; 16 * 1024];
+                // bytes read beyond a multiple of 3, held back so that the
+                // body has no padding in the middle of what the client writes
+                let mut pending = Vec::new();
+                let mut remaining = POST_LENGTH;
+                let mut closed = false;
+                while !closed {
+                    let read = remote_read.read(&mut buf);
+                    // a remainder is only held while more is ready to be read,
+                    // then it is flushed with its padding, as servers decode
+                    // the POST body as it arrives
+                    let n = match pending.is_empty() {
+                        true => Some(read.await?),
+                        false => read.now_or_never().transpose()?,
+                    };
+                    let encoded = match n {
+                        Some(0) if pending.is_empty() => break,
+                        Some(n) if n > 0 => {
+                            pending.extend_from_slice(&buf[..n]);
+                            let complete = pending.len() - pending.len() % 3;
+                            engine.encode(pending.drain(..complete).as_slice())
+                        }
+                        _ => {
+                            closed = n == Some(0);
+                            engine.encode(std::mem::take(&mut pending))
+                        }
+                    };
+                    let mut encoded = encoded.as_bytes();
+                    while !encoded.is_empty() {
+                        if remaining == 0 {
+                            post = self.post(connect(self.host.clone(), self.port).await).await?;
+                            remaining = POST_LENGTH;
+                        }
+                        let len = encoded.len().min(remaining);
+                        post.write_all(&encoded[..len]).await?;
+                        encoded = &encoded[len..];
+                        remaining -= len;
+                    }
+                }
+                Ok::<(), Error>(())
+            }
+            .await;
+            if let Err(e) = result {
+                debug!("HTTP tunnel POST closed: {}", e);
+            }
+        });
+
+        Ok(local)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Lines of an HTTP request header, lowercased.
+    async fn read_header<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Vec<String> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            let line = line.trim_end().to_ascii_lowercase();
+            if line.is_empty() {
+                return lines;
+            }
+            lines.push(line);
+        }
+    }
+
+    fn header<'a>(lines: &'a [String], name: &str) -> &'a str {
+        lines.iter().find_map(|line| line.strip_prefix(&format!("{name}: "))).unwrap()
+    }
+
+    /// Tunnel server echoing on the GET connection what is posted, decoding
+    /// the base64 by groups of 4 as it arrives like the servers do. Records
+    /// the Content-Length and the body of each POST.
+    async fn echo_server(listener: TcpListener, lengths: Arc<Mutex<Vec<usize>>>, posted: Arc<Mutex<Vec<u8>>>) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut get = BufReader::new(stream);
+        let lines = read_header(&mut get).await;
+        assert!(lines[0].starts_with("get /live?x=1 http/1.0"));
+        let cookie = header(&lines, "x-sessioncookie").to_string();
+        get.write_all(b"HTTP/1.0 200 OK\r\nContent-Type: application/x-rtsp-tunnelled\r\n\r\n").await.unwrap();
+
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut post = BufReader::new(stream);
+            let lines = read_header(&mut post).await;
+            assert!(lines[0].starts_with("post /live?x=1 http/1.0"));
+            assert_eq!(header(&lines, "x-sessioncookie"), cookie);
+            let length: usize = header(&lines, "content-length").parse().unwrap();
+            lengths.lock().unwrap().push(length);
+            let mut body = (&mut post).take(length as u64);
+            let mut pending = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = body.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                posted.lock().unwrap().extend_from_slice(&buf[..n]);
+                pending.extend_from_slice(&buf[..n]);
+                let complete = pending.len() - pending.len() % 4;
+                for group in pending[..complete].chunks(4) {
+                    let decoded = base64::engine::general_purpose::STANDARD.decode(group).unwrap();
+                    get.write_all(&decoded).await.unwrap();
+                }
+                pending.drain(..complete);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn posts_are_renewed_and_echoed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let lengths = Arc::new(Mutex::new(Vec::new()));
+        let posted = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn(echo_server(listener, lengths.clone(), posted.clone()));
+
+        let mut tunnel = connect("127.0.0.1", addr.port(), "/live?x=1", None).await.unwrap();
+        // more than two POST bodies once encoded, written in chunks that are
+        // not multiples of 3
+        let sent: Vec<u8> = (0..60_000u32).map(|i| (i % 251) as u8).collect();
+        for chunk in sent.chunks(7_000) {
+            tunnel.write_all(chunk).await.unwrap();
+        }
+        let mut received = vec![0u8; sent.len()];
+        tunnel.read_exact(&mut received).await.unwrap();
+        assert!(received == sent);
+        assert_eq!(*lengths.lock().unwrap(), vec![POST_LENGTH; 3]);
+        // no padding before the end, the posted bodies decode in one pass
+        let posted = base64::engine::general_purpose::STANDARD.decode(&*posted.lock().unwrap()).unwrap();
+        assert!(posted == sent);
+    }
+
+    #[tokio::test]
+    async fn refused_get_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut get = BufReader::new(stream);
+            read_header(&mut get).await;
+            get.write_all(b"HTTP/1.0 403 Forbidden\r\n\r\n").await.unwrap();
+        });
+        let error = connect("127.0.0.1", addr.port(), "/live?x=1", None).await.unwrap_err();
+        assert_eq!(error.to_string(), "tunnel GET refused: HTTP/1.0 403 Forbidden");
+    }
+}
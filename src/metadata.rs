@@ -0,0 +1,304 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use anyhow::Error;
+use serde_json::{json, Map, Value};
+
+use crate::bitstream::nal_units;
+
+/// SEI payload types.
+const SEI_USER_DATA_UNREGISTERED: u32 = 5;
+const SEI_TIME_CODE: u32 = 136;
+
+/// Remove the emulation prevention bytes (00 00 03 → 00 00) of a NAL unit.
+fn unescape(nal: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &byte in nal {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+/// MSB-first reader of fixed-length fields.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read(&mut self, bits: usize) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self.data.get(self.pos / 8)?;
+            value = (value << 1) | u32::from((byte >> (7 - self.pos % 8)) & 1);
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    fn flag(&mut self) -> Option<bool> {
+        self.read(1).map(|bit| bit == 1)
+    }
+}
+
+/// H.265 time_code SEI (D.2.27), one entry per clock timestamp.
+fn parse_time_code(payload: &[u8]) -> Option<Value> {
+    let mut reader = BitReader::new(payload);
+    let mut timecodes = vec![];
+    for _ in 0..reader.read(2)? {
+        if !reader.flag()? {
+            continue;
+        }
+        let _units_field_based = reader.flag()?;
+        let _counting_type = reader.read(5)?;
+        let full_timestamp = reader.flag()?;
+        let discontinuity = reader.flag()?;
+        let _cnt_dropped = reader.flag()?;
+        let frames = reader.read(9)?;
+        let (mut hours, mut minutes, mut seconds) = (0, 0, 0);
+        if full_timestamp {
+            seconds = reader.read(6)?;
+            minutes = reader.read(6)?;
+            hours = reader.read(5)?;
+        } else if reader.flag()? {
+            seconds = reader.read(6)?;
+            if reader.flag()? {
+                minutes = reader.read(6)?;
+                if reader.flag()? {
+                    hours = reader.read(5)?;
+                }
+            }
+        }
+        let offset_length = reader.read(5)? as usize;
+        reader.read(offset_length)?;
+        timecodes.push(json!({
+            "timecode": format!("{hours:02}:{minutes:02}:{seconds:02}:{frames:02}"),
+            "discontinuity": discontinuity,
+        }));
+    }
+    Some(json!({ "type": "time_code", "timecodes": timecodes }))
+}
+
+/// user_data_unregistered SEI: a UUID followed by data, decoded as JSON or
+/// XML when it is, kept as a string or hex dump otherwise.
+fn parse_user_data(payload: &[u8]) -> Option<Value> {
+    let (uuid, data) = (payload.get(..16)?, &payload[16..]);
+    let uuid = uuid.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    let uuid = format!("{}-{}-{}-{}-{}", &uuid[..8], &uuid[8..12], &uuid[12..16], &uuid[16..20], &uuid[20..]);
+
+    let data = match std::str::from_utf8(data) {
+        Ok(text) => {
+            let text = text.trim_end_matches('\0').trim();
+            serde_json::from_str(text)
+                .ok()
+                .or_else(|| text.starts_with('<').then(|| xml_to_json(text).ok()).flatten())
+                .unwrap_or_else(|| text.into())
+        }
+        Err(_) => data.iter().map(|b| format!("{b:02x}")).collect::<String>().into(),
+    };
+    Some(json!({ "type": "user_data_unregistered", "uuid": uuid, "data": data }))
+}
+
+/// Parse the SEI messages of an Annex B frame. Only user_data_unregistered
+/// and, for H.265, time_code messages are decoded; H.264 timecodes are carried
+/// in pic_timing messages that cannot be parsed without the SPS.
+pub fn parse_sei(codec: &str, frame: &[u8]) -> Vec<Value> {
+    let hevc = !codec.starts_with("avc");
+    let mut messages = vec![];
+    for nal in nal_units(frame) {
+        let Some(&header) = nal.first() else { continue };
+        let (is_sei, header_len) = if hevc {
+            (matches!((header >> 1) & 0x3f, 39 | 40), 2)
+        } else {
+            (header & 0x1f == 6, 1)
+        };
+        if !is_sei || nal.len() <= header_len {
+            continue;
+        }
+
+        let rbsp = unescape(&nal[header_len..]);
+        let mut pos = 0;
+        // stop at the rbsp trailing bits
+        while pos + 1 < rbsp.len() {
+            let mut read_value = || {
+                let mut value = 0u32;
+                while let Some(&byte) = rbsp.get(pos) {
+                    pos += 1;
+                    value += u32::from(byte);
+                    if byte != 0xff {
+                        return Some(value);
+                    }
+                }
+                None
+            };
+            let (Some(payload_type), Some(size)) = (read_value(), read_value()) else { break };
+            let Some(payload) = rbsp.get(pos..pos + size as usize) else { break };
+            pos += size as usize;
+
+            let message = match payload_type {
+                SEI_USER_DATA_UNREGISTERED => parse_user_data(payload),
+                SEI_TIME_CODE if hevc => parse_time_code(payload),
+                _ => None,
+            };
+            messages.extend(message);
+        }
+    }
+    messages
+}
+
+fn element_to_json(node: roxmltree::Node) -> Value {
+    let mut fields = Map::new();
+    for attribute in node.attributes() {
+        fields.insert(attribute.name().to_string(), attribute.value().into());
+    }
+    for child in node.children().filter(|n| n.is_element()) {
+        let value = element_to_json(child);
+        match fields.get_mut(child.tag_name().name()) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+            None => {
+                fields.insert(child.tag_name().name().to_string(), value);
+            }
+        }
+    }
+    let text = node.children().filter(|n| n.is_text()).filter_map(|n| n.text()).collect::<String>();
+    let text = text.trim();
+    if !text.is_empty() {
+        if fields.is_empty() {
+            return text.into();
+        }
+        fields.insert("#text".to_string(), text.into());
+    }
+    Value::Object(fields)
+}
+
+/// Convert an XML document to JSON: elements become objects keyed by local
+/// name (arrays when repeated), attributes become string fields, and elements
+/// holding only text become strings.
+pub fn xml_to_json(xml: &str) -> Result<Value, Error> {
+    let document = roxmltree::Document::parse(xml)?;
+    let root = document.root_element();
+    Ok(json!({ root.tag_name().name(): element_to_json(root) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: [u8; 16] = [0x9a, 0x21, 0xf3, 0xbe, 0x31, 0xf0, 0x4b, 0x78, 0xb0, 0xbe, 0xc7, 0xf7, 0xdb, 0xb9, 0x72, 0x50];
+
+    /// MSB-first packing of `(value, bits)` fields, padded with zeros.
+    fn pack(fields: &[(u32, usize)]) -> Vec<u8> {
+        let bits: Vec<u8> = fields.iter().flat_map(|&(value, n)| (0..n).rev().map(move |i| (value >> i & 1) as u8)).collect();
+        bits.chunks(8).map(|byte| byte.iter().enumerate().fold(0, |acc, (i, bit)| acc | bit << (7 - i))).collect()
+    }
+
+    /// SEI message of a payload type and its payload, sizes coded with 0xff bytes.
+    fn message(payload_type: usize, payload: &[u8]) -> Vec<u8> {
+        let mut message = vec![];
+        for mut value in [payload_type, payload.len()] {
+            while value >= 255 {
+                message.push(0xff);
+                value -= 255;
+            }
+            message.push(value as u8);
+        }
+        [&message[..], payload].concat()
+    }
+
+    #[test]
+    fn emulation_prevention_bytes_are_removed() {
+        assert_eq!(unescape(&[0, 0, 3, 1]), [0, 0, 1]);
+        assert_eq!(unescape(&[0, 0, 3, 0, 0, 3, 0]), [0, 0, 0, 0, 0]);
+        assert_eq!(unescape(&[0, 0, 0, 3, 2]), [0, 0, 0, 2]);
+        assert_eq!(unescape(&[0, 3, 0, 3]), [0, 3, 0, 3]);
+        assert_eq!(unescape(&[0x65, 0, 0, 3]), [0x65, 0, 0]);
+        assert!(unescape(&[]).is_empty());
+    }
+
+    #[test]
+    fn time_codes_are_read_with_their_optional_fields() {
+        // full timestamp 12:56:34 frame 12
+        let full = pack(&[(1, 2), (1, 1), (0, 1), (0, 5), (1, 1), (0, 1), (0, 1), (12, 9), (34, 6), (56, 6), (12, 5), (0, 5)]);
+        assert_eq!(
+            parse_time_code(&full),
+            Some(json!({ "type": "time_code", "timecodes": [{ "timecode": "12:56:34:12", "discontinuity": false }] }))
+        );
+
+        // a skipped clock timestamp, then seconds only and a 3-bit time offset
+        let partial = pack(&[(2, 2), (0, 1), (1, 1), (0, 1), (0, 5), (0, 1), (1, 1), (0, 1), (3, 9), (1, 1), (5, 6), (0, 1), (3, 5), (5, 3)]);
+        assert_eq!(
+            parse_time_code(&partial),
+            Some(json!({ "type": "time_code", "timecodes": [{ "timecode": "00:00:05:03", "discontinuity": true }] }))
+        );
+
+        for len in 0..full.len() - 1 {
+            assert_eq!(parse_time_code(&full[..len]), None, "{len} bytes");
+        }
+        assert_eq!(parse_time_code(&[0]), Some(json!({ "type": "time_code", "timecodes": [] })));
+    }
+
+    #[test]
+    fn user_data_is_decoded_as_json_xml_text_or_hex() {
+        let uuid = "9a21f3be-31f0-4b78-b0be-c7f7dbb97250";
+        let json_data = message(5, &[&UUID[..], br#"{"plate": "AB-123"}"#, b"\0"].concat());
+        let xml = message(5, &[&UUID[..], b"<Event Topic=\"Motion\"><State>true</State></Event>"].concat());
+        // escaped 00 00 01, the size counting the unescaped bytes
+        let binary = [&message(5, &[&UUID[..], &[0xff, 0, 0, 1]].concat())[..19], &[0, 0, 3, 1]].concat();
+        let long_text = message(5, &[&UUID[..], &[b'x'; 300]].concat());
+        let rbsp = [&json_data[..], &xml, &binary, &long_text, &[0x80]].concat();
+        let frame = [&[0, 0, 0, 1, 0x06][..], &rbsp, &[0, 0, 0, 1, 0x65, 0x88]].concat();
+
+        let messages = parse_sei("avc1.64001E", &frame);
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0], json!({ "type": "user_data_unregistered", "uuid": uuid, "data": { "plate": "AB-123" } }));
+        assert_eq!(messages[1]["data"], json!({ "Event": { "Topic": "Motion", "State": "true" } }));
+        assert_eq!(messages[2]["data"], "ff000001");
+        assert_eq!(messages[3]["data"], "x".repeat(300));
+    }
+
+    #[test]
+    fn time_codes_are_decoded_for_hevc_only() {
+        let time_code = message(136, &pack(&[(1, 2), (1, 1), (0, 1), (0, 5), (1, 1), (0, 1), (0, 1), (1, 9), (2, 6), (3, 6), (4, 5), (0, 5)]));
+        let sei = [&time_code[..], &[0x80]].concat();
+        let hevc = [&[0, 0, 1, 0x4e, 0x01][..], &sei].concat();
+        let messages = parse_sei("hvc1.1.6.L93.B0", &hevc);
+        assert_eq!(messages, vec![json!({ "type": "time_code", "timecodes": [{ "timecode": "04:03:02:01", "discontinuity": false }] })]);
+
+        let avc = [&[0, 0, 1, 0x06][..], &sei].concat();
+        assert!(parse_sei("avc1.64001E", &avc).is_empty());
+    }
+
+    #[test]
+    fn malformed_sei_keeps_the_messages_read_before() {
+        let user_data = message(5, &[&UUID[..], b"first"].concat());
+        // a size past the end of the NAL unit
+        let truncated = [&[0, 0, 1, 0x06][..], &user_data, &[5, 40], &UUID, &[0x80]].concat();
+        let messages = parse_sei("avc1.64001E", &truncated);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["data"], "first");
+
+        // user data shorter than its UUID, sizes running out, empty SEI
+        let short = [&[0, 0, 1, 0x06][..], &message(5, &UUID[..8]), &[0x80]].concat();
+        assert!(parse_sei("avc1.64001E", &short).is_empty());
+        assert!(parse_sei("avc1.64001E", &[0, 0, 1, 0x06, 0xff, 0xff]).is_empty());
+        assert!(parse_sei("avc1.64001E", &[0, 0, 1, 0x06]).is_empty());
+        assert!(parse_sei("avc1.64001E", &[]).is_empty());
+    }
+}
@@ -0,0 +1,439 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use log::debug;
+use std::collections::HashMap;
+
+use crate::bitstream::{Bitstream, Format};
+use crate::streamdef::DataFrame;
+
+pub const PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+
+pub const STREAM_TYPE_AAC: u8 = 0x0f;
+pub const STREAM_TYPE_H264: u8 = 0x1b;
+pub const STREAM_TYPE_H265: u8 = 0x24;
+
+const PAT_PID: u16 = 0;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x100;
+const AUDIO_PID: u16 = 0x101;
+
+/// Delay of the presentation times on the clock reference, in 90 kHz units,
+/// leaving the receivers time to buffer.
+const PTS_DELAY: u64 = 63000;
+
+/// MPEG-2 CRC of the PSI sections.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= u32::from(*byte) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn read_timestamp(data: &[u8]) -> u64 {
+    (u64::from(data[0] >> 1) & 0x07) << 30
+        | u64::from(data[1]) << 22
+        | u64::from(data[2] >> 1) << 15
+        | u64::from(data[3]) << 7
+        | u64::from(data[4] >> 1)
+}
+
+fn write_timestamp(out: &mut Vec<u8>, marker: u8, ts: u64) {
+    out.push(marker << 4 | ((ts >> 29) as u8 & 0x0e) | 1);
+    out.push((ts >> 22) as u8);
+    out.push((ts >> 14) as u8 | 1);
+    out.push((ts >> 7) as u8);
+    out.push((ts << 1) as u8 | 1);
+}
+
+/// An elementary stream packet reassembled by the [`Demuxer`].
+pub struct Pes {
+    pub stream_type: u8,
+    /// Presentation time, in 90 kHz units.
+    pub pts: Option<u64>,
+    pub data: Vec<u8>,
+    /// Packets of the stream were lost before this one.
+    pub discontinuity: bool,
+}
+
+struct Elementary {
+    stream_type: u8,
+    buffer: Vec<u8>,
+    continuity: Option<u8>,
+    discontinuity: bool,
+}
+
+impl Elementary {
+    /// Complete PES packet of the buffer, which is left empty.
+    fn take(&mut self) -> Option<Pes> {
+        let buffer = std::mem::take(&mut self.buffer);
+        if buffer.len() < 9 || buffer[..3] != [0, 0, 1] {
+            return None;
+        }
+        let header_len = 9 + usize::from(buffer[8]);
+        let pts = (buffer[7] & 0x80 != 0 && buffer.len() >= 14).then(|| read_timestamp(&buffer[9..14]));
+        let pes = Pes {
+            stream_type: self.stream_type,
+            pts,
+            data: buffer.get(header_len..)?.to_vec(),
+            discontinuity: self.discontinuity,
+        };
+        self.discontinuity = false;
+        Some(pes)
+    }
+
+    /// Whether the buffer holds a PES packet of known length in full.
+    fn is_complete(&self) -> bool {
+        let length = match self.buffer.get(4..6) {
+            Some(length) => usize::from(u16::from_be_bytes([length[0], length[1]])),
+            None => return false,
+        };
+        length != 0 && self.buffer.len() >= 6 + length
+    }
+}
+
+/// Demultiplexer of the first program of an MPEG transport stream.
+#[derive(Default)]
+pub struct Demuxer {
+    pending: Vec<u8>,
+    pmt_pid: Option<u16>,
+    streams: HashMap<u16, Elementary>,
+}
+
+impl Demuxer {
+    /// Push transport stream data, which does not need to be aligned on
+    /// packets, and return the elementary stream packets it completes.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Pes> {
+        let mut out = vec![];
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.extend_from_slice(data);
+        let mut start = 0;
+        while start + PACKET_SIZE <= pending.len() {
+            if pending[start] != SYNC_BYTE {
+                start += 1;
+                continue;
+            }
+            self.packet(&pending[start..start + PACKET_SIZE], &mut out);
+            start += PACKET_SIZE;
+        }
+        pending.drain(..start);
+        self.pending = pending;
+        out
+    }
+
+    fn packet(&mut self, packet: &[u8], out: &mut Vec<Pes>) {
+        let unit_start = packet[1] & 0x40 != 0;
+        let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+        let adaptation = packet[3] & 0x20 != 0;
+        let has_payload = packet[3] & 0x10 != 0;
+        let continuity = packet[3] & 0x0f;
+        let mut start = 4;
+        if adaptation {
+            start += 1 + usize::from(packet[4]);
+        }
+        if !has_payload || start >= PACKET_SIZE {
+            return;
+        }
+        let payload = &packet[start..];
+
+        if pid == PAT_PID || Some(pid) == self.pmt_pid {
+            if unit_start {
+                let pointer = usize::from(payload[0]);
+                if let Some(section) = payload.get(1 + pointer..) {
+                    self.section(section);
+                }
+            }
+            return;
+        }
+
+        let Some(stream) = self.streams.get_mut(&pid) else { return };
+        match stream.continuity {
+            // duplicate packet
+            Some(last) if last == continuity => return,
+            Some(last) if (last + 1) & 0x0f != continuity => {
+                debug!("MPEG-TS discontinuity on PID {}", pid);
+                stream.buffer.clear();
+                stream.discontinuity = true;
+            }
+            _ => {}
+        }
+        stream.continuity = Some(continuity);
+        if unit_start {
+            out.extend(stream.take());
+        } else if stream.buffer.is_empty() {
+            // continuation of a packet whose start was lost
+            return;
+        }
+        stream.buffer.extend_from_slice(payload);
+        if stream.is_complete() {
+            out.extend(stream.take());
+        }
+    }
+
+    /// Program association and program map tables, assumed to fit in a packet.
+    fn section(&mut self, section: &[u8]) {
+        if section.len() < 3 {
+            return;
+        }
+        let length = usize::from(u16::from_be_bytes([section[1] & 0x0f, section[2]]));
+        let Some(section) = section.get(..3 + length).filter(|_| length >= 9) else { return };
+        let entries = &section[..section.len() - 4];
+        match section[0] {
+            0x00 => {
+                self.pmt_pid = entries[8..]
+                    .chunks_exact(4)
+                    .find(|program| program[..2] != [0, 0])
+                    .map(|program| u16::from_be_bytes([program[2] & 0x1f, program[3]]));
+            }
+            0x02 if entries.len() >= 12 => {
+                let info_length = usize::from(u16::from_be_bytes([entries[10] & 0x0f, entries[11]]));
+                let mut entries = entries.get(12 + info_length..).unwrap_or_default();
+                let mut pids = vec![];
+                while entries.len() >= 5 {
+                    let stream_type = entries[0];
+                    let pid = u16::from_be_bytes([entries[1] & 0x1f, entries[2]]);
+                    let es_info_length = usize::from(u16::from_be_bytes([entries[3] & 0x0f, entries[4]]));
+                    pids.push(pid);
+                    let stream = self.streams.entry(pid).or_insert_with(|| Elementary {
+                        stream_type,
+                        buffer: vec![],
+                        continuity: None,
+                        discontinuity: false,
+                    });
+                    if stream.stream_type != stream_type {
+                        stream.stream_type = stream_type;
+                        stream.buffer.clear();
+                    }
+                    entries = entries.get(5 + es_info_length..).unwrap_or_default();
+                }
+                self.streams.retain(|pid, _| pids.contains(pid));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// An AAC frame of an ADTS stream.
+pub struct AdtsFrame<'a> {
+    /// AudioSpecificConfig of the frame.
+    pub config: [u8; 2],
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub data: &'a [u8],
+}
+
+const SAMPLE_RATES: [u32; 13] = [96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350];
+
+/// Split an ADTS stream into its AAC frames.
+pub fn adts_frames(mut data: &[u8]) -> Vec<AdtsFrame<'_>> {
+    let mut frames = vec![];
+    while data.len() >= 7 && data[0] == 0xff && data[1] & 0xf0 == 0xf0 {
+        let header_len = if data[1] & 0x01 != 0 { 7 } else { 9 };
+        let object_type = (data[2] >> 6) + 1;
+        let frequency = (data[2] >> 2) & 0x0f;
+        let channels = (data[2] & 0x01) << 2 | data[3] >> 6;
+        let length = usize::from(data[3] & 0x03) << 11 | usize::from(data[4]) << 3 | usize::from(data[5] >> 5);
+        let (Some(frame), Some(&sample_rate)) = (data.get(header_len..length), SAMPLE_RATES.get(usize::from(frequency))) else {
+            break;
+        };
+        let config = u16::from(object_type) << 11 | u16::from(frequency) << 7 | u16::from(channels) << 3;
+        frames.push(AdtsFrame { config: config.to_be_bytes(), sample_rate, channels: channels.into(), data: frame });
+        data = &data[length..];
+    }
+    frames
+}
+
+/// Multiplexer of a single program with a video stream and an optional AAC
+/// stream, declared in the PMT once its first frame is written.
+pub struct Muxer {
+    video_type: u8,
+    audio: bool,
+    version: u8,
+    continuity: HashMap<u16, u8>,
+}
+
+impl Muxer {
+    pub fn new(video_type: u8) -> Self {
+        Self { video_type, audio: false, version: 0, continuity: HashMap::new() }
+    }
+
+    /// Stream type of an RFC 6381 video codec string.
+    pub fn video_type(codec: &str) -> Option<u8> {
+        if codec.starts_with("avc") {
+            Some(STREAM_TYPE_H264)
+        } else if codec.starts_with("hvc") || codec.starts_with("hev") {
+            Some(STREAM_TYPE_H265)
+        } else {
+            None
+        }
+    }
+
+    fn next_continuity(&mut self, pid: u16) -> u8 {
+        let continuity = self.continuity.entry(pid).or_insert(0x0f);
+        *continuity = (*continuity + 1) & 0x0f;
+        *continuity
+    }
+
+    fn write_section(&mut self, out: &mut Vec<u8>, pid: u16, mut section: Vec<u8>) {
+        let crc = crc32(&section);
+        section.extend_from_slice(&crc.to_be_bytes());
+        let continuity = self.next_continuity(pid);
+        let start = out.len();
+        out.extend_from_slice(&[SYNC_BYTE, 0x40 | (pid >> 8) as u8, pid as u8, 0x10 | continuity, 0]);
+        out.extend_from_slice(&section);
+        out.resize(start + PACKET_SIZE, 0xff);
+    }
+
+    /// Program association and program map tables, repeated on keyframes.
+    pub fn write_tables(&mut self, out: &mut Vec<u8>) {
+        let version = self.version << 1 | 0xc1;
+        let mut pat = vec![0x00, 0xb0, 13, 0, 1, version, 0, 0, 0, 1];
+        pat.extend_from_slice(&(0xe000 | PMT_PID).to_be_bytes());
+        self.write_section(out, PAT_PID, pat);
+
+        let mut streams = vec![(self.video_type, VIDEO_PID)];
+        if self.audio {
+            streams.push((STREAM_TYPE_AAC, AUDIO_PID));
+        }
+        let mut pmt = vec![0x02, 0xb0, 0, 0, 1, version, 0, 0];
+        pmt.extend_from_slice(&(0xe000 | VIDEO_PID).to_be_bytes());
+        pmt.extend_from_slice(&[0xf0, 0]);
+        for (stream_type, pid) in streams {
+            pmt.push(stream_type);
+            pmt.extend_from_slice(&(0xe000 | pid).to_be_bytes());
+            pmt.extend_from_slice(&[0xf0, 0]);
+        }
+        pmt[2] = (pmt.len() + 4 - 3) as u8;
+        self.write_section(out, PMT_PID, pmt);
+    }
+
+    /// Write an Annex B access unit of time `ts` (90 kHz), which also gives
+    /// the clock reference of the program.
+    pub fn write_video(&mut self, out: &mut Vec<u8>, ts: u64, keyframe: bool, data: &[u8]) {
+        if keyframe {
+            self.write_tables(out);
+        }
+        let mut pes = vec![0, 0, 1, 0xe0, 0, 0, 0x80, 0x80, 5];
+        write_timestamp(&mut pes, 0x2, ts + PTS_DELAY);
+        // access unit delimiter, required by some demuxers
+        match self.video_type {
+            STREAM_TYPE_H264 => pes.extend_from_slice(&[0, 0, 0, 1, 0x09, 0xf0]),
+            _ => pes.extend_from_slice(&[0, 0, 0, 1, 0x46, 0x01, 0x50]),
+        }
+        pes.extend_from_slice(data);
+        self.write_pes(out, VIDEO_PID, &pes, Some(ts), keyframe);
+    }
+
+    /// Write ADTS frames of time `ts` (90 kHz).
+    pub fn write_audio(&mut self, out: &mut Vec<u8>, ts: u64, data: &[u8]) {
+        if !self.audio {
+            self.audio = true;
+            self.version = (self.version + 1) & 0x1f;
+            self.write_tables(out);
+        }
+        let mut pes = vec![0, 0, 1, 0xc0, 0, 0, 0x80, 0x80, 5];
+        write_timestamp(&mut pes, 0x2, ts + PTS_DELAY);
+        pes.extend_from_slice(data);
+        let length = pes.len() - 6;
+        if length <= 0xffff {
+            pes[4..6].copy_from_slice(&(length as u16).to_be_bytes());
+        }
+        self.write_pes(out, AUDIO_PID, &pes, None, false);
+    }
+
+    fn write_pes(&mut self, out: &mut Vec<u8>, pid: u16, mut pes: &[u8], pcr: Option<u64>, random_access: bool) {
+        let mut first = true;
+        while !pes.is_empty() {
+            let continuity = self.next_continuity(pid);
+            // adaptation field, without its length byte
+            let mut adaptation = None;
+            if first && (pcr.is_some() || random_access) {
+                let mut field = vec![if random_access { 0x40 } else { 0 }];
+                if let Some(pcr) = pcr {
+                    field[0] |= 0x10;
+                    field.extend_from_slice(&[(pcr >> 25) as u8, (pcr >> 17) as u8, (pcr >> 9) as u8, (pcr >> 1) as u8, (pcr << 7) as u8 | 0x7e, 0]);
+                }
+                adaptation = Some(field);
+            }
+            let room = PACKET_SIZE - 4 - adaptation.as_ref().map_or(0, |field| 1 + field.len());
+            if pes.len() < room {
+                // the last packet is filled with stuffing bytes of the adaptation field
+                let stuffing = room - pes.len();
+                match adaptation.as_mut() {
+                    Some(field) => field.resize(field.len() + stuffing, 0xff),
+                    None if stuffing == 1 => adaptation = Some(vec![]),
+                    None => {
+                        let mut field = vec![0];
+                        field.resize(stuffing - 1, 0xff);
+                        adaptation = Some(field);
+                    }
+                }
+            }
+            out.extend_from_slice(&[SYNC_BYTE, if first { 0x40 } else { 0 } | (pid >> 8) as u8, pid as u8]);
+            match &adaptation {
+                Some(field) => {
+                    out.extend_from_slice(&[0x30 | continuity, field.len() as u8]);
+                    out.extend_from_slice(field);
+                }
+                None => out.push(0x10 | continuity),
+            }
+            let size = room.min(pes.len());
+            out.extend_from_slice(&pes[..size]);
+            pes = &pes[size..];
+            first = false;
+        }
+    }
+}
+
+/// Conversion of the broadcast frames to a transport stream, started on the
+/// first keyframe, which gives the video codec.
+pub struct Remuxer {
+    bitstream: Bitstream,
+    muxer: Option<Muxer>,
+}
+
+impl Default for Remuxer {
+    fn default() -> Self {
+        Self { bitstream: Bitstream::new(Format::AnnexB), muxer: None }
+    }
+}
+
+impl Remuxer {
+    /// Transport stream packets of a frame.
+    pub fn remux(&mut self, frame: DataFrame) -> Vec<u8> {
+        let mut out = vec![];
+        let ts = (frame.metadata["ts"].as_f64().unwrap_or_default() * 90.0).max(0.0) as u64;
+        match frame.metadata["media"].as_str() {
+            Some("video") if !frame.is_config() => {
+                let keyframe = frame.is_keyframe();
+                if self.muxer.is_none() && keyframe {
+                    self.muxer = frame.metadata["codec"].as_str().and_then(Muxer::video_type).map(Muxer::new);
+                }
+                if let Some(muxer) = self.muxer.as_mut() {
+                    for frame in self.bitstream.convert(frame) {
+                        muxer.write_video(&mut out, ts, keyframe, &frame.data);
+                    }
+                }
+            }
+            // AAC frames are ADTS framed by retina
+            Some("audio") if frame.metadata["codec"].as_str().is_some_and(|c| c.starts_with("mp4a.40.")) && !frame.is_config() => {
+                if let Some(muxer) = self.muxer.as_mut() {
+                    muxer.write_audio(&mut out, ts, &frame.data);
+                }
+            }
+            _ => {}
+        }
+        out
+    }
+}
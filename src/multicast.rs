@@ -0,0 +1,248 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use anyhow::{anyhow, Error};
+use log::{debug, info, warn};
+use retina::codec::ParametersRef;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::io::BufReader;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::rtsp::{self, Challenge, Credentials, Message, RtpReceiver};
+use crate::rtspclient::{self, Source};
+use crate::streamdef::FrameSender;
+
+/// Default RTSP session timeout, when the SETUP response does not give one.
+const SESSION_TIMEOUT: u64 = 60;
+
+/// Control connection of a multicast session. retina does not support
+/// multicast, so the session is set up with this minimal client and the
+/// packets are depacketized with retina.
+struct RtspConnection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    cseq: u32,
+    session: Option<String>,
+    credentials: Option<Credentials>,
+    challenge: Option<Challenge>,
+    /// Requests made with the nonce of a Digest challenge.
+    nc: u32,
+}
+
+impl RtspConnection {
+    async fn connect(url: &url::Url) -> Result<Self, Error> {
+        let host = url.host_str().ok_or_else(|| anyhow!("missing host in {}", url))?;
+        let stream = TcpStream::connect((host, url.port().unwrap_or(554))).await?;
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            reader: BufReader::new(reader),
+            writer,
+            cseq: 0,
+            session: None,
+            credentials: Credentials::from_url(url),
+            challenge: None,
+            nc: 0,
+        })
+    }
+
+    /// Send a request and wait for its response, failing on error statuses.
+    /// The credentials of the URL answer the first 401 challenge, and then
+    /// authenticate the following requests.
+    async fn send(&mut self, request: Message) -> Result<Message, Error> {
+        let mut challenged = false;
+        loop {
+            let response = self.exchange(request.clone()).await?;
+            match response.status() {
+                Some(200..=299) => return Ok(response),
+                Some(401) if !challenged && self.credentials.is_some() => {
+                    if let Some(challenge) = Challenge::parse(&response) {
+                        self.challenge = Some(challenge);
+                        self.nc = 0;
+                        challenged = true;
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+            let method = request.method().map(|(method, _)| method).unwrap_or_default();
+            return Err(anyhow!("'{}' response to {}", response.start_line, method));
+        }
+    }
+
+    async fn exchange(&mut self, request: Message) -> Result<Message, Error> {
+        self.cseq += 1;
+        let mut request = request
+            .with_header("CSeq", self.cseq)
+            .with_header("User-Agent", concat!("rtsp2web-rs/", env!("CARGO_PKG_VERSION")));
+        if let Some(session) = &self.session {
+            request = request.with_header("Session", session);
+        }
+        if let (Some(credentials), Some(challenge), Some((method, uri))) = (&self.credentials, &self.challenge, request.method()) {
+            self.nc += 1;
+            let authorization = credentials.authorization(challenge, method, uri, self.nc);
+            request = request.with_header("Authorization", authorization);
+        }
+        request.write_to(&mut self.writer).await?;
+
+        let method = request.method().map(|(method, _)| method).unwrap_or_default();
+        loop {
+            let response = Message::read_from(&mut self.reader)
+                .await?
+                .ok_or_else(|| anyhow!("connection closed while expecting response to {}", method))?;
+            if response.header("CSeq").and_then(|cseq| cseq.parse().ok()) == Some(self.cseq) {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+/// Multicast group and RTP and RTCP ports of a SETUP response transport:
+/// `RTP/AVP;multicast;destination=239.1.1.1;port=5000-5001;ttl=16`. The RTCP
+/// port is the next one when the range is not given.
+fn parse_transport(transport: &str, connection: Option<&str>) -> Result<(Ipv4Addr, u16, u16), Error> {
+    let param = |name: &str| {
+        transport
+            .split(';')
+            .filter_map(|p| p.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    };
+    if !transport.split(';').any(|p| p.trim().eq_ignore_ascii_case("multicast")) {
+        return Err(anyhow!("server refused multicast transport: {}", transport));
+    }
+    let group = param("destination")
+        .or(connection)
+        .ok_or_else(|| anyhow!("no multicast group in {}", transport))?
+        .parse::<Ipv4Addr>()?;
+    let ports = param("port").ok_or_else(|| anyhow!("no multicast port in {}", transport))?;
+    let (port, rtcp_port) = match ports.split_once('-') {
+        Some((port, rtcp_port)) => (port.parse::<u16>()?, rtcp_port.parse::<u16>()?),
+        None => {
+            let port = ports.parse::<u16>()?;
+            (port, port.wrapping_add(1))
+        }
+    };
+    Ok((group, port, rtcp_port))
+}
+
+/// Bind the RTP port of the group and join it on the given interface. The
+/// address is reused so that several proxies on a host can share the group.
+fn join(group: Ipv4Addr, port: u16, interface: Ipv4Addr) -> Result<UdpSocket, Error> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    // binding the group address keeps out other groups sent to the same port
+    socket.bind(&SocketAddr::from((group, port)).into())?;
+    socket.join_multicast_v4(&group, &interface)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Play the video of `url` over multicast until `stop` completes.
+pub async fn run_until<Stop>(
+    url: url::Url,
+    source: &Source,
+    tx: FrameSender,
+    stop: Stop,
+) -> Result<(), Error>
+where
+    Stop: Future<Output = ()>,
+{
+    let mut connection = RtspConnection::connect(&url).await?;
+    let mut url = url;
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+
+    let describe = connection
+        .send(Message::request("DESCRIBE", url.as_str()).with_header("Accept", "application/sdp"))
+        .await?;
+    let base = match describe.header("Content-Base").or(describe.header("Content-Location")) {
+        Some(base) => url::Url::parse(base)?,
+        None => url.clone(),
+    };
+    let medias = rtsp::parse_sdp(&String::from_utf8_lossy(&describe.body));
+    let media = medias
+        .iter()
+        .find(|m| m.media == "video" && matches!(m.encoding_name.as_str(), "h264" | "h265"))
+        .ok_or_else(|| anyhow!("couldn't find video stream"))?;
+    let mut receiver = RtpReceiver::new(0, media)?;
+
+    let control = rtsp::control_url(&base, media.control.as_deref())?;
+    let setup = connection
+        .send(Message::request("SETUP", control.as_str()).with_header("Transport", "RTP/AVP;multicast"))
+        .await?;
+    connection.session = setup.session().map(str::to_string);
+    let timeout = setup
+        .header("Session")
+        .and_then(|s| s.split(';').find_map(|p| p.trim().strip_prefix("timeout=")))
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(SESSION_TIMEOUT);
+    let transport = setup.header("Transport").ok_or_else(|| anyhow!("missing Transport in SETUP response"))?;
+    let (group, port, rtcp_port) = parse_transport(transport, media.connection.as_deref())?;
+    let interface = source.interface.unwrap_or(Ipv4Addr::UNSPECIFIED);
+    let socket = join(group, port, interface)?;
+    // the sender reports give the wall clock of the frames
+    let rtcp = join(group, rtcp_port, interface)?;
+    info!("Joined multicast group {}:{} on {} for {}", group, port, interface, url);
+
+    connection
+        .send(Message::request("PLAY", base.as_str()).with_header("Range", "npt=0.000-"))
+        .await?;
+
+    let mut video_params = match receiver.parameters() {
+        Some(ParametersRef::Video(v)) => Some(v.clone()),
+        _ => None,
+    };
+    if let Some(video_params) = &video_params {
+        rtspclient::send_frame(&tx, rtspclient::config_frame(video_params));
+    }
+
+    let mut keepalive = tokio::time::interval(Duration::from_secs((timeout / 2).max(1)));
+    keepalive.tick().await;
+    let mut buf = vec![0u8; 65536];
+    let mut rtcp_buf = vec![0u8; 2048];
+    tokio::pin!(stop);
+    let result = loop {
+        tokio::select! {
+            received = socket.recv(&mut buf) => {
+                let n = match received {
+                    Ok(n) => n,
+                    Err(e) => break Err(e.into()),
+                };
+                if let Err(e) = receiver.push(&buf[..n], Instant::now()) {
+                    warn!("Multicast {} depacketization error: {}", url, e);
+                    continue;
+                }
+                if let Err(e) = receiver.broadcast_video(&mut video_params, source.metadata, &tx) {
+                    warn!("Multicast {} depacketization error: {}", url, e);
+                }
+            }
+            received = rtcp.recv(&mut rtcp_buf) => {
+                match received {
+                    Ok(n) => receiver.push_rtcp(&rtcp_buf[..n]),
+                    Err(e) => break Err(e.into()),
+                }
+            }
+            _ = keepalive.tick() => {
+                if let Err(e) = connection.send(Message::request("OPTIONS", base.as_str())).await {
+                    break Err(e);
+                }
+            }
+            _ = &mut stop => break Ok(()),
+        }
+    };
+
+    if let Err(e) = connection.send(Message::request("TEARDOWN", base.as_str())).await {
+        debug!("TEARDOWN failed: {}", e);
+    }
+    result
+}
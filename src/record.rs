@@ -0,0 +1,305 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use actix_web::{delete, post, web, HttpResponse};
+use log::{error, info, warn};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::oneshot;
+
+use crate::appcontext::AppContext;
+use crate::clientsession::ClientSession;
+use crate::dataframe::{iso8601, now_ms};
+use crate::mpegts;
+use crate::streamdef::{StreamsDef, Viewer};
+
+/// Why a recording cannot be started.
+#[derive(Debug)]
+pub enum RecordError {
+    /// The config has no "recordings" directory.
+    Disabled,
+    UnknownStream(String),
+    /// The stream is already recorded, to the file given.
+    Recording(String),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disabled => f.write_str("recording is disabled"),
+            Self::UnknownStream(path) => write!(f, "unknown stream '{path}'"),
+            Self::Recording(file) => write!(f, "stream is already recorded to {file}"),
+            Self::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+struct Recording {
+    id: u64,
+    file: String,
+    stop_tx: oneshot::Sender<()>,
+}
+
+/// Recordings of the streams to MPEG-TS files, by stream path.
+#[derive(Clone, Default)]
+pub struct Recorders {
+    /// Directory of the files, recording being disabled without.
+    dir: Option<PathBuf>,
+    running: Arc<Mutex<HashMap<String, Recording>>>,
+    next_id: Arc<Mutex<u64>>,
+}
+
+impl Recorders {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self { dir, ..Self::default() }
+    }
+
+    /// Start recording the stream at `path` to a new file of the directory,
+    /// named after the stream and the time, which is returned.
+    pub fn start(&self, app_context: &AppContext, path: &str) -> Result<String, RecordError> {
+        let dir = self.dir.as_ref().ok_or(RecordError::Disabled)?;
+        let stream_def = app_context.stream(path).ok_or_else(|| RecordError::UnknownStream(path.to_string()))?;
+        let mut running = self.running.lock().unwrap();
+        if let Some(recording) = running.get(path) {
+            return Err(RecordError::Recording(recording.file.clone()));
+        }
+        let name = format!("{}_{}.ts", path.trim_matches('/').replace('/', "_"), iso8601(now_ms()).replace(':', "-"));
+        let file = dir.join(name).to_string_lossy().into_owned();
+        let out = match std::fs::create_dir_all(dir).and_then(|_| std::fs::File::create(&file)) {
+            Ok(out) => tokio::fs::File::from_std(out),
+            Err(e) => {
+                error!("Recording of {} to {} failed: {}", path, file, e);
+                app_context.events.emit("recording_failed", json!({ "stream": path, "file": file, "error": e.to_string() }));
+                return Err(RecordError::Io(e));
+            }
+        };
+
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            *next_id
+        };
+        let (stop_tx, stop_rx) = oneshot::channel();
+        running.insert(path.to_string(), Recording { id, file: file.clone(), stop_tx });
+        info!("Recording of {} to {} started", path, file);
+        app_context.events.emit("recording_started", json!({ "stream": path, "file": file }));
+        tokio::spawn(run(app_context.clone(), path.to_string(), stream_def, out, file.clone(), id, stop_rx));
+        Ok(file)
+    }
+
+    /// Stop recording the stream at `path`, returning the file if it was
+    /// recorded.
+    pub fn stop(&self, path: &str) -> Option<String> {
+        let recording = self.running.lock().unwrap().remove(path)?;
+        let _ = recording.stop_tx.send(());
+        Some(recording.file)
+    }
+
+    /// File the stream at `path` is recorded to.
+    pub fn file(&self, path: &str) -> Option<String> {
+        self.running.lock().unwrap().get(path).map(|recording| recording.file.clone())
+    }
+}
+
+/// Write the stream at `path` to `out` from its next keyframe, until stopped
+/// or the stream ends.
+async fn run(
+    app_context: AppContext,
+    path: String,
+    stream_def: Arc<Mutex<StreamsDef>>,
+    out: tokio::fs::File,
+    file: String,
+    id: u64,
+    mut stop_rx: oneshot::Receiver<()>,
+) {
+    // the recording counts as a viewer of the stream until it stops
+    let mut viewer = Viewer::new(stream_def, &path);
+    let mut client = ClientSession::new(&app_context.clients, "record", "recorder".to_string(), &path);
+    client.wait_keyframe = true;
+    let mut remuxer = mpegts::Remuxer::default();
+    let mut out = BufWriter::new(out);
+    let started = now_ms();
+    let mut bytes = 0;
+    let result = loop {
+        let frame = tokio::select! {
+            frame = viewer.rx.recv() => frame,
+            _ = &mut stop_rx => break Ok(()),
+        };
+        match frame {
+            Ok(frame) => {
+                if !client.accept(&frame) {
+                    continue;
+                }
+                let data = remuxer.remux(frame.clone());
+                if let Err(e) = out.write_all(&data).await {
+                    break Err(e);
+                }
+                bytes += data.len();
+                client.sent(&frame);
+            }
+            Err(RecvError::Lagged(n)) => {
+                warn!("Recording of {} lagged {} frames, waiting for next keyframe", path, n);
+                client.lagged(n);
+            }
+            Err(RecvError::Closed) => break Ok(()),
+        }
+    };
+    let result = match result {
+        Ok(()) => out.flush().await,
+        Err(e) => Err(e),
+    };
+    drop(viewer);
+
+    // the stream may be recorded again already
+    let mut running = app_context.recordings.running.lock().unwrap();
+    if running.get(&path).is_some_and(|recording| recording.id == id) {
+        running.remove(&path);
+    }
+    drop(running);
+    if let Err(e) = result {
+        error!("Recording of {} to {} failed: {}", path, file, e);
+        app_context.events.emit("recording_failed", json!({ "stream": path, "file": file, "error": e.to_string() }));
+    }
+    info!("Recording of {} to {} stopped", path, file);
+    let duration = ((now_ms() - started) / 1000.0) as u64;
+    app_context.events.emit("recording_stopped", json!({ "stream": path, "file": file, "bytes": bytes, "duration": duration }));
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/record/{name}",
+    params(("name" = String, Path, description = "Stream to record")),
+    responses(
+        (status = 200, description = "Recording started from the next keyframe, with the `file` written"),
+        (status = 403, description = "Recording is disabled, the config having no `recordings` directory"),
+        (status = 404, description = "Unknown stream"),
+        (status = 409, description = "Stream already recorded"),
+        (status = 500, description = "File could not be created")
+    )
+)]
+#[post("/api/record/{name:.*}")]
+pub async fn start_recording(name: web::Path<String>, data: web::Data<AppContext>) -> HttpResponse {
+    let app_context = data.get_ref();
+    let path = format!("/{}", name.trim_matches('/'));
+    match app_context.recordings.start(app_context, &path) {
+        Ok(file) => HttpResponse::Ok().json(json!({ "stream": path, "file": file })),
+        Err(e @ RecordError::Disabled) => HttpResponse::Forbidden().body(e.to_string()),
+        Err(e @ RecordError::UnknownStream(_)) => HttpResponse::NotFound().body(e.to_string()),
+        Err(e @ RecordError::Recording(_)) => HttpResponse::Conflict().body(e.to_string()),
+        Err(e @ RecordError::Io(_)) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/record/{name}",
+    params(("name" = String, Path, description = "Stream recorded")),
+    responses(
+        (status = 200, description = "Recording stopped, with the `file` written"),
+        (status = 404, description = "Stream not recorded")
+    )
+)]
+#[delete("/api/record/{name:.*}")]
+pub async fn stop_recording(name: web::Path<String>, data: web::Data<AppContext>) -> HttpResponse {
+    let path = format!("/{}", name.trim_matches('/'));
+    match data.recordings.stop(&path) {
+        Some(file) => HttpResponse::Ok().json(json!({ "stream": path, "file": file })),
+        None => HttpResponse::NotFound().body(format!("stream '{path}' is not recorded")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streamdef::DataFrame;
+    use std::time::Duration;
+
+    /// Context with a published stream "/cam", recorded to `dir`.
+    fn context(dir: &std::path::Path) -> (AppContext, Arc<Mutex<StreamsDef>>) {
+        let stream_def = Arc::new(Mutex::new(StreamsDef::published(None, 100)));
+        let mut app_context = AppContext::new(HashMap::from([("/cam".to_string(), stream_def.clone())]), None, None, None);
+        app_context.recordings = Recorders::new(Some(dir.to_path_buf()));
+        (app_context, stream_def)
+    }
+
+    /// Next event of `kind`.
+    async fn event(rx: &mut tokio::sync::broadcast::Receiver<bytes::Bytes>, kind: &str) -> serde_json::Value {
+        loop {
+            let event: serde_json::Value = serde_json::from_slice(&rx.recv().await.unwrap()).unwrap();
+            if event["type"] == kind {
+                return event;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_are_recorded_to_ts_files_from_their_next_keyframe() {
+        let dir = std::env::temp_dir().join(format!("rtsp2web-record-{}", std::process::id()));
+        let (app_context, stream_def) = context(&dir);
+        let mut events = app_context.events.subscribe();
+
+        assert!(matches!(Recorders::default().start(&app_context, "/cam"), Err(RecordError::Disabled)));
+        assert!(matches!(app_context.recordings.start(&app_context, "/other"), Err(RecordError::UnknownStream(_))));
+        let file = app_context.recordings.start(&app_context, "/cam").unwrap();
+        assert!(file.starts_with(dir.join("cam_").to_str().unwrap()) && file.ends_with(".ts"));
+        assert!(matches!(app_context.recordings.start(&app_context, "/cam"), Err(RecordError::Recording(_))));
+        assert_eq!(event(&mut events, "recording_started").await["file"], file);
+        assert_eq!(app_context.recordings.file("/cam").as_ref(), Some(&file));
+
+        let tx = stream_def.lock().unwrap().tx.clone();
+        // the recorder is a viewer of the stream, which drops the frames sent before it subscribed
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let codec = "avc1.42001E";
+        let delta = DataFrame::new(json!({ "media": "video", "codec": codec, "ts": 0.0 }), vec![0, 0, 0, 1, 0x41, 0x9a]);
+        let keyframe = DataFrame::new(json!({ "type": "keyframe", "media": "video", "codec": codec, "ts": 40.0 }), vec![0, 0, 0, 1, 0x65, 0x88]);
+        for frame in [delta.clone(), keyframe, delta] {
+            assert!(tx.send(frame).is_ok());
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(app_context.recordings.stop("/cam"), Some(file.clone()));
+        assert_eq!(app_context.recordings.stop("/cam"), None);
+        let stopped = event(&mut events, "recording_stopped").await;
+        assert_eq!(stopped["file"], file);
+
+        // PAT, PMT, and a packet for each frame from the keyframe
+        let data = std::fs::read(&file).unwrap();
+        assert_eq!(data.len(), 4 * 188);
+        assert_eq!(stopped["bytes"], data.len());
+        let pes = mpegts::Demuxer::default().push(&data);
+        assert_eq!(pes.len(), 1);
+        assert_eq!(pes[0].data, [0, 0, 0, 1, 0x09, 0xf0, 0, 0, 0, 1, 0x65, 0x88]);
+        assert_eq!(stream_def.lock().unwrap().count, 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn files_that_cannot_be_created_fail_the_recording() {
+        // a file in place of the directory
+        let dir = std::env::temp_dir().join(format!("rtsp2web-record-{}.ts", std::process::id()));
+        std::fs::write(&dir, b"").unwrap();
+        let (app_context, stream_def) = context(&dir);
+        let mut events = app_context.events.subscribe();
+
+        assert!(matches!(app_context.recordings.start(&app_context, "/cam"), Err(RecordError::Io(_))));
+        let failed = event(&mut events, "recording_failed").await;
+        assert_eq!(failed["stream"], "/cam");
+        assert!(failed["error"].is_string());
+        assert_eq!(app_context.recordings.file("/cam"), None);
+        assert_eq!(stream_def.lock().unwrap().count, 0);
+        std::fs::remove_file(&dir).unwrap();
+    }
+}
@@ -0,0 +1,563 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use anyhow::{anyhow, Error};
+use log::{info, warn};
+use rml_rtmp::handshake::{Handshake, HandshakeProcessResult, PeerType};
+use rml_rtmp::sessions::{
+    ClientSession as RtmpSession, ClientSessionConfig, ClientSessionEvent, ClientSessionResult, PublishRequestType,
+    StreamMetadata,
+};
+use rml_rtmp::time::RtmpTimestamp;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::appcontext::AppContext;
+use crate::clientsession::ClientSession;
+use crate::dataframe::now_ms;
+use crate::flv;
+use crate::streamdef::{DataFrame, StreamsDef, Viewer};
+use crate::tls::TlsOptions;
+
+pub const RTMP_PORT: u16 = 1935;
+pub const RTMPS_PORT: u16 = 443;
+
+/// Time given to the server to accept the connection and the publication.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay before reconnecting, doubled after each failure up to
+/// [`MAX_RECONNECT_DELAY`], and reset once a publication lasted that long.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Connecting,
+    Publishing,
+    /// Waiting to reconnect after a failure.
+    Waiting,
+    /// Given up, the stream cannot be carried over RTMP.
+    Stopped,
+}
+
+impl State {
+    fn as_str(&self) -> &'static str {
+        match self {
+            State::Connecting => "connecting",
+            State::Publishing => "publishing",
+            State::Waiting => "waiting",
+            State::Stopped => "stopped",
+        }
+    }
+}
+
+struct Status {
+    state: State,
+    since: f64,
+    reconnects: u32,
+    error: Option<String>,
+}
+
+/// An RTMP(S) server the frames of a stream are pushed to, from the "push"
+/// URLs of the stream config: `rtmp://host[:port]/app/key`.
+pub struct PushTarget {
+    url: url::Url,
+    app: String,
+    key: String,
+    status: Mutex<Status>,
+}
+
+impl PushTarget {
+    pub fn new(url: url::Url) -> Result<Self, Error> {
+        if !matches!(url.scheme(), "rtmp" | "rtmps") {
+            return Err(anyhow!("unsupported push URL scheme '{}'", url.scheme()));
+        }
+        if url.host_str().is_none() {
+            return Err(anyhow!("missing host in push URL"));
+        }
+        // the stream key is the last path segment, the application the others
+        let path = url.path().trim_matches('/');
+        let (app, key) = path
+            .rsplit_once('/')
+            .filter(|(app, key)| !app.is_empty() && !key.is_empty())
+            .ok_or_else(|| anyhow!("push URL has no application and stream key"))?;
+        let key = match url.query() {
+            Some(query) => format!("{}?{}", key, query),
+            None => key.to_string(),
+        };
+        Ok(Self {
+            app: app.to_string(),
+            key,
+            url,
+            status: Mutex::new(Status {
+                state: State::Connecting,
+                since: now_ms(),
+                reconnects: 0,
+                error: None,
+            }),
+        })
+    }
+
+    /// Targets of a stream config "push" value, a URL or an array of URLs.
+    pub fn from_json(value: &serde_json::Value) -> Result<Vec<Arc<Self>>, Error> {
+        let urls = match value {
+            serde_json::Value::String(url) => vec![url.as_str()],
+            serde_json::Value::Array(urls) => urls
+                .iter()
+                .map(|url| url.as_str().ok_or_else(|| anyhow!("push URLs must be strings")))
+                .collect::<Result<_, _>>()?,
+            _ => return Err(anyhow!("push must be a URL or an array of URLs")),
+        };
+        urls.into_iter()
+            .map(|url| Ok(Arc::new(Self::new(url::Url::parse(url)?)?)))
+            .collect()
+    }
+
+    /// URL of the application, the stream key being a secret.
+    pub fn tc_url(&self) -> String {
+        let mut url = self.url.clone();
+        url.set_path(&self.app);
+        url.set_query(None);
+        let _ = url.set_username("");
+        let _ = url.set_password(None);
+        url.to_string()
+    }
+
+    fn set_state(&self, state: State, error: Option<String>) {
+        let mut status = self.status.lock().unwrap();
+        if state == State::Waiting {
+            status.reconnects += 1;
+        }
+        status.state = state;
+        status.since = now_ms();
+        if error.is_some() || state == State::Publishing {
+            status.error = error;
+        }
+    }
+
+    /// Status reported in `/api/streams`, with the last error while the
+    /// target is not publishing.
+    pub fn to_json(&self) -> serde_json::Value {
+        let status = self.status.lock().unwrap();
+        json!({
+            "url": self.tc_url(),
+            "state": status.state.as_str(),
+            "since": status.since,
+            "reconnects": status.reconnects,
+            "error": status.error,
+        })
+    }
+}
+
+/// RTMP session on a connection, whose outgoing packets are written as they
+/// are produced.
+struct Connection<S> {
+    session: RtmpSession,
+    writer: WriteHalf<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite> Connection<S> {
+    /// Write the packets of `results` and return their events.
+    async fn apply(&mut self, results: Vec<ClientSessionResult>) -> Result<Vec<ClientSessionEvent>, Error> {
+        let mut events = vec![];
+        for result in results {
+            match result {
+                ClientSessionResult::OutboundResponse(packet) => self.writer.write_all(&packet.bytes).await?,
+                ClientSessionResult::RaisedEvent(event) => events.push(event),
+                ClientSessionResult::UnhandleableMessageReceived(_) => {}
+            }
+        }
+        Ok(events)
+    }
+}
+
+/// Read server messages until `expected` is raised.
+async fn wait_for<R, S>(reader: &mut R, connection: &mut Connection<S>, expected: ClientSessionEvent) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    S: AsyncRead + AsyncWrite,
+{
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Err(anyhow!("connection closed by server"));
+        }
+        let results = connection.session.handle_input(&buf[..n])?;
+        for event in connection.apply(results).await? {
+            match event {
+                event if event == expected => return Ok(()),
+                ClientSessionEvent::ConnectionRequestRejected { description } => {
+                    return Err(anyhow!("connection rejected: {}", description))
+                }
+                ClientSessionEvent::UnhandleableOnStatusCode { code } => return Err(anyhow!("server status {}", code)),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Publish the frames of the stream on an established connection, until it
+/// fails.
+async fn publish<S>(stream: S, app_context: &AppContext, path: &str, viewer: &mut Viewer, target: &PushTarget) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Send,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buf = vec![0u8; 4096];
+
+    let mut handshake = Handshake::new(PeerType::Client);
+    writer.write_all(&handshake.generate_outbound_p0_and_p1()?).await?;
+    let remaining = loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Err(anyhow!("connection closed during handshake"));
+        }
+        match handshake.process_bytes(&buf[..n])? {
+            HandshakeProcessResult::InProgress { response_bytes } => writer.write_all(&response_bytes).await?,
+            HandshakeProcessResult::Completed { response_bytes, remaining_bytes } => {
+                writer.write_all(&response_bytes).await?;
+                break remaining_bytes;
+            }
+        }
+    };
+
+    let mut config = ClientSessionConfig::new();
+    config.tc_url = Some(target.tc_url());
+    let (session, results) = RtmpSession::new(config)?;
+    let mut connection = Connection { session, writer };
+    connection.apply(results).await?;
+    let results = connection.session.handle_input(&remaining)?;
+    connection.apply(results).await?;
+
+    tokio::time::timeout(CONNECT_TIMEOUT, async {
+        let request = connection.session.request_connection(target.app.clone())?;
+        connection.apply(vec![request]).await?;
+        wait_for(&mut reader, &mut connection, ClientSessionEvent::ConnectionRequestAccepted).await?;
+        let request = connection.session.request_publishing(target.key.clone(), PublishRequestType::Live)?;
+        connection.apply(vec![request]).await?;
+        wait_for(&mut reader, &mut connection, ClientSessionEvent::PublishRequestAccepted).await
+    })
+    .await
+    .map_err(|_| anyhow!("server did not accept the publication"))??;
+
+    info!("RTMP push of {} to {} publishing", path, target.tc_url());
+    target.set_state(State::Publishing, None);
+
+    // frames buffered while disconnected are stale, the receiver starts
+    // again with the configurations describing the stream in the metadata
+    viewer.rx = viewer.rx.resubscribe();
+    let mut client = ClientSession::new(&app_context.clients, "rtmp", target.tc_url(), path);
+    let mut remuxer = flv::Remuxer::default();
+    let mut metadata = StreamMetadata::new();
+    metadata.encoder = Some(concat!("rtsp2web-rs/", env!("CARGO_PKG_VERSION")).to_string());
+    let mut metadata_sent = false;
+    loop {
+        tokio::select! {
+            frame = viewer.rx.recv() => match frame {
+                Ok(frame) => {
+                    if !client.accept(&frame) {
+                        continue;
+                    }
+                    if frame.is_config() {
+                        describe(&mut metadata, &frame);
+                    }
+                    let tags = remuxer.remux(&frame)?;
+                    if !metadata_sent && !tags.is_empty() {
+                        let request = connection.session.publish_metadata(&metadata)?;
+                        connection.apply(vec![request]).await?;
+                        metadata_sent = true;
+                    }
+                    for tag in tags {
+                        let timestamp = RtmpTimestamp::new(tag.ts);
+                        let request = match tag.tag_type {
+                            flv::TAG_AUDIO => connection.session.publish_audio_data(tag.data, timestamp, false)?,
+                            _ => connection.session.publish_video_data(tag.data, timestamp, false)?,
+                        };
+                        connection.apply(vec![request]).await?;
+                    }
+                    client.sent(&frame);
+                }
+                Err(RecvError::Lagged(n)) => {
+                    warn!("RTMP push of {} lagged {} frames, waiting for next keyframe", path, n);
+                    client.lagged(n);
+                }
+                Err(RecvError::Closed) => return Err(anyhow!("stream closed")),
+            },
+            read = reader.read(&mut buf) => {
+                let n = read?;
+                if n == 0 {
+                    return Err(anyhow!("connection closed by server"));
+                }
+                let results = connection.session.handle_input(&buf[..n])?;
+                for event in connection.apply(results).await? {
+                    if let ClientSessionEvent::UnhandleableOnStatusCode { code } = event {
+                        return Err(anyhow!("server status {}", code));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Describe the media of a configuration frame in the stream metadata.
+fn describe(metadata: &mut StreamMetadata, config: &DataFrame) {
+    let field = |name: &str| config.metadata[name].as_u64().map(|value| value as u32);
+    let codec = config.metadata["codec"].as_str().unwrap_or_default();
+    match config.metadata["media"].as_str() {
+        Some("video") if codec.starts_with("avc1") => {
+            metadata.video_codec_id = Some(flv::CODEC_AVC.into());
+            metadata.video_width = field("width");
+            metadata.video_height = field("height");
+        }
+        Some("audio") if codec.starts_with("mp4a.40.") => {
+            metadata.audio_codec_id = Some(flv::SOUND_FORMAT_AAC.into());
+            metadata.audio_sample_rate = field("sample_rate");
+            metadata.audio_channels = field("channels");
+            metadata.audio_is_stereo = field("channels").map(|channels| channels == 2);
+        }
+        _ => {}
+    }
+}
+
+async fn push(app_context: &AppContext, path: &str, viewer: &mut Viewer, target: &PushTarget) -> Result<(), Error> {
+    let host = target.url.host_str().ok_or_else(|| anyhow!("missing host in push URL"))?;
+    if target.url.scheme() == "rtmps" {
+        let port = target.url.port().unwrap_or(RTMPS_PORT);
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TlsOptions::default().connect(host, port))
+            .await
+            .map_err(|_| anyhow!("connection timed out"))??;
+        publish(stream, app_context, path, viewer, target).await
+    } else {
+        let port = target.url.port().unwrap_or(RTMP_PORT);
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port)))
+            .await
+            .map_err(|_| anyhow!("connection timed out"))??;
+        stream.set_nodelay(true)?;
+        publish(stream, app_context, path, viewer, target).await
+    }
+}
+
+/// Keep pushing the frames of the stream at `path` to `target`, reconnecting
+/// after failures.
+pub async fn run(app_context: AppContext, path: String, stream_def: Arc<Mutex<StreamsDef>>, target: Arc<PushTarget>) {
+    // kept while reconnecting, so that the camera keeps being pulled
+    let mut viewer = Viewer::new(stream_def, &path);
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        target.set_state(State::Connecting, None);
+        let started = Instant::now();
+        let error = match push(&app_context, &path, &mut viewer, &target).await {
+            Ok(()) => anyhow!("publication ended"),
+            Err(e) => e,
+        };
+        // reconnecting would not change the codec
+        if let Some(e) = error.downcast_ref::<flv::UnsupportedCodec>() {
+            warn!("RTMP push of {} to {} stopped: {}", path, target.tc_url(), e);
+            target.set_state(State::Stopped, Some(e.to_string()));
+            return;
+        }
+        // the stream was removed or changed by a config reload
+        if !app_context.stream(&path).is_some_and(|current| Arc::ptr_eq(&current, viewer.stream_def())) {
+            info!("RTMP push of {} to {} stopped: stream removed", path, target.tc_url());
+            target.set_state(State::Stopped, Some("stream removed".to_string()));
+            return;
+        }
+        warn!("RTMP push of {} to {} failed: {}", path, target.tc_url(), error);
+        target.set_state(State::Waiting, Some(error.to_string()));
+        if started.elapsed() > MAX_RECONNECT_DELAY {
+            delay = RECONNECT_DELAY;
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rml_rtmp::sessions::{ServerSession, ServerSessionConfig, ServerSessionEvent, ServerSessionResult};
+    use std::collections::HashMap;
+    use tokio::net::TcpListener;
+    use crate::rtspclient;
+    use crate::streamdef::FrameSender;
+    use tokio::sync::mpsc;
+
+    const SPS: &[u8] = &[0x67, 0x64, 0x00, 0x1e, 0xac, 0xd9, 0x40, 0xa0, 0x2f, 0xf9, 0x61, 0x00, 0x00, 0x03, 0x00, 0x01, 0x00, 0x00, 0x03, 0x00, 0x3c, 0x8f, 0x16, 0x2d, 0x96];
+    const PPS: &[u8] = &[0x68, 0xeb, 0xec, 0xb2, 0x2c];
+
+    /// RTMP server accepting the publication of one client and forwarding
+    /// the other events of its session.
+    async fn serve(listener: TcpListener, events: mpsc::UnboundedSender<ServerSessionEvent>) -> Result<(), Error> {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = vec![0u8; 65536];
+        let mut handshake = Handshake::new(PeerType::Server);
+        let remaining = loop {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Err(anyhow!("connection closed during handshake"));
+            }
+            match handshake.process_bytes(&buf[..n])? {
+                HandshakeProcessResult::InProgress { response_bytes } => stream.write_all(&response_bytes).await?,
+                HandshakeProcessResult::Completed { response_bytes, remaining_bytes } => {
+                    stream.write_all(&response_bytes).await?;
+                    break remaining_bytes;
+                }
+            }
+        };
+        let (mut session, mut results) = ServerSession::new(ServerSessionConfig::new())?;
+        results.extend(session.handle_input(&remaining)?);
+        loop {
+            while !results.is_empty() {
+                for result in std::mem::take(&mut results) {
+                    match result {
+                        ServerSessionResult::OutboundResponse(packet) => stream.write_all(&packet.bytes).await?,
+                        ServerSessionResult::RaisedEvent(
+                            ServerSessionEvent::ConnectionRequested { request_id, .. }
+                            | ServerSessionEvent::PublishStreamRequested { request_id, .. },
+                        ) => results.extend(session.accept_request(request_id)?),
+                        ServerSessionResult::RaisedEvent(event) => {
+                            let _ = events.send(event);
+                        }
+                        ServerSessionResult::UnhandleableMessageReceived(_) => {}
+                    }
+                }
+            }
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            results = session.handle_input(&buf[..n])?;
+        }
+    }
+
+    fn video_config(codec: &str) -> DataFrame {
+        DataFrame::new(json!({ "type": "config", "media": "video", "codec": codec, "width": 1280, "height": 720 }), vec![])
+    }
+
+    fn keyframe() -> DataFrame {
+        let data: Vec<u8> = [SPS, PPS, &[0x65, 0x88, 0x84, 0x00]]
+            .iter()
+            .flat_map(|nal| [&[0, 0, 0, 1][..], nal].concat())
+            .collect();
+        DataFrame::new(json!({ "type": "keyframe", "media": "video", "codec": "avc1.64001E", "ts": 0.0 }), data)
+    }
+
+    /// A stream pushed to a stand-in server.
+    struct Push {
+        stream_def: Arc<Mutex<StreamsDef>>,
+        tx: FrameSender,
+        target: Arc<PushTarget>,
+        events: mpsc::UnboundedReceiver<ServerSessionEvent>,
+        task: tokio::task::JoinHandle<()>,
+    }
+
+    /// Push a stream fed by `configs` to a stand-in server.
+    async fn start(configs: Vec<DataFrame>) -> Push {
+        let stream_def = Arc::new(Mutex::new(StreamsDef::published(None, 100)));
+        for config in configs {
+            let _ = stream_def.lock().unwrap().tx.send(config);
+        }
+        push(stream_def).await
+    }
+
+    async fn push(stream_def: Arc<Mutex<StreamsDef>>) -> Push {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!("rtmp://127.0.0.1:{}/live/key", listener.local_addr().unwrap().port())).unwrap();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        tokio::spawn(serve(listener, events_tx));
+
+        let tx = stream_def.lock().unwrap().tx.clone();
+        let target = Arc::new(PushTarget::new(url).unwrap());
+        let app_context = AppContext::new(HashMap::new(), None, None, None);
+        let task = tokio::spawn(run(app_context, "/cam".to_string(), stream_def.clone(), target.clone()));
+        Push { stream_def, tx, target, events, task }
+    }
+
+    #[tokio::test]
+    async fn pushes_the_metadata_before_the_frames() {
+        let audio_config = DataFrame::new(json!({ "type": "config", "media": "audio", "codec": "mp4a.40.2", "sample_rate": 44100, "channels": 2 }), vec![0x12, 0x10]);
+        let mut push = start(vec![video_config("avc1.64001E"), audio_config]).await;
+
+        let mut metadata = None;
+        let video = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                tokio::select! {
+                    event = push.events.recv() => match event.expect("server stopped") {
+                        ServerSessionEvent::StreamMetadataChanged { metadata: m, .. } => metadata = Some(m),
+                        ServerSessionEvent::VideoDataReceived { data, .. } => return data,
+                        _ => {}
+                    },
+                    // the keyframes broadcast before the publication starts are skipped
+                    _ = tokio::time::sleep(Duration::from_millis(50)) => {
+                        let _ = push.tx.send(keyframe());
+                    }
+                }
+            }
+        })
+        .await
+        .expect("no video received");
+
+        let metadata = metadata.expect("no metadata before the video");
+        assert_eq!(metadata.video_codec_id, Some(flv::CODEC_AVC.into()));
+        assert_eq!((metadata.video_width, metadata.video_height), (Some(1280), Some(720)));
+        assert_eq!(metadata.audio_codec_id, Some(flv::SOUND_FORMAT_AAC.into()));
+        assert_eq!(metadata.audio_sample_rate, Some(44100));
+        assert_eq!(metadata.audio_is_stereo, Some(true));
+        // AVC sequence header
+        assert_eq!(&video[..2], &[0x17, 0]);
+        assert_eq!(push.target.to_json()["state"], "publishing");
+        push.task.abort();
+    }
+
+    #[tokio::test]
+    async fn pushes_the_aac_track_of_cameras() {
+        let url = rtspclient::tests::camera().await;
+        let source = rtspclient::Source::new(url, Some("tcp".to_string()));
+        let mut push = push(Arc::new(Mutex::new(StreamsDef::new(source, 100)))).await;
+
+        let mut metadata = None;
+        let mut audio = vec![];
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while audio.len() < 2 {
+                match push.events.recv().await.expect("server stopped") {
+                    ServerSessionEvent::StreamMetadataChanged { metadata: m, .. } => metadata = Some(m),
+                    ServerSessionEvent::AudioDataReceived { data, .. } => audio.push(data),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("no audio received");
+
+        let metadata = metadata.expect("no metadata before the audio");
+        assert_eq!(metadata.audio_codec_id, Some(flv::SOUND_FORMAT_AAC.into()));
+        assert_eq!(metadata.audio_sample_rate, Some(44100));
+        // AAC sequence header, then raw frames
+        assert_eq!(&audio[0][..], [&[0xaf, 0][..], rtspclient::tests::AUDIO_SPECIFIC_CONFIG].concat());
+        assert_eq!(&audio[1][..], [&[0xaf, 1][..], rtspclient::tests::AAC_FRAME].concat());
+        push.task.abort();
+    }
+
+    #[tokio::test]
+    async fn stops_on_a_codec_flv_cannot_carry() {
+        let push = start(vec![video_config("hvc1.1.6.L93.B0")]).await;
+
+        tokio::time::timeout(Duration::from_secs(5), push.task).await.expect("push not stopped").unwrap();
+        let status = push.target.to_json();
+        assert_eq!(status["state"], "stopped");
+        assert!(status["error"].as_str().unwrap().contains("hvc1"));
+        assert_eq!(status["reconnects"], 0);
+        assert_eq!(push.stream_def.lock().unwrap().count, 0);
+    }
+}
@@ -0,0 +1,743 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use anyhow::{anyhow, Error};
+use base64::Engine;
+use log::{debug, info};
+use md5::{Digest, Md5};
+use retina::codec::{CodecItem, Depacketizer, FrameFormat, ParametersRef, VideoParameters};
+use retina::rtp::ReceivedPacketBuilder;
+use retina::{PacketContext, Timestamp};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::num::{NonZeroU16, NonZeroU32};
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::rtspclient::{self, WallClock};
+use crate::streamdef::FrameSender;
+
+/// Largest RTSP body accepted, SDP descriptions are much smaller.
+const MAX_BODY: usize = 64 * 1024;
+
+/// An RTSP request or response.
+#[derive(Clone, Debug, Default)]
+pub struct Message {
+    /// Request line or status line.
+    pub start_line: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Message {
+    pub fn request(method: &str, url: &str) -> Self {
+        Self {
+            start_line: format!("{method} {url} RTSP/1.0"),
+            ..Default::default()
+        }
+    }
+
+    pub fn response(status: u16, reason: &str) -> Self {
+        Self {
+            start_line: format!("RTSP/1.0 {status} {reason}"),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: impl ToString) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body(mut self, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self.with_header("Content-Type", content_type)
+    }
+
+    /// Value of a header, matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Method and URL of a request.
+    pub fn method(&self) -> Option<(&str, &str)> {
+        let mut parts = self.start_line.split_whitespace();
+        Some((parts.next()?, parts.next()?))
+    }
+
+    /// Status code of a response.
+    pub fn status(&self) -> Option<u16> {
+        self.start_line.split_whitespace().nth(1)?.parse().ok()
+    }
+
+    /// Session identifier, without its parameters.
+    pub fn session(&self) -> Option<&str> {
+        self.header("Session").map(|s| s.split(';').next().unwrap_or(s).trim())
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<(), Error> {
+        let mut out = format!("{}\r\n", self.start_line);
+        for (name, value) in &self.headers {
+            out.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !self.body.is_empty() {
+            out.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        out.push_str("\r\n");
+        let mut bytes = out.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        writer.write_all(&bytes).await?;
+        Ok(())
+    }
+
+    /// Read the next message, `None` when the connection is closed.
+    pub async fn read_from<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Self>, Error> {
+        let mut message = Message::default();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 {
+                return Ok(None);
+            }
+            let line = line.trim_end();
+            if message.start_line.is_empty() {
+                // tolerate blank lines between messages
+                if !line.is_empty() {
+                    message.start_line = line.to_string();
+                }
+                continue;
+            }
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| anyhow!("invalid RTSP header '{}'", line))?;
+            message.headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        let length = match message.header("Content-Length") {
+            Some(length) => length.parse::<usize>()?,
+            None => 0,
+        };
+        if length > MAX_BODY {
+            return Err(anyhow!("RTSP body too large ({} bytes)", length));
+        }
+        message.body = vec![0; length];
+        reader.read_exact(&mut message.body).await?;
+        Ok(Some(message))
+    }
+}
+
+/// A media description of an SDP session.
+#[derive(Debug, Default)]
+pub struct SdpMedia {
+    pub media: String,
+    pub payload_type: u8,
+    /// Lowercase encoding name, e.g. "h264".
+    pub encoding_name: String,
+    pub clock_rate: u32,
+    pub channels: Option<u16>,
+    pub fmtp: Option<String>,
+    pub control: Option<String>,
+    /// Connection address of the media, or of the session.
+    pub connection: Option<String>,
+}
+
+/// Parse the media descriptions of an SDP session, with their first payload type.
+pub fn parse_sdp(sdp: &str) -> Vec<SdpMedia> {
+    let mut medias: Vec<SdpMedia> = vec![];
+    let mut session_connection = None;
+    for line in sdp.lines().map(str::trim) {
+        let Some((kind, value)) = line.split_once('=') else { continue };
+        match (kind, medias.last_mut()) {
+            ("m", _) => {
+                let fields: Vec<&str> = value.split_whitespace().collect();
+                medias.push(SdpMedia {
+                    media: fields.first().unwrap_or(&"").to_string(),
+                    payload_type: fields.get(3).and_then(|pt| pt.parse().ok()).unwrap_or_default(),
+                    connection: session_connection.clone(),
+                    ..Default::default()
+                });
+            }
+            ("c", media) => {
+                // c=IN IP4 239.1.1.1/16
+                let address = value.split_whitespace().nth(2).map(|a| a.split('/').next().unwrap_or(a).to_string());
+                match media {
+                    Some(media) => media.connection = address,
+                    None => session_connection = address,
+                }
+            }
+            ("a", Some(media)) => {
+                let (attribute, value) = value.split_once(':').unwrap_or((value, ""));
+                match attribute {
+                    "rtpmap" => {
+                        // a=rtpmap:96 H264/90000[/channels]
+                        let Some((pt, encoding)) = value.split_once(' ') else { continue };
+                        if pt.parse() != Ok(media.payload_type) {
+                            continue;
+                        }
+                        let mut parts = encoding.split('/');
+                        media.encoding_name = parts.next().unwrap_or_default().to_ascii_lowercase();
+                        media.clock_rate = parts.next().and_then(|r| r.parse().ok()).unwrap_or_default();
+                        media.channels = parts.next().and_then(|c| c.parse().ok());
+                    }
+                    "fmtp" => {
+                        if let Some((pt, fmtp)) = value.split_once(' ') {
+                            if pt.parse() == Ok(media.payload_type) {
+                                media.fmtp = Some(fmtp.to_string());
+                            }
+                        }
+                    }
+                    "control" => media.control = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    medias
+}
+
+/// Resolve the control attribute of a media against the base URL of the session.
+pub fn control_url(base: &url::Url, control: Option<&str>) -> Result<url::Url, Error> {
+    match control {
+        None | Some("*") => Ok(base.clone()),
+        Some(control) if control.contains("://") => Ok(url::Url::parse(control)?),
+        Some(control) => {
+            let mut base = base.clone();
+            if !base.path().ends_with('/') {
+                base.set_path(&format!("{}/", base.path()));
+            }
+            Ok(base.join(control)?)
+        }
+    }
+}
+/// Fields of an RTP packet header needed by the depacketizer.
+pub struct RtpHeader<'a> {
+    pub mark: bool,
+    pub payload_type: u8,
+    pub sequence_number: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub payload: &'a [u8],
+}
+
+fn parse_rtp(data: &[u8]) -> Option<RtpHeader<'_>> {
+    if data.len() < 12 || data[0] >> 6 != 2 {
+        return None;
+    }
+    let mut start = 12 + 4 * usize::from(data[0] & 0x0f);
+    if data[0] & 0x10 != 0 {
+        let words = u16::from_be_bytes([*data.get(start + 2)?, *data.get(start + 3)?]);
+        start += 4 + 4 * usize::from(words);
+    }
+    let mut end = data.len();
+    if data[0] & 0x20 != 0 {
+        end = end.checked_sub(usize::from(*data.last()?))?;
+    }
+    Some(RtpHeader {
+        mark: data[1] & 0x80 != 0,
+        payload_type: data[1] & 0x7f,
+        sequence_number: u16::from_be_bytes([data[2], data[3]]),
+        timestamp: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+        ssrc: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+        payload: data.get(start..end)?,
+    })
+}
+
+/// Extension of the 32-bit RTP timestamps, which wrap around.
+struct Timeline {
+    clock_rate: NonZeroU32,
+    start: Option<u32>,
+    last: u32,
+    extended: i64,
+}
+
+impl Timeline {
+    fn advance(&mut self, timestamp: u32) -> Option<Timestamp> {
+        match self.start {
+            None => {
+                self.start = Some(timestamp);
+                self.extended = i64::from(timestamp);
+            }
+            Some(_) => self.extended += i64::from(timestamp.wrapping_sub(self.last) as i32),
+        }
+        self.last = timestamp;
+        Timestamp::new(self.extended, self.clock_rate, self.start?)
+    }
+
+    /// Position of `timestamp` relative to the last one, without advancing.
+    fn at(&self, timestamp: u32) -> Option<Timestamp> {
+        let extended = self.extended + i64::from(timestamp.wrapping_sub(self.last) as i32);
+        Timestamp::new(extended, self.clock_rate, self.start?)
+    }
+}
+
+/// Depacketization of the RTP packets of an SDP media received outside of a
+/// retina session, from a multicast group or a publisher. It relies on the
+/// hidden packet API of retina, hence its exact version in Cargo.toml.
+pub struct RtpReceiver {
+    pub payload_type: u8,
+    stream_id: usize,
+    depacketizer: Depacketizer,
+    timeline: Timeline,
+    next_sequence: Option<u16>,
+    received: Instant,
+    /// Wall clock of the last sender report, giving the `ntp` of the frames.
+    wallclock: Option<WallClock>,
+}
+
+impl RtpReceiver {
+    pub fn new(stream_id: usize, media: &SdpMedia) -> Result<Self, Error> {
+        let clock_rate = NonZeroU32::new(media.clock_rate).ok_or_else(|| anyhow!("missing {} clock rate", media.media))?;
+        let channels = media.channels.and_then(NonZeroU16::new);
+        let mut depacketizer = Depacketizer::new(&media.media, &media.encoding_name, media.clock_rate, channels, media.fmtp.as_deref())
+            .map_err(|e| anyhow!(e))?;
+        depacketizer.set_frame_format(FrameFormat::SIMPLE);
+        Ok(Self {
+            payload_type: media.payload_type,
+            stream_id,
+            depacketizer,
+            timeline: Timeline { clock_rate, start: None, last: 0, extended: 0 },
+            next_sequence: None,
+            received: Instant::now(),
+            wallclock: None,
+        })
+    }
+
+    pub fn parameters(&self) -> Option<ParametersRef<'_>> {
+        self.depacketizer.parameters()
+    }
+
+    /// Push an RTP packet received at `received`, skipping other payload
+    /// types and late packets.
+    pub fn push(&mut self, data: &[u8], received: Instant) -> Result<(), Error> {
+        match parse_rtp(data) {
+            Some(rtp) => self.push_rtp(rtp, received),
+            None => Ok(()),
+        }
+    }
+
+    pub fn push_rtp(&mut self, rtp: RtpHeader<'_>, received: Instant) -> Result<(), Error> {
+        if rtp.payload_type != self.payload_type {
+            return Ok(());
+        }
+        self.received = received;
+        let loss = self.next_sequence.map(|s| rtp.sequence_number.wrapping_sub(s)).unwrap_or(0);
+        if loss >= 0x8000 {
+            debug!("dropping late packet {}", rtp.sequence_number);
+            return Ok(());
+        }
+        self.next_sequence = Some(rtp.sequence_number.wrapping_add(1));
+        let Some(timestamp) = self.timeline.advance(rtp.timestamp) else { return Ok(()) };
+        // the context of the packet is only known to retina's own sessions,
+        // the receive time is kept by `received`
+        let packet = ReceivedPacketBuilder {
+            ctx: PacketContext::dummy(),
+            stream_id: self.stream_id,
+            sequence_number: rtp.sequence_number,
+            timestamp,
+            payload_type: rtp.payload_type,
+            ssrc: rtp.ssrc,
+            mark: rtp.mark,
+            loss,
+        }
+        .build(rtp.payload.iter().copied())
+        .map_err(|e| anyhow!("invalid RTP packet: {}", e))?;
+        self.depacketizer.push(packet).map_err(|e| anyhow!(e))
+    }
+
+    /// Push a payload that did not come over RTP, numbered after the previous
+    /// one. After a `loss` a number is skipped, telling the depacketizer
+    /// about it.
+    pub fn push_payload(&mut self, timestamp: u32, mark: bool, payload: &[u8], loss: bool, received: Instant) -> Result<(), Error> {
+        let rtp = RtpHeader {
+            mark,
+            payload_type: self.payload_type,
+            sequence_number: self.next_sequence.unwrap_or(0).wrapping_add(u16::from(loss)),
+            timestamp,
+            ssrc: 0,
+            payload,
+        };
+        self.push_rtp(rtp, received)
+    }
+
+    /// Take the wall clock of the sender reports of an RTCP compound packet.
+    pub fn push_rtcp(&mut self, data: &[u8]) {
+        let mut data = data;
+        while data.len() >= 4 {
+            let len = (usize::from(u16::from_be_bytes([data[2], data[3]])) + 1) * 4;
+            if data[0] >> 6 != 2 || len > data.len() {
+                debug!("ignoring invalid RTCP packet");
+                return;
+            }
+            // header, SSRC, NTP and RTP timestamps, packet and octet counts
+            if data[1] == 200 && len >= 28 {
+                let ntp = u64::from_be_bytes(data[8..16].try_into().unwrap_or_default());
+                let timestamp = u32::from_be_bytes(data[16..20].try_into().unwrap_or_default());
+                self.sender_report(rtspclient::ntp_ms(ntp), timestamp);
+            }
+            data = &data[len..];
+        }
+    }
+
+    /// Take the wall clock of a sender report mapping `ntp_ms`, in
+    /// milliseconds since the Unix epoch, to the RTP `timestamp`. Reports
+    /// coming before the first packet are skipped, the next ones follow.
+    pub fn sender_report(&mut self, ntp_ms: f64, timestamp: u32) {
+        if let Some(rtp) = self.timeline.at(timestamp) {
+            self.wallclock = Some(WallClock::new(ntp_ms, rtp));
+        }
+    }
+
+    /// Broadcast the video frames completed by the pushed packets, after a
+    /// config frame when their parameters change. The parameters are kept in
+    /// `video_params`, frames coming before any are skipped.
+    pub fn broadcast_video(&mut self, video_params: &mut Option<VideoParameters>, with_sei: bool, tx: &FrameSender) -> Result<(), Error> {
+        while let Some(item) = self.pull() {
+            let CodecItem::VideoFrame(m) = item? else { continue };
+            if m.has_new_parameters() {
+                if let Some(ParametersRef::Video(v)) = self.parameters() {
+                    info!("new video_params:{:?}", v);
+                    rtspclient::send_frame(tx, rtspclient::config_frame(v));
+                    *video_params = Some(v.clone());
+                }
+            }
+            match video_params {
+                Some(video_params) => rtspclient::process_video_frame(m, self.received, video_params, self.wallclock.as_ref(), with_sei, tx),
+                None => debug!("skipping frame received before video parameters"),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn pull(&mut self) -> Option<Result<CodecItem, Error>> {
+        self.depacketizer.pull().map(|item| item.map_err(|e| anyhow!("{:?}", e)))
+    }
+}
+
+/// Credentials expected from publishers, in a Basic `Authorization` header or
+/// as the Bearer token of WHIP clients, which then is the password.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub user: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// Credentials of a `{"user": ..., "password": ...}` object.
+    pub fn from_json(value: &serde_json::Value) -> Result<Self, Error> {
+        let field = |name: &str| {
+            value[name]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("'{}' is missing or not a string", name))
+        };
+        Ok(Self { user: field("user")?, password: field("password")? })
+    }
+
+    /// Credentials of the userinfo of a camera URL, percent-decoded.
+    pub fn from_url(url: &url::Url) -> Option<Self> {
+        if url.username().is_empty() {
+            return None;
+        }
+        let decode = |s: &str| percent_encoding::percent_decode_str(s).decode_utf8_lossy().to_string();
+        Some(Self { user: decode(url.username()), password: decode(url.password().unwrap_or_default()) })
+    }
+
+    /// Set the credentials as the userinfo of a camera URL.
+    pub fn set_in_url(&self, url: &mut url::Url) -> Result<(), Error> {
+        url.set_username(&self.user)
+            .and_then(|_| url.set_password(Some(&self.password)))
+            .map_err(|_| anyhow!("cannot set credentials in {}", url))
+    }
+
+    /// Whether the `Authorization` header of a `method` request presents
+    /// these credentials, as a Bearer token, Basic or Digest answering
+    /// [`digest_challenge`].
+    pub fn authorize(&self, authorization: Option<&str>, method: &str) -> bool {
+        let Some((scheme, value)) = authorization.and_then(|a| a.trim().split_once(' ')) else {
+            return false;
+        };
+        if scheme.eq_ignore_ascii_case("Bearer") {
+            return constant_time_eq(value.trim().as_bytes(), self.password.as_bytes());
+        }
+        if scheme.eq_ignore_ascii_case("Digest") {
+            return self.authorize_digest(value, method);
+        }
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return false;
+        }
+        let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(value.trim()) else {
+            return false;
+        };
+        constant_time_eq(&decoded, format!("{}:{}", self.user, self.password).as_bytes())
+    }
+
+    fn authorize_digest(&self, params: &str, method: &str) -> bool {
+        let param = |name: &str| auth_param(params, name);
+        let (Some(user), Some(realm), Some(nonce), Some(uri), Some(response)) =
+            (param("username"), param("realm"), param("nonce"), param("uri"), param("response"))
+        else {
+            return false;
+        };
+        if user != self.user || realm != REALM || !nonce_is_valid(&nonce) {
+            return false;
+        }
+        let ha1 = md5_hex(&format!("{}:{}:{}", self.user, realm, self.password));
+        let ha2 = md5_hex(&format!("{method}:{uri}"));
+        let expected = match (param("qop").as_deref(), param("nc"), param("cnonce")) {
+            (None, _, _) => md5_hex(&format!("{ha1}:{nonce}:{ha2}")),
+            (Some("auth"), Some(nc), Some(cnonce)) => md5_hex(&format!("{ha1}:{nonce}:{nc}:{cnonce}:auth:{ha2}")),
+            _ => return false,
+        };
+        constant_time_eq(expected.as_bytes(), response.to_ascii_lowercase().as_bytes())
+    }
+}
+
+/// Realm of the challenges sent to publishers.
+const REALM: &str = "rtsp2web-rs";
+/// Seconds a nonce of [`digest_challenge`] is accepted for. Nonces are not
+/// stored, a response replayed in that time is not detected.
+const NONCE_LIFETIME: u64 = 300;
+
+/// Key of the process, signing the nonces and hiding the timing of
+/// credential comparisons.
+fn process_key() -> &'static hmac::Key {
+    static KEY: OnceLock<hmac::Key> = OnceLock::new();
+    KEY.get_or_init(|| hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new()).expect("no random source"))
+}
+
+/// Whether `a` equals `b`, in a time not depending on where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let tag = hmac::sign(process_key(), a);
+    hmac::verify(process_key(), b, tag.as_ref()).is_ok()
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Nonce issued at `time`, signed so that it needs no state to be checked.
+fn nonce(time: u64) -> String {
+    let tag = hmac::sign(process_key(), &time.to_be_bytes());
+    let signature: String = tag.as_ref()[..16].iter().map(|b| format!("{b:02x}")).collect();
+    format!("{time:x}.{signature}")
+}
+
+fn nonce_is_valid(value: &str) -> bool {
+    let Some(time) = value.split_once('.').and_then(|(time, _)| u64::from_str_radix(time, 16).ok()) else {
+        return false;
+    };
+    unix_time().checked_sub(time).is_some_and(|age| age <= NONCE_LIFETIME)
+        && constant_time_eq(nonce(time).as_bytes(), value.as_bytes())
+}
+
+/// `WWW-Authenticate` header asking a publisher for Digest credentials.
+pub fn digest_challenge() -> String {
+    format!(r#"Digest realm="{REALM}", nonce="{}", algorithm=MD5, qop="auth""#, nonce(unix_time()))
+}
+
+/// Authentication requested by a server in its `WWW-Authenticate` headers.
+#[derive(Clone, Debug)]
+pub enum Challenge {
+    Basic,
+    Digest {
+        realm: String,
+        nonce: String,
+        opaque: Option<String>,
+        /// Whether the server accepts `qop=auth`, requiring a client nonce.
+        qop: bool,
+    },
+}
+
+impl Challenge {
+    /// Strongest challenge of a 401 response, Digest being preferred.
+    pub fn parse(response: &Message) -> Option<Self> {
+        let mut challenge = None;
+        for (name, value) in &response.headers {
+            if !name.eq_ignore_ascii_case("WWW-Authenticate") {
+                continue;
+            }
+            let (scheme, params) = value.trim().split_once(' ').unwrap_or((value.trim(), ""));
+            if scheme.eq_ignore_ascii_case("Digest") {
+                let param = |name: &str| auth_param(params, name);
+                let algorithm = param("algorithm").unwrap_or_else(|| "MD5".to_string());
+                if !algorithm.eq_ignore_ascii_case("MD5") {
+                    debug!("unsupported digest algorithm {}", algorithm);
+                    continue;
+                }
+                return Some(Challenge::Digest {
+                    realm: param("realm")?,
+                    nonce: param("nonce")?,
+                    opaque: param("opaque"),
+                    qop: param("qop").is_some_and(|qop| qop.split(',').any(|qop| qop.trim() == "auth")),
+                });
+            }
+            if scheme.eq_ignore_ascii_case("Basic") {
+                challenge = Some(Challenge::Basic);
+            }
+        }
+        challenge
+    }
+}
+
+/// Value of a parameter of an authentication header, unquoted.
+fn auth_param(params: &str, name: &str) -> Option<String> {
+    let mut rest = params;
+    while !rest.is_empty() {
+        let (key, value) = rest.split_once('=')?;
+        let value = value.trim_start();
+        let (value, next) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], quoted[end + 1..].trim_start_matches([' ', ',']))
+            }
+            None => match value.split_once(',') {
+                Some((value, next)) => (value.trim(), next.trim_start()),
+                None => (value.trim(), ""),
+            },
+        };
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(value.to_string());
+        }
+        rest = next;
+    }
+    None
+}
+
+fn md5_hex(data: &str) -> String {
+    Md5::digest(data.as_bytes()).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+impl Credentials {
+    /// `Authorization` header of a request to `uri` answering `challenge`,
+    /// `nc` counting the requests made with the same nonce.
+    pub fn authorization(&self, challenge: &Challenge, method: &str, uri: &str, nc: u32) -> String {
+        match challenge {
+            Challenge::Basic => {
+                let credentials = format!("{}:{}", self.user, self.password);
+                format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials))
+            }
+            Challenge::Digest { realm, nonce, opaque, qop } => {
+                let ha1 = md5_hex(&format!("{}:{}:{}", self.user, realm, self.password));
+                let ha2 = md5_hex(&format!("{method}:{uri}"));
+                let mut header = format!(r#"Digest username="{}", realm="{realm}", nonce="{nonce}", uri="{uri}""#, self.user);
+                if *qop {
+                    let cnonce = format!("{:08x}", rand_u32());
+                    let response = md5_hex(&format!("{ha1}:{nonce}:{nc:08x}:{cnonce}:auth:{ha2}"));
+                    header += &format!(r#", response="{response}", qop=auth, nc={nc:08x}, cnonce="{cnonce}""#);
+                } else {
+                    let response = md5_hex(&format!("{ha1}:{nonce}:{ha2}"));
+                    header += &format!(r#", response="{response}""#);
+                }
+                if let Some(opaque) = opaque {
+                    header += &format!(r#", opaque="{opaque}""#);
+                }
+                header += ", algorithm=MD5";
+                header
+            }
+        }
+    }
+}
+
+fn rand_u32() -> u32 {
+    let mut bytes = [0u8; 4];
+    let _ = SystemRandom::new().fill(&mut bytes);
+    u32::from_ne_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authorizes_digest_answering_the_challenge() {
+        let credentials = Credentials { user: "pub".to_string(), password: "secret".to_string() };
+        let mut response = Message::response(401, "Unauthorized");
+        response.headers.push(("WWW-Authenticate".to_string(), digest_challenge()));
+        let challenge = Challenge::parse(&response).unwrap();
+
+        let authorization = credentials.authorization(&challenge, "ANNOUNCE", "rtsp://host/live", 1);
+        assert!(credentials.authorize(Some(&authorization), "ANNOUNCE"));
+        assert!(!credentials.authorize(Some(&authorization), "POST"));
+
+        let wrong = Credentials { user: "pub".to_string(), password: "guess".to_string() };
+        assert!(!credentials.authorize(Some(&wrong.authorization(&challenge, "ANNOUNCE", "rtsp://host/live", 1)), "ANNOUNCE"));
+
+        let forged = Challenge::Digest { realm: REALM.to_string(), nonce: format!("{:x}.00", unix_time()), opaque: None, qop: true };
+        assert!(!credentials.authorize(Some(&credentials.authorization(&forged, "ANNOUNCE", "rtsp://host/live", 1)), "ANNOUNCE"));
+        let expired = Challenge::Digest { realm: REALM.to_string(), nonce: nonce(unix_time() - NONCE_LIFETIME - 1), opaque: None, qop: false };
+        assert!(!credentials.authorize(Some(&credentials.authorization(&expired, "ANNOUNCE", "rtsp://host/live", 1)), "ANNOUNCE"));
+    }
+
+    #[test]
+    fn authorizes_basic_and_bearer() {
+        let credentials = Credentials { user: "pub".to_string(), password: "secret".to_string() };
+        assert!(credentials.authorize(Some("Basic cHViOnNlY3JldA=="), "POST"));
+        assert!(!credentials.authorize(Some("Basic cHViOnNlY3JldQ=="), "POST"));
+        assert!(credentials.authorize(Some("Bearer secret"), "POST"));
+        assert!(!credentials.authorize(Some("Bearer secre"), "POST"));
+        assert!(!credentials.authorize(None, "POST"));
+    }
+
+    /// RTCP sender report of `ntp` seconds since 1900 and RTP `timestamp`.
+    fn sender_report(ntp: f64, timestamp: u32) -> Vec<u8> {
+        let mut packet = vec![0x80, 200, 0, 6, 0x12, 0x34, 0x56, 0x78];
+        packet.extend_from_slice(&((ntp * (1u64 << 32) as f64) as u64).to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&[0; 8]);
+        packet
+    }
+
+    #[test]
+    fn sender_reports_give_the_ntp_of_the_frames() {
+        const SPS: &[u8] = &[0x67, 0x64, 0x00, 0x1e, 0xac, 0xd9, 0x40, 0xa0, 0x2f, 0xf9, 0x61, 0x00, 0x00, 0x03, 0x00, 0x01, 0x00, 0x00, 0x03, 0x00, 0x3c, 0x8f, 0x16, 0x2d, 0x96];
+        const PPS: &[u8] = &[0x68, 0xeb, 0xec, 0xb2, 0x2c];
+        const IDR: &[u8] = &[0x65, 0x88, 0x84, 0x00];
+        // 2023-11-14T22:13:20.500Z
+        let ntp = 2_208_988_800.0 + 1_700_000_000.5;
+        let media = SdpMedia {
+            media: "video".to_string(),
+            payload_type: 96,
+            encoding_name: "h264".to_string(),
+            clock_rate: 90000,
+            ..Default::default()
+        };
+        let mut receiver = RtpReceiver::new(0, &media).unwrap();
+        let tx = FrameSender::new(16);
+        let mut rx = tx.subscribe();
+        let mut video_params = None;
+
+        // reports coming before the first packet are not on the timeline yet
+        receiver.push_rtcp(&sender_report(ntp, 90_000));
+        receiver.push_payload(4_000_000_000, false, SPS, false, Instant::now()).unwrap();
+        receiver.push_payload(4_000_000_000, false, PPS, false, Instant::now()).unwrap();
+        receiver.push_payload(4_000_000_000, true, IDR, false, Instant::now()).unwrap();
+        receiver.broadcast_video(&mut video_params, false, &tx).unwrap();
+        assert!(rx.try_recv().unwrap().is_config());
+        assert!(rx.try_recv().unwrap().metadata.get("ntp").is_none());
+
+        // the report is taken 100 ms before the next frame, across the
+        // wraparound of the RTP timestamps, and followed by an SDES packet;
+        // a truncated packet is ignored
+        let sdes = [0x81, 202, 0, 1, 0x12, 0x34, 0x56, 0x78];
+        receiver.push_rtcp(&[&sender_report(ntp, 4_294_964_000)[..], &sdes].concat());
+        receiver.push_rtcp(&sender_report(ntp + 1.0, 4_294_964_000)[..20]);
+        receiver.push_payload(5_704, true, IDR, false, Instant::now()).unwrap();
+        receiver.broadcast_video(&mut video_params, false, &tx).unwrap();
+        let frame = rx.try_recv().unwrap();
+        assert_eq!(frame.metadata["ntp"].as_f64().unwrap().round(), 1_700_000_000_600.0);
+    }
+}
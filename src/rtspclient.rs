@@ -8,26 +8,118 @@
 ** -------------------------------------------------------------------------*/
 
 use retina::client::{SessionGroup, SetupOptions, Transport};
-use retina::codec::{CodecItem, FrameFormat, VideoFrame, VideoParameters};
+use retina::codec::{AudioFrame, AudioParameters, CodecItem, FrameFormat, MessageFrame, ParametersRef, VideoFrame, VideoParameters};
+use retina::rtcp::ReceivedCompoundPacket;
+use retina::{Timestamp, UNIX_EPOCH};
 use anyhow::{anyhow, Error};
 use log::{debug, error, info};
 use serde_json::json;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
 use std::sync::Arc;
-use tokio::sync::broadcast;
+use std::time::Instant;
 use tokio::sync::oneshot;
 use futures::StreamExt;
 use std::future::Future;
 
-use crate::streamdef::DataFrame;
+use crate::bridge::Bridge;
+use crate::dataframe::now_ms;
+use crate::httptunnel;
+use crate::metadata;
+use crate::multicast;
+use crate::srt;
+use crate::tls::{self, TlsOptions};
+use crate::streamdef::{DataFrame, FrameSender};
+
+/// Upstream connection settings of a stream.
+#[derive(Clone)]
+pub struct Source {
+    pub url: url::Url,
+    pub transport: Option<String>,
+    /// Forward SEI messages and the ONVIF metadata track as metadata frames.
+    pub metadata: bool,
+    /// Trust settings of `rtsps://` sources and of the HTTPS tunnel.
+    pub tls: TlsOptions,
+    /// Reach the camera through the RTSP-over-HTTP tunnel.
+    pub tunnel: Option<httptunnel::Tunnel>,
+    /// Interface joining the group of `udp-multicast` sources.
+    pub interface: Option<Ipv4Addr>,
+}
+
+impl Source {
+    pub fn new(url: url::Url, transport: Option<String>) -> Self {
+        Self {
+            url,
+            transport,
+            metadata: false,
+            tls: TlsOptions::default(),
+            tunnel: None,
+            interface: None,
+        }
+    }
+
+    /// Local bridge for the sources retina cannot reach by itself, which only
+    /// speaks plain RTSP. RTP is then interleaved on the RTSP connection.
+    async fn bridge(&self) -> Result<Option<Bridge>, Error> {
+        let url = &self.url;
+        let host = url.host_str().ok_or_else(|| anyhow!("missing host in {}", url))?.to_string();
+        let bridge = if let Some(tunnel) = self.tunnel {
+            let port = url.port().unwrap_or(tunnel.default_port());
+            let tls = (tunnel == httptunnel::Tunnel::Https).then(|| self.tls.clone());
+            let path = match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_string(),
+            };
+            Bridge::start(url, move || {
+                let host = host.clone();
+                let path = path.clone();
+                let tls = tls.clone();
+                async move { httptunnel::connect(&host, port, &path, tls).await }
+            })
+            .await?
+        } else if url.scheme() == "rtsps" {
+            let port = url.port().unwrap_or(tls::RTSPS_PORT);
+            let tls = self.tls.clone();
+            Bridge::start(url, move || {
+                let tls = tls.clone();
+                let host = host.clone();
+                async move { tls.connect(&host, port).await }
+            })
+            .await?
+        } else {
+            return Ok(None);
+        };
+        debug!("RTSP {} bridged on {}", url, bridge.url);
+        Ok(Some(bridge))
+    }
+}
 
 pub async fn run_until(
-    url: url::Url,
-    transport: Option<String>,
-    tx: broadcast::Sender<DataFrame>,
+    source: Source,
+    tx: FrameSender,
     stop: oneshot::Receiver<()>,
 ) -> Result<(), Error> {
+    if source.url.scheme() == "srt" {
+        return srt::run_until(&source.url, source.metadata, tx, async move {
+            let _ = stop.await;
+        })
+        .await;
+    }
+    let bridge = source.bridge().await?;
+    if source.transport.as_deref() == Some("udp-multicast") {
+        let url = bridge.as_ref().map(|b| b.url.clone()).unwrap_or_else(|| source.url.clone());
+        return multicast::run_until(url, &source, tx, async move {
+            let _ = stop.await;
+        })
+        .await;
+    }
+    let (url, transport) = match &bridge {
+        Some(bridge) => (bridge.url.clone(), Some("tcp".to_string())),
+        None => (source.url, source.transport),
+    };
+
     let session_group = Arc::new(SessionGroup::default());
-    let r = run_inner(url, transport, session_group.clone(), tx, async move {
+    let r = run_inner(url, transport, source.metadata, session_group.clone(), tx, async move {
         let _ = stop.await;
     })
     .await;
@@ -38,7 +130,85 @@ pub async fn run_until(
 }
 
 
-fn process_video_frame(m: VideoFrame, video_params: VideoParameters, tx: broadcast::Sender<DataFrame>) {
+/// Milliseconds since the Unix epoch of a 64-bit NTP timestamp.
+pub fn ntp_ms(ntp: u64) -> f64 {
+    let since_epoch = ntp.wrapping_sub(UNIX_EPOCH.0);
+    (since_epoch >> 32) as f64 * 1000.0 + (since_epoch & 0xFFFF_FFFF) as f64 * 1000.0 / (1u64 << 32) as f64
+}
+
+/// Mapping between RTP and wall-clock time given by the last RTCP sender report.
+pub struct WallClock {
+    /// NTP time of the report, in milliseconds since the Unix epoch.
+    ntp_ms: f64,
+    rtp: Timestamp,
+}
+
+impl WallClock {
+    /// Mapping of a report taken at `ntp_ms`, in milliseconds since the Unix
+    /// epoch, and at `rtp` on the media timeline.
+    pub fn new(ntp_ms: f64, rtp: Timestamp) -> Self {
+        Self { ntp_ms, rtp }
+    }
+
+    fn from_sender_report(rtcp: &ReceivedCompoundPacket) -> Option<Self> {
+        let rtp = rtcp.rtp_timestamp()?;
+        let sr = rtcp.pkts().next()?.as_sender_report().ok()??;
+        Some(Self::new(ntp_ms(sr.ntp_timestamp().0), rtp))
+    }
+
+    /// Capture time of a frame, in milliseconds since the Unix epoch.
+    fn at(&self, ts: Timestamp) -> f64 {
+        let ticks = ts.timestamp() - self.rtp.timestamp();
+        self.ntp_ms + ticks as f64 * 1000.0 / ts.clock_rate().get() as f64
+    }
+}
+
+/// Decoder configuration event, sent when the video parameters become known or
+/// change mid-stream, with the avcC/hvcC record as payload.
+pub fn config_frame(video_params: &VideoParameters) -> DataFrame {
+    let (width, height) = video_params.pixel_dimensions();
+    let metadata = json!({
+        "type": "config",
+        "media": "video",
+        "codec": video_params.rfc6381_codec(),
+        "width": width,
+        "height": height,
+    });
+    DataFrame::new(metadata, video_params.extra_data().to_vec())
+}
+
+pub fn send_frame(tx: &FrameSender, frame: DataFrame) {
+    // published streams are fed whether they have viewers or not, their
+    // configuration is kept for the viewers to come
+    if tx.receiver_count() == 0 && !frame.is_config() {
+        return;
+    }
+    if let Err(e) = tx.send(frame) {
+        if tx.receiver_count() > 0 {
+            error!("Error broadcasting message: {}", e);
+        }
+    }
+}
+
+/// Timing fields shared by the frames of every media: "ts" is the media time
+/// in milliseconds since the start of the stream, "ntp" the capture time in
+/// milliseconds since the Unix epoch, known once the first RTCP sender report
+/// has been received, and "received" the time the last packet of the frame was
+/// received from the camera.
+fn timing(received: Instant, ts: Timestamp, wallclock: Option<&WallClock>) -> serde_json::Value {
+    let received = now_ms() - received.elapsed().as_secs_f64() * 1000.0;
+    let mut metadata = json!({
+        "ts":  ts.elapsed_secs()*1000.0,
+        "received": received,
+    });
+    if let Some(wallclock) = wallclock {
+        metadata["ntp"] = wallclock.at(ts).into();
+    }
+    metadata
+}
+
+/// Video frame whose last packet was received at `received`.
+pub fn process_video_frame(m: VideoFrame, received: Instant, video_params: &VideoParameters, wallclock: Option<&WallClock>, with_sei: bool, tx: &FrameSender) {
     debug!(
         "{}: size:{} is_random_access_point:{} has_new_parameters:{}",
         m.timestamp().timestamp(),
@@ -47,11 +217,13 @@ fn process_video_frame(m: VideoFrame, video_params: VideoParameters, tx: broadca
         m.has_new_parameters(),
     );
 
-    let mut metadata = json!({
-        "ts":  (m.timestamp().timestamp() as f64)*1000.0,
-        "media": "video",
-        "codec": video_params.rfc6381_codec(),
-    });
+    let mut metadata = timing(received, m.timestamp(), wallclock);
+    metadata["media"] = "video".into();
+    metadata["codec"] = video_params.rfc6381_codec().into();
+    let sei = match with_sei {
+        true => metadata::parse_sei(video_params.rfc6381_codec(), m.data()),
+        false => vec![],
+    };
     let mut data: Vec<u8> = vec![];
     if m.is_random_access_point() {
         metadata["type"] = "keyframe".into();
@@ -59,32 +231,82 @@ fn process_video_frame(m: VideoFrame, video_params: VideoParameters, tx: broadca
         let cfg = video_params.extra_data();
         data.extend_from_slice(cfg);
     }
+    let config_len = data.len();
     data.extend_from_slice(m.data());
 
-    let frame = DataFrame {
-        metadata,
-        data,
-    };
+    send_frame(tx, DataFrame::new(metadata, data).with_config_len(config_len));
 
-    if let Err(e) = tx.send(frame) {
-        error!("Error broadcasting message: {}", e);
-    }                        
+    if !sei.is_empty() {
+        let mut metadata = timing(received, m.timestamp(), wallclock);
+        metadata["media"] = "metadata".into();
+        metadata["type"] = "sei".into();
+        metadata["data"] = sei.into();
+        send_frame(tx, DataFrame::new(metadata, vec![]));
+    }
+}
+
+/// Audio configuration event, with the codec specific data as payload, empty
+/// for AAC whose ADTS frames describe themselves.
+pub fn audio_config_frame(codec: &str, audio_params: &AudioParameters) -> DataFrame {
+    let metadata = json!({
+        "type": "config",
+        "media": "audio",
+        "codec": audio_params.rfc6381_codec().unwrap_or(codec),
+        "sample_rate": audio_params.clock_rate(),
+        "channels": audio_params.channels().get(),
+    });
+    DataFrame::new(metadata, audio_params.extra_data().to_vec())
+}
+
+/// Audio frame received at `received`, `codec` naming the encoding when it has
+/// no RFC 6381 codec string.
+pub fn process_audio_frame(m: AudioFrame, received: Instant, codec: &str, audio_params: &AudioParameters, wallclock: Option<&WallClock>, tx: &FrameSender) {
+    let mut metadata = timing(received, m.timestamp(), wallclock);
+    metadata["media"] = "audio".into();
+    metadata["codec"] = audio_params.rfc6381_codec().unwrap_or(codec).into();
+    send_frame(tx, DataFrame::new(metadata, m.data().to_vec()));
+}
+
+/// ONVIF metadata, forwarded with the XML document as payload and its JSON
+/// conversion in the "data" field.
+fn process_onvif_frame(m: MessageFrame, wallclock: Option<&WallClock>, tx: &FrameSender) {
+    let mut metadata = timing(m.ctx().received(), m.timestamp(), wallclock);
+    metadata["media"] = "metadata".into();
+    metadata["type"] = "onvif".into();
+    match std::str::from_utf8(m.data()).map_err(Error::from).and_then(metadata::xml_to_json) {
+        Ok(data) => metadata["data"] = data,
+        Err(e) => debug!("invalid ONVIF metadata: {}", e),
+    }
+    send_frame(tx, DataFrame::new(metadata, m.data().to_vec()));
 }
 
 async fn run_inner<Stop>(
     url: url::Url,
     transport: Option<String>,
+    metadata: bool,
     session_group: Arc<SessionGroup>,
-    tx: broadcast::Sender<DataFrame>,
+    tx: FrameSender,
     stop: Stop,
 ) -> Result<(), Error>
 where
     Stop: Future<Output = ()>,
 {
+    // credentials of the URL are given to retina on their own
+    let mut url = url;
+    let creds = (!url.username().is_empty()).then(|| {
+        let decode = |s: &str| percent_encoding::percent_decode_str(s).decode_utf8_lossy().to_string();
+        retina::client::Credentials {
+            username: decode(url.username()),
+            password: decode(url.password().unwrap_or_default()),
+        }
+    });
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
 
     let mut session = retina::client::Session::describe(
         url.clone(),
         retina::client::SessionOptions::default()
+            .creds(creds)
             .session_group(session_group),
     )
     .await?;
@@ -97,22 +319,52 @@ where
         .ok_or_else(|| anyhow!("couldn't find video stream"))?;
 
     let transport_value = match transport {
-        Some(t) => t.parse::<Transport>().unwrap(),
+        Some(t) => t.parse::<Transport>()?,
         None => Transport::default(), 
     };    
 
     let options = SetupOptions::frame_format(SetupOptions::default(), FrameFormat::SIMPLE);
-    let options = SetupOptions::transport(options, transport_value);
+    let options = SetupOptions::transport(options, transport_value.clone());
     session
         .setup(video_stream, options)
         .await?;
 
-    let video_params = match session.streams()[video_stream].parameters() {
-        Some(retina::codec::ParametersRef::Video(v)) => v.clone(),
-        Some(_) => unreachable!(),
-        None => unreachable!(),
+    // object detections of analytics cameras, only the uncompressed variant is supported
+    let metadata_stream = match metadata {
+        true => session
+            .streams()
+            .iter()
+            .position(|s| s.media() == "application" && s.encoding_name() == "vnd.onvif.metadata"),
+        false => None,
+    };
+    if let Some(metadata_stream) = metadata_stream {
+        session.setup(metadata_stream, SetupOptions::default().transport(transport_value.clone())).await?;
+    }
+
+    // AAC, the audio codec FLV and fMP4 viewers can carry, ADTS framed as
+    // the audio of publishers
+    let audio_stream = session
+        .streams()
+        .iter()
+        .position(|s| s.media() == "audio" && s.encoding_name() == "mpeg4-generic");
+    if let Some(audio_stream) = audio_stream {
+        let options = SetupOptions::default().frame_format(FrameFormat::SIMPLE).transport(transport_value);
+        session.setup(audio_stream, options).await?;
+        if let Some(ParametersRef::Audio(audio_params)) = session.streams()[audio_stream].parameters() {
+            info!("audio_params:{:?}", audio_params);
+            send_frame(&tx, audio_config_frame("mpeg4-generic", audio_params));
+        }
+    }
+
+    // parameters may be missing from the SDP, they are then known with the first frame
+    let mut video_params = match session.streams()[video_stream].parameters() {
+        Some(ParametersRef::Video(v)) => Some(v.clone()),
+        _ => None,
     };
     info!("video_params:{:?}", video_params);
+    if let Some(video_params) = &video_params {
+        send_frame(&tx, config_frame(video_params));
+    }
 
     let mut videosession = session
         .play(retina::client::PlayOptions::default())
@@ -120,12 +372,44 @@ where
         .demuxed()?;
 
     
+    let mut wallclocks = HashMap::new();
     tokio::pin!(stop);
     loop {
         tokio::select! {
             item = videosession.next() => {
                 match item.ok_or_else(|| anyhow!("EOF"))?? {
-                    CodecItem::VideoFrame(m) => process_video_frame(m, video_params.clone(), tx.clone()),
+                    CodecItem::VideoFrame(m) => {
+                        if m.has_new_parameters() {
+                            if let Some(ParametersRef::Video(v)) = videosession.streams()[video_stream].parameters() {
+                                info!("new video_params:{:?}", v);
+                                send_frame(&tx, config_frame(v));
+                                video_params = Some(v.clone());
+                            }
+                        }
+                        match &video_params {
+                            Some(video_params) => {
+                                let received = m.end_ctx().received();
+                                process_video_frame(m, received, video_params, wallclocks.get(&video_stream), metadata, &tx)
+                            }
+                            None => debug!("skipping frame received before video parameters"),
+                        }
+                    }
+                    CodecItem::AudioFrame(m) => {
+                        let stream_id = m.stream_id();
+                        if let Some(ParametersRef::Audio(audio_params)) = videosession.streams()[stream_id].parameters() {
+                            let received = m.ctx().received();
+                            process_audio_frame(m, received, "mpeg4-generic", audio_params, wallclocks.get(&stream_id), &tx);
+                        }
+                    }
+                    CodecItem::MessageFrame(m) => {
+                        let wallclock = wallclocks.get(&m.stream_id());
+                        process_onvif_frame(m, wallclock, &tx);
+                    }
+                    CodecItem::Rtcp(rtcp) => {
+                        if let Some(sr) = WallClock::from_sender_report(&rtcp) {
+                            wallclocks.insert(rtcp.stream_id(), sr);
+                        }
+                    }
                     _ => continue,
                 };
             },
@@ -136,3 +420,152 @@ where
     }
     Ok(())
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::rtsp::Message;
+    use base64::Engine;
+    use tokio::io::{AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    const SPS: &[u8] = &[0x67, 0x64, 0x00, 0x1e, 0xac, 0xd9, 0x40, 0xa0, 0x2f, 0xf9, 0x61, 0x00, 0x00, 0x03, 0x00, 0x01, 0x00, 0x00, 0x03, 0x00, 0x3c, 0x8f, 0x16, 0x2d, 0x96];
+    const PPS: &[u8] = &[0x68, 0xeb, 0xec, 0xb2, 0x2c];
+    /// AAC LC, 44.1 kHz, stereo.
+    pub(crate) const AUDIO_SPECIFIC_CONFIG: &[u8] = &[0x12, 0x10];
+    pub(crate) const AAC_FRAME: &[u8] = &[0x21, 0x10, 0x04, 0x60, 0x8c, 0x1c];
+
+    fn rtp(payload_type: u8, mark: bool, sequence: u16, timestamp: u32, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x80, payload_type | if mark { 0x80 } else { 0 }];
+        packet.extend_from_slice(&sequence.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&0x1234_5678u32.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn interleaved(channel: u8, packet: &[u8]) -> Vec<u8> {
+        [&[b'$', channel][..], &(packet.len() as u16).to_be_bytes(), packet].concat()
+    }
+
+    /// RTSP camera with an H.264 track and an AAC track, played over TCP, which
+    /// sends a keyframe and an audio frame every 40 ms once playing.
+    pub(crate) async fn camera() -> url::Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!("rtsp://127.0.0.1:{}/cam", listener.local_addr().unwrap().port())).unwrap();
+        let base = format!("{url}/");
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let (requests_tx, mut requests) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                let mut read = BufReader::new(read);
+                while let Ok(Some(request)) = Message::read_from(&mut read).await {
+                    if requests_tx.send(request).is_err() {
+                        return;
+                    }
+                }
+            });
+            let b64 = |nal: &[u8]| base64::engine::general_purpose::STANDARD.encode(nal);
+            let sdp = format!(
+                "v=0\r\no=- 0 0 IN IP4 127.0.0.1\r\ns=camera\r\nc=IN IP4 0.0.0.0\r\nt=0 0\r\n\
+                 m=video 0 RTP/AVP 96\r\na=rtpmap:96 H264/90000\r\n\
+                 a=fmtp:96 packetization-mode=1;profile-level-id=64001E;sprop-parameter-sets={},{}\r\na=control:trackID=0\r\n\
+                 m=audio 0 RTP/AVP 97\r\na=rtpmap:97 MPEG4-GENERIC/44100/2\r\n\
+                 a=fmtp:97 streamtype=5;profile-level-id=1;mode=AAC-hbr;sizelength=13;indexlength=3;indexdeltalength=3;config=1210\r\n\
+                 a=control:trackID=1\r\n",
+                b64(SPS),
+                b64(PPS)
+            );
+            let mut channels = [0u8, 2];
+            let mut playing = false;
+            let mut tick = tokio::time::interval(Duration::from_millis(40));
+            let mut n = 0u32;
+            loop {
+                tokio::select! {
+                    request = requests.recv() => {
+                        let Some(request) = request else {
+                            return;
+                        };
+                        let (method, target) = request.method().unwrap();
+                        let mut response = Message::response(200, "OK")
+                            .with_header("CSeq", request.header("CSeq").unwrap_or("0"))
+                            .with_header("Session", "cafe;timeout=60");
+                        match method {
+                            "DESCRIBE" => response = response.with_header("Content-Base", &base).with_body("application/sdp", sdp.clone()),
+                            "SETUP" => {
+                                let transport = request.header("Transport").unwrap_or_default();
+                                let channel = transport
+                                    .split(';')
+                                    .find_map(|param| param.strip_prefix("interleaved="))
+                                    .and_then(|channels| channels.split('-').next()?.parse().ok())
+                                    .unwrap_or(0);
+                                channels[usize::from(target.ends_with("trackID=1"))] = channel;
+                                response = response.with_header("Transport", transport);
+                            }
+                            "PLAY" => {
+                                playing = true;
+                                response = response.with_header("RTP-Info", format!("url={base}trackID=0;seq=0;rtptime=0,url={base}trackID=1;seq=0;rtptime=0"));
+                            }
+                            _ => {}
+                        }
+                        if response.write_to(&mut write).await.is_err() {
+                            return;
+                        }
+                    }
+                    _ = tick.tick(), if playing => {
+                        let sequence = n as u16;
+                        let video = rtp(96, true, sequence, n * 3600, &[0x65, 0x88, 0x84, 0x00]);
+                        let au_header = ((AAC_FRAME.len() as u16) << 3).to_be_bytes();
+                        let audio = rtp(97, true, sequence, n * 1024, &[&[0x00, 0x10][..], &au_header, AAC_FRAME].concat());
+                        let packets = [interleaved(channels[0], &video), interleaved(channels[1], &audio)].concat();
+                        if write.write_all(&packets).await.is_err() {
+                            return;
+                        }
+                        n += 1;
+                    }
+                }
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn cameras_forward_their_aac_track() {
+        let url = camera().await;
+        let tx = FrameSender::new(100);
+        let mut rx = tx.receiver();
+        let (stop_tx, stop) = oneshot::channel();
+        let task = tokio::spawn(run_until(Source::new(url, Some("tcp".to_string())), tx, stop));
+
+        let mut configs = HashMap::new();
+        let audio = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let frame = rx.recv().await.unwrap();
+                let media = frame.metadata["media"].as_str().unwrap_or_default().to_string();
+                if frame.is_config() {
+                    configs.insert(media, frame);
+                } else if media == "audio" {
+                    return frame;
+                }
+            }
+        })
+        .await
+        .expect("no audio frame");
+
+        let config = &configs["audio"];
+        assert_eq!(config.metadata["codec"], "mp4a.40.2");
+        assert_eq!(config.metadata["sample_rate"], 44100);
+        assert_eq!(config.metadata["channels"], 2);
+        assert_eq!(configs["video"].metadata["codec"], "avc1.64001E");
+        assert_eq!(audio.metadata["codec"], "mp4a.40.2");
+        let frames = crate::mpegts::adts_frames(&audio.data);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].config, AUDIO_SPECIFIC_CONFIG);
+        assert_eq!(frames[0].data, AAC_FRAME);
+        let _ = stop_tx.send(());
+        let _ = tokio::time::timeout(Duration::from_secs(5), task).await;
+    }
+}
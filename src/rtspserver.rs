@@ -0,0 +1,891 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use anyhow::{anyhow, Error};
+use base64::Engine;
+use log::{debug, info, warn};
+use retina::codec::{CodecItem, ParametersRef, VideoParameters};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::appcontext::{AppContext, PublishError};
+use crate::bitstream::nal_units;
+use crate::clientsession::ClientSession;
+use crate::rtsp::{self, Message, RtpReceiver};
+use crate::rtspclient;
+use crate::streamdef::{DataFrame, FrameSender, StreamsDef, Viewer};
+
+/// Seconds without request after which a UDP session is closed.
+const SESSION_TIMEOUT: u64 = 60;
+
+/// Time given to a starting stream to deliver the configuration describing it.
+const DESCRIBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Seconds between two RTCP sender reports of a playing session.
+const REPORT_INTERVAL: u64 = 5;
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Largest RTP payload, leaving room for the IP/UDP/RTP headers in a 1500 MTU.
+const MAX_PAYLOAD: usize = 1400;
+
+const PAYLOAD_TYPE: u8 = 96;
+
+const PUBLIC: &str = "OPTIONS, DESCRIBE, ANNOUNCE, SETUP, PLAY, RECORD, TEARDOWN, GET_PARAMETER, SET_PARAMETER";
+
+fn random_u32() -> u32 {
+    let mut bytes = [0u8; 4];
+    let _ = SystemRandom::new().fill(&mut bytes);
+    u32::from_be_bytes(bytes)
+}
+
+/// RTP packetization of H.264 (RFC 6184) and H.265 (RFC 7798) access units,
+/// with single NAL unit packets and fragmentation units.
+struct Packetizer {
+    hevc: bool,
+    ssrc: u32,
+    sequence: u16,
+    /// RTP timestamp of the first frame, announced in the RTP-Info of PLAY.
+    offset: u32,
+    /// Media time of the first frame, in milliseconds.
+    start: Option<f64>,
+    /// RTP timestamp of the last frame and when it was sent, for the sender
+    /// reports.
+    last: Option<(u32, Instant)>,
+    packets: u32,
+    octets: u32,
+}
+
+impl Packetizer {
+    fn new(hevc: bool) -> Self {
+        Self {
+            hevc,
+            ssrc: random_u32(),
+            sequence: random_u32() as u16,
+            offset: random_u32(),
+            start: None,
+            last: None,
+            packets: 0,
+            octets: 0,
+        }
+    }
+
+    /// RTP timestamp of a frame from its media time in milliseconds, counted
+    /// from the first frame sent.
+    fn timestamp(&mut self, ts_ms: f64) -> u32 {
+        let start = *self.start.get_or_insert(ts_ms);
+        (((ts_ms - start) * 90.0) as i64 as u32).wrapping_add(self.offset)
+    }
+
+    /// RTCP sender report (RFC 3550) mapping the current time to the RTP
+    /// timestamps, once a frame has been sent.
+    fn sender_report(&self) -> Option<Vec<u8>> {
+        let (timestamp, sent) = self.last?;
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).ok()?;
+        let seconds = now.as_secs() + NTP_UNIX_OFFSET;
+        let fraction = (u64::from(now.subsec_nanos()) << 32) / 1_000_000_000;
+        let elapsed = (sent.elapsed().as_secs_f64() * 90000.0) as u32;
+        let mut report = vec![0x80, 200, 0, 6];
+        report.extend_from_slice(&self.ssrc.to_be_bytes());
+        report.extend_from_slice(&(seconds as u32).to_be_bytes());
+        report.extend_from_slice(&(fraction as u32).to_be_bytes());
+        report.extend_from_slice(&timestamp.wrapping_add(elapsed).to_be_bytes());
+        report.extend_from_slice(&self.packets.to_be_bytes());
+        report.extend_from_slice(&self.octets.to_be_bytes());
+        Some(report)
+    }
+
+    fn packet(&mut self, timestamp: u32, mark: bool, payload: &[&[u8]]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(12 + MAX_PAYLOAD + 3);
+        packet.push(0x80);
+        packet.push(PAYLOAD_TYPE | if mark { 0x80 } else { 0 });
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        for part in payload {
+            packet.extend_from_slice(part);
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        self.packets = self.packets.wrapping_add(1);
+        self.octets = self.octets.wrapping_add((packet.len() - 12) as u32);
+        packet
+    }
+
+    /// RTP packets of an Annex B access unit, the last one with the marker bit.
+    fn packetize(&mut self, ts_ms: f64, annexb: &[u8]) -> Vec<Vec<u8>> {
+        let timestamp = self.timestamp(ts_ms);
+        self.last = Some((timestamp, Instant::now()));
+        let nals: Vec<&[u8]> = nal_units(annexb).into_iter().filter(|nal| nal.len() > 2).collect();
+        let mut packets = vec![];
+        for (i, nal) in nals.iter().enumerate() {
+            let last_nal = i + 1 == nals.len();
+            if nal.len() <= MAX_PAYLOAD {
+                packets.push(self.packet(timestamp, last_nal, &[nal]));
+                continue;
+            }
+            // fragmentation unit header, followed by the start and end bits and the NAL type
+            let (header, nal_type, body): (Vec<u8>, u8, &[u8]) = if self.hevc {
+                (vec![(nal[0] & 0x81) | (49 << 1), nal[1]], (nal[0] >> 1) & 0x3f, &nal[2..])
+            } else {
+                (vec![(nal[0] & 0xe0) | 28], nal[0] & 0x1f, &nal[1..])
+            };
+            let chunks: Vec<&[u8]> = body.chunks(MAX_PAYLOAD - 3).collect();
+            for (j, chunk) in chunks.iter().enumerate() {
+                let start = if j == 0 { 0x80 } else { 0 };
+                let end = if j + 1 == chunks.len() { 0x40 } else { 0 };
+                let fu_header = [start | end | nal_type];
+                let mark = last_nal && end != 0;
+                packets.push(self.packet(timestamp, mark, &[&header, &fu_header, chunk]));
+            }
+        }
+        packets
+    }
+}
+
+/// SDP description of a stream, from its configuration frame carrying the
+/// parameter sets in Annex B.
+fn describe(path: &str, local: &SocketAddr, codec: &str, config: &[u8]) -> String {
+    let hevc = !codec.starts_with("avc");
+    let b64 = |nal: &[u8]| base64::engine::general_purpose::STANDARD.encode(nal);
+    let mut vps = None;
+    let mut sps = None;
+    let mut pps = None;
+    for nal in nal_units(config) {
+        let Some(&header) = nal.first() else { continue };
+        let slot = match (hevc, header) {
+            (false, h) if h & 0x1f == 7 => &mut sps,
+            (false, h) if h & 0x1f == 8 => &mut pps,
+            (true, h) if (h >> 1) & 0x3f == 32 => &mut vps,
+            (true, h) if (h >> 1) & 0x3f == 33 => &mut sps,
+            (true, h) if (h >> 1) & 0x3f == 34 => &mut pps,
+            _ => continue,
+        };
+        slot.get_or_insert(nal);
+    }
+
+    let (encoding, fmtp) = if hevc {
+        let mut fmtp = vec![];
+        for (name, nal) in [("sprop-vps", vps), ("sprop-sps", sps), ("sprop-pps", pps)] {
+            if let Some(nal) = nal {
+                fmtp.push(format!("{}={}", name, b64(nal)));
+            }
+        }
+        ("H265", fmtp.join(";"))
+    } else {
+        let mut fmtp = vec!["packetization-mode=1".to_string()];
+        if let Some(sps) = sps.filter(|sps| sps.len() >= 4) {
+            fmtp.push(format!("profile-level-id={:02X}{:02X}{:02X}", sps[1], sps[2], sps[3]));
+            let sets: Vec<String> = [Some(sps), pps].into_iter().flatten().map(b64).collect();
+            fmtp.push(format!("sprop-parameter-sets={}", sets.join(",")));
+        }
+        ("H264", fmtp.join(";"))
+    };
+
+    format!(
+        "v=0\r\n\
+         o=- {id} 1 IN IP4 {ip}\r\n\
+         s={path}\r\n\
+         c=IN IP4 0.0.0.0\r\n\
+         t=0 0\r\n\
+         a=control:*\r\n\
+         a=range:npt=0-\r\n\
+         m=video 0 RTP/AVP {PAYLOAD_TYPE}\r\n\
+         a=rtpmap:{PAYLOAD_TYPE} {encoding}/90000\r\n\
+         a=fmtp:{PAYLOAD_TYPE} {fmtp}\r\n\
+         a=control:trackID=0\r\n",
+        id = random_u32(),
+        ip = local.ip(),
+    )
+}
+
+/// Where the RTP packets of a session go, and its RTCP packets on the next
+/// channel or port.
+enum Delivery {
+    /// Interleaved on the RTSP connection, on the given channel.
+    Interleaved(u8),
+    Udp { socket: UdpSocket, rtcp: UdpSocket, peer: SocketAddr },
+}
+
+/// UDP sockets on consecutive ports for RTP and RTCP, the RTP one even.
+async fn bind_pair(ip: std::net::IpAddr) -> Result<(UdpSocket, UdpSocket), Error> {
+    for _ in 0..16 {
+        let rtp = UdpSocket::bind((ip, 0)).await?;
+        let port = rtp.local_addr()?.port();
+        if port % 2 != 0 || port == u16::MAX {
+            continue;
+        }
+        if let Ok(rtcp) = UdpSocket::bind((ip, port + 1)).await {
+            return Ok((rtp, rtcp));
+        }
+    }
+    Err(anyhow!("no free pair of UDP ports"))
+}
+
+/// A client of the RTSP server, counting as a viewer of its stream until dropped.
+struct Session {
+    id: String,
+    path: String,
+    viewer: Viewer,
+    client: ClientSession,
+    packetizer: Packetizer,
+    delivery: Option<Delivery>,
+    playing: bool,
+}
+
+impl Session {
+    async fn open(app_context: &AppContext, path: &str, remote: &SocketAddr) -> Result<(Self, String, Vec<u8>), Error> {
+        let stream_def = app_context
+            .stream(path)
+            .ok_or_else(|| anyhow!("unknown stream '{}'", path))?;
+        let mut session = Self {
+            id: format!("{:08X}", random_u32()),
+            path: path.to_string(),
+            viewer: Viewer::new(stream_def, path),
+            client: ClientSession::new(&app_context.clients, "rtsp", remote.to_string(), path),
+            packetizer: Packetizer::new(false),
+            delivery: None,
+            playing: false,
+        };
+
+        // the codec and parameter sets are known from the video configuration,
+        // kept for the new viewers of a running stream
+        let config = tokio::time::timeout(DESCRIBE_TIMEOUT, async {
+            loop {
+                match session.viewer.rx.recv().await {
+                    Ok(frame) if frame.is_config() && frame.metadata["media"] == "video" => return Ok(frame),
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return Err(anyhow!("stream '{}' closed", path)),
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("no video configuration from '{}'", path))??;
+
+        let codec = config.metadata["codec"].as_str().unwrap_or_default().to_string();
+        session.packetizer.hevc = !codec.starts_with("avc");
+        Ok((session, codec, config.payload().to_vec()))
+    }
+
+    /// Next video frame to send, pending until the session plays.
+    async fn next(session: &mut Option<Session>) -> Option<DataFrame> {
+        let Some(session) = session.as_mut().filter(|s| s.playing) else {
+            return std::future::pending().await;
+        };
+        loop {
+            match session.viewer.rx.recv().await {
+                Ok(frame) => {
+                    if frame.metadata["media"] != "video" || frame.is_config() {
+                        continue;
+                    }
+                    if session.client.accept(&frame) {
+                        return Some(frame);
+                    }
+                }
+                Err(RecvError::Lagged(n)) => {
+                    warn!("RTSP client {} lagged {} frames, waiting for next keyframe", session.id, n);
+                    session.client.lagged(n);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+type Failure = (u16, &'static str, Error);
+
+/// A media announced by a publisher.
+struct Track {
+    media: String,
+    encoding_name: String,
+    control: url::Url,
+    receiver: RtpReceiver,
+    /// Channel of the RTP packets, interleaved or assigned to the UDP socket.
+    channel: Option<u8>,
+    udp: Option<JoinHandle<()>>,
+    /// Socket of the RTCP port announced to UDP publishers, bound for as long
+    /// as the track so that their reports are not refused.
+    rtcp: Option<UdpSocket>,
+}
+
+/// A client pushing a stream with ANNOUNCE and RECORD, feeding it until dropped.
+struct Publisher {
+    id: String,
+    stream_def: Arc<Mutex<StreamsDef>>,
+    tx: FrameSender,
+    tracks: Vec<Track>,
+    video_params: Option<VideoParameters>,
+    recording: bool,
+}
+
+impl Publisher {
+    /// Take the stream at `path` for the media announced by `request`.
+    fn announce(app_context: &AppContext, url: &str, path: &str, request: &Message, remote: &SocketAddr) -> Result<Self, Failure> {
+        let base = url::Url::parse(url).map_err(|e| (400, "Bad Request", e.into()))?;
+        let mut tracks = vec![];
+        for (i, media) in rtsp::parse_sdp(&String::from_utf8_lossy(&request.body)).iter().enumerate() {
+            let supported = match media.media.as_str() {
+                "video" => matches!(media.encoding_name.as_str(), "h264" | "h265"),
+                "audio" => true,
+                _ => false,
+            };
+            if !supported {
+                debug!("Ignoring {} {} track of publisher {}", media.media, media.encoding_name, remote);
+                continue;
+            }
+            let receiver = match RtpReceiver::new(i, media) {
+                Ok(receiver) => receiver,
+                Err(e) => {
+                    warn!("Ignoring {} {} track of publisher {}: {}", media.media, media.encoding_name, remote, e);
+                    continue;
+                }
+            };
+            tracks.push(Track {
+                media: media.media.clone(),
+                encoding_name: media.encoding_name.clone(),
+                control: rtsp::control_url(&base, media.control.as_deref()).map_err(|e| (400, "Bad Request", e))?,
+                receiver,
+                channel: None,
+                udp: None,
+                rtcp: None,
+            });
+        }
+        if !tracks.iter().any(|t| t.media == "video") {
+            return Err((415, "Unsupported Media Type", anyhow!("no H.264 or H.265 video announced")));
+        }
+        let video_params = tracks.iter().find_map(|t| match t.receiver.parameters() {
+            Some(ParametersRef::Video(v)) => Some(v.clone()),
+            _ => None,
+        });
+
+        // RTSP is not encrypted, Basic would send the password in clear
+        let authorization = request.header("Authorization").filter(|a| a.trim_start().get(..7).is_some_and(|scheme| scheme.eq_ignore_ascii_case("Digest ")));
+        let stream_def = app_context
+            .claim_stream(path, authorization, "ANNOUNCE", &remote.to_string())
+            .map_err(|e| match e {
+                PublishError::UnknownStream(_) => (404, "Not Found", e.into()),
+                PublishError::Unauthorized(_) => (401, "Unauthorized", e.into()),
+                PublishError::Forbidden(_) => (403, "Forbidden", e.into()),
+            })?;
+        let tx = stream_def.lock().unwrap().tx.clone();
+        Ok(Self {
+            id: format!("{:08X}", random_u32()),
+            stream_def,
+            tx,
+            tracks,
+            video_params,
+            recording: false,
+        })
+    }
+
+    /// Set up the track at `url` for the `transport` requested by the
+    /// publisher, returning the transport of the response.
+    async fn setup(&mut self, url: &str, transport: &str, local: &SocketAddr, remote: &SocketAddr, incoming: &mpsc::Sender<Incoming>) -> Result<String, Failure> {
+        let index = self
+            .tracks
+            .iter()
+            .position(|t| t.control.as_str().trim_end_matches('/') == url.trim_end_matches('/'))
+            .ok_or_else(|| (404, "Not Found", anyhow!("unknown track '{}'", url)))?;
+        let unsupported = |e: Error| (461, "Unsupported Transport", e);
+        let track = &mut self.tracks[index];
+        if transport.starts_with("RTP/AVP/TCP") {
+            let channel = transport_param(transport, "interleaved")
+                .and_then(|c| c.split('-').next())
+                .and_then(|c| c.parse::<u8>().ok())
+                .unwrap_or(2 * index as u8);
+            track.channel = Some(channel);
+            return Ok(format!("RTP/AVP/TCP;unicast;interleaved={}-{};mode=record", channel, channel.wrapping_add(1)));
+        }
+        if transport.contains("multicast") {
+            return Err(unsupported(anyhow!("multicast is not supported")));
+        }
+
+        // UDP packets are handled as if they were interleaved on a channel of their own
+        let client_port = transport_param(transport, "client_port")
+            .and_then(|p| p.split('-').next())
+            .and_then(|p| p.parse::<u16>().ok())
+            .ok_or_else(|| unsupported(anyhow!("missing client_port in '{}'", transport)))?;
+        let (socket, rtcp) = bind_pair(local.ip()).await.map_err(unsupported)?;
+        let server_port = socket.local_addr().map_err(|e| unsupported(e.into()))?.port();
+        let channel = 2 * index as u8;
+        let peer = remote.ip();
+        let incoming = incoming.clone();
+        track.channel = Some(channel);
+        track.rtcp = Some(rtcp);
+        track.udp = Some(tokio::spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+                if from.ip() != peer {
+                    continue;
+                }
+                if incoming.send(Incoming::Packet(channel, buf[..n].to_vec(), Instant::now())).await.is_err() {
+                    break;
+                }
+            }
+        }));
+        Ok(format!(
+            "RTP/AVP;unicast;client_port={}-{};server_port={}-{};mode=record",
+            client_port,
+            client_port.wrapping_add(1),
+            server_port,
+            server_port.wrapping_add(1)
+        ))
+    }
+
+    fn record(&mut self) {
+        self.recording = true;
+        if let Some(video_params) = &self.video_params {
+            rtspclient::send_frame(&self.tx, rtspclient::config_frame(video_params));
+        }
+        for track in &self.tracks {
+            if let Some(ParametersRef::Audio(audio_params)) = track.receiver.parameters() {
+                rtspclient::send_frame(&self.tx, rtspclient::audio_config_frame(&track.encoding_name, audio_params));
+            }
+        }
+    }
+
+    /// Depacketize an RTP packet and broadcast the frames it completes.
+    fn push(&mut self, channel: u8, packet: &[u8], received: Instant) {
+        if !self.recording {
+            return;
+        }
+        let Some(track) = self.tracks.iter_mut().find(|t| t.channel == Some(channel)) else {
+            return;
+        };
+        if let Err(e) = track.receiver.push(packet, received) {
+            warn!("RTSP publisher {} depacketization error: {}", self.id, e);
+            return;
+        }
+        if track.media == "video" {
+            if let Err(e) = track.receiver.broadcast_video(&mut self.video_params, false, &self.tx) {
+                warn!("RTSP publisher {} depacketization error: {}", self.id, e);
+            }
+            return;
+        }
+        while let Some(item) = track.receiver.pull() {
+            match item {
+                Ok(CodecItem::AudioFrame(m)) => {
+                    if let Some(ParametersRef::Audio(audio_params)) = track.receiver.parameters() {
+                        rtspclient::process_audio_frame(m, received, &track.encoding_name, audio_params, None, &self.tx);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("RTSP publisher {} depacketization error: {}", self.id, e),
+            }
+        }
+    }
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        for track in &self.tracks {
+            if let Some(udp) = &track.udp {
+                udp.abort();
+            }
+        }
+        self.stream_def.lock().unwrap().release();
+    }
+}
+
+/// What a client sends on its RTSP connection.
+enum Incoming {
+    Request(Message),
+    /// RTP or RTCP packet, with its interleaved channel and when it was read.
+    Packet(u8, Vec<u8>, Instant),
+}
+
+/// Requests and interleaved packets of a connection.
+async fn read_requests(read: OwnedReadHalf, tx: mpsc::Sender<Incoming>) -> Result<(), Error> {
+    let mut reader = BufReader::new(read);
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        if buf[0] == b'$' {
+            let mut header = [0u8; 4];
+            reader.read_exact(&mut header).await?;
+            let mut packet = vec![0u8; usize::from(u16::from_be_bytes([header[2], header[3]]))];
+            reader.read_exact(&mut packet).await?;
+            if tx.send(Incoming::Packet(header[1], packet, Instant::now())).await.is_err() {
+                return Ok(());
+            }
+            continue;
+        }
+        let Some(request) = Message::read_from(&mut reader).await? else {
+            return Ok(());
+        };
+        if tx.send(Incoming::Request(request)).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Stream path of a request URL, without the track of SETUP requests.
+fn stream_path(url: &str) -> Option<String> {
+    let url = url::Url::parse(url).ok()?;
+    let path = percent_encoding::percent_decode_str(url.path()).decode_utf8_lossy();
+    let path = path.trim_end_matches('/');
+    let path = path.strip_suffix("/trackID=0").unwrap_or(path);
+    Some(path.to_string())
+}
+
+fn transport_param<'a>(transport: &'a str, name: &str) -> Option<&'a str> {
+    transport
+        .split(';')
+        .filter_map(|p| p.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+struct Connection {
+    app_context: AppContext,
+    writer: OwnedWriteHalf,
+    local: SocketAddr,
+    remote: SocketAddr,
+    session: Option<Session>,
+    publisher: Option<Publisher>,
+    /// Sender of the incoming packets, given to the UDP sockets of publishers.
+    incoming: mpsc::Sender<Incoming>,
+}
+
+impl Connection {
+    async fn handle(&mut self, request: &Message) -> Message {
+        let cseq = request.header("CSeq").unwrap_or("0").to_string();
+        let mut response = match self.respond(request).await {
+            Ok(response) => response,
+            Err((status, reason, e)) => {
+                debug!("RTSP {} {}: {}", self.remote, request.start_line, e);
+                let response = Message::response(status, reason);
+                match status {
+                    401 => response.with_header("WWW-Authenticate", rtsp::digest_challenge()),
+                    _ => response,
+                }
+            }
+        };
+        response = response.with_header("CSeq", cseq).with_header("Server", concat!("rtsp2web-rs/", env!("CARGO_PKG_VERSION")));
+        let id = self.session.as_ref().map(|s| &s.id).or(self.publisher.as_ref().map(|p| &p.id));
+        if let Some(id) = id {
+            response = response.with_header("Session", format!("{};timeout={}", id, SESSION_TIMEOUT));
+        }
+        response
+    }
+
+    async fn respond(&mut self, request: &Message) -> Result<Message, Failure> {
+        let bad_request = |e: Error| (400, "Bad Request", e);
+        let (method, url) = request.method().ok_or_else(|| bad_request(anyhow!("invalid request line")))?;
+        let path = stream_path(url).ok_or_else(|| bad_request(anyhow!("invalid URL '{}'", url)))?;
+        let session = self.session.as_ref().map(|s| &s.id).or(self.publisher.as_ref().map(|p| &p.id));
+        if let (Some(session), Some(id)) = (session, request.session()) {
+            if *session != id {
+                return Err((454, "Session Not Found", anyhow!("unknown session {}", id)));
+            }
+        }
+        let invalid_state = |e: Error| (455, "Method Not Valid in This State", e);
+
+        match method {
+            "OPTIONS" | "GET_PARAMETER" | "SET_PARAMETER" => Ok(Message::response(200, "OK").with_header("Public", PUBLIC)),
+            "ANNOUNCE" => {
+                if self.session.is_some() || self.publisher.is_some() {
+                    return Err(invalid_state(anyhow!("ANNOUNCE in a session")));
+                }
+                let publisher = Publisher::announce(&self.app_context, url, &path, request, &self.remote)?;
+                info!("RTSP publisher {} announced {}", self.remote, path);
+                self.publisher = Some(publisher);
+                Ok(Message::response(200, "OK"))
+            }
+            "SETUP" if self.publisher.is_some() => {
+                let transport = request
+                    .header("Transport")
+                    .and_then(|t| t.split(',').next())
+                    .ok_or_else(|| bad_request(anyhow!("missing Transport")))?;
+                let publisher = self.publisher.as_mut().expect("publisher");
+                let reply = publisher.setup(url, transport, &self.local, &self.remote, &self.incoming).await?;
+                Ok(Message::response(200, "OK").with_header("Transport", reply))
+            }
+            "RECORD" => {
+                let publisher = self
+                    .publisher
+                    .as_mut()
+                    .filter(|p| p.tracks.iter().any(|t| t.channel.is_some()))
+                    .ok_or_else(|| invalid_state(anyhow!("RECORD before SETUP")))?;
+                publisher.record();
+                info!("RTSP publisher {} recording {}", self.remote, path);
+                Ok(Message::response(200, "OK"))
+            }
+            "DESCRIBE" => {
+                let (session, codec, keyframe) = Session::open(&self.app_context, &path, &self.remote)
+                    .await
+                    .map_err(|e| (404, "Not Found", e))?;
+                let sdp = describe(&path, &self.local, &codec, &keyframe);
+                let base = format!("{}/", url.trim_end_matches('/'));
+                self.session = Some(session);
+                Ok(Message::response(200, "OK")
+                    .with_header("Content-Base", base)
+                    .with_body("application/sdp", sdp))
+            }
+            "SETUP" => {
+                if let Some(session) = self.session.as_ref().filter(|s| s.path != path) {
+                    return Err(invalid_state(anyhow!("SETUP of '{}' in the session of '{}'", path, session.path)));
+                }
+                if self.session.is_none() {
+                    let (session, _, _) = Session::open(&self.app_context, &path, &self.remote)
+                        .await
+                        .map_err(|e| (404, "Not Found", e))?;
+                    self.session = Some(session);
+                }
+                let transport = request
+                    .header("Transport")
+                    .and_then(|t| t.split(',').next())
+                    .ok_or_else(|| bad_request(anyhow!("missing Transport")))?
+                    .to_string();
+                let unsupported = |e: Error| (461, "Unsupported Transport", e);
+                let (delivery, reply) = if transport.starts_with("RTP/AVP/TCP") {
+                    let channel = transport_param(&transport, "interleaved")
+                        .and_then(|c| c.split('-').next())
+                        .and_then(|c| c.parse::<u8>().ok())
+                        .unwrap_or(0);
+                    (
+                        Delivery::Interleaved(channel),
+                        format!("RTP/AVP/TCP;unicast;interleaved={}-{}", channel, channel.wrapping_add(1)),
+                    )
+                } else if transport.contains("multicast") {
+                    return Err(unsupported(anyhow!("multicast is not supported")));
+                } else {
+                    let client_port = transport_param(&transport, "client_port")
+                        .and_then(|p| p.split('-').next())
+                        .and_then(|p| p.parse::<u16>().ok())
+                        .ok_or_else(|| unsupported(anyhow!("missing client_port in '{}'", transport)))?;
+                    let (socket, rtcp) = bind_pair(self.local.ip()).await.map_err(unsupported)?;
+                    let server_port = socket.local_addr().map_err(|e| unsupported(e.into()))?.port();
+                    let peer = SocketAddr::new(self.remote.ip(), client_port);
+                    (
+                        Delivery::Udp { socket, rtcp, peer },
+                        format!(
+                            "RTP/AVP;unicast;client_port={}-{};server_port={}-{}",
+                            client_port,
+                            client_port.wrapping_add(1),
+                            server_port,
+                            server_port.wrapping_add(1)
+                        ),
+                    )
+                };
+                if let Some(session) = self.session.as_mut() {
+                    session.delivery = Some(delivery);
+                }
+                Ok(Message::response(200, "OK").with_header("Transport", reply))
+            }
+            "PLAY" => {
+                let session = self
+                    .session
+                    .as_mut()
+                    .filter(|s| s.delivery.is_some())
+                    .ok_or_else(|| invalid_state(anyhow!("PLAY before SETUP")))?;
+                session.playing = true;
+                session.client.wait_keyframe = true;
+                let rtp_info = format!(
+                    "url={}/trackID=0;seq={};rtptime={}",
+                    url.trim_end_matches('/'),
+                    session.packetizer.sequence,
+                    session.packetizer.offset
+                );
+                info!("RTSP client {} playing {}", self.remote, path);
+                Ok(Message::response(200, "OK")
+                    .with_header("Range", "npt=0.000-")
+                    .with_header("RTP-Info", rtp_info))
+            }
+            "TEARDOWN" => {
+                self.session = None;
+                self.publisher = None;
+                Ok(Message::response(200, "OK"))
+            }
+            _ => Err((501, "Not Implemented", anyhow!("unsupported method {}", method))),
+        }
+    }
+
+    async fn send_frame(&mut self, frame: &DataFrame) -> Result<(), Error> {
+        let Some(session) = self.session.as_mut() else { return Ok(()) };
+        let ts = frame.metadata["ts"].as_f64().unwrap_or_default();
+        let packets = session.packetizer.packetize(ts, &frame.payload());
+        match &session.delivery {
+            Some(Delivery::Interleaved(channel)) => {
+                let mut out = Vec::with_capacity(packets.iter().map(|p| p.len() + 4).sum());
+                for packet in &packets {
+                    out.push(b'$');
+                    out.push(*channel);
+                    out.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+                    out.extend_from_slice(packet);
+                }
+                self.writer.write_all(&out).await?;
+            }
+            Some(Delivery::Udp { socket, peer, .. }) => {
+                for packet in &packets {
+                    socket.send_to(packet, peer).await?;
+                }
+            }
+            None => {}
+        }
+        session.client.sent(frame);
+        Ok(())
+    }
+
+    async fn send_report(&mut self) -> Result<(), Error> {
+        let Some(session) = self.session.as_ref().filter(|s| s.playing) else { return Ok(()) };
+        let Some(report) = session.packetizer.sender_report() else { return Ok(()) };
+        match &session.delivery {
+            Some(Delivery::Interleaved(channel)) => {
+                let mut out = vec![b'$', channel.wrapping_add(1)];
+                out.extend_from_slice(&(report.len() as u16).to_be_bytes());
+                out.extend_from_slice(&report);
+                self.writer.write_all(&out).await?;
+            }
+            Some(Delivery::Udp { rtcp, peer, .. }) => {
+                rtcp.send_to(&report, SocketAddr::new(peer.ip(), peer.port().wrapping_add(1))).await?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+async fn serve(app_context: AppContext, stream: TcpStream) -> Result<(), Error> {
+    let local = stream.local_addr()?;
+    let remote = stream.peer_addr()?;
+    let (read, writer) = stream.into_split();
+    let (tx, mut incoming) = mpsc::channel(256);
+    let mut reader = tokio::spawn(read_requests(read, tx.clone()));
+    let mut connection = Connection { app_context, writer, local, remote, session: None, publisher: None, incoming: tx };
+
+    let mut last_activity = Instant::now();
+    let mut expiry = tokio::time::interval(Duration::from_secs(5));
+    let mut reports = tokio::time::interval(Duration::from_secs(REPORT_INTERVAL));
+    let result = async {
+        loop {
+            // the pending requests are handled before noticing the connection is closed
+            tokio::select! {
+                biased;
+                incoming = incoming.recv() => {
+                    last_activity = Instant::now();
+                    match incoming {
+                        Some(Incoming::Request(request)) => {
+                            let response = connection.handle(&request).await;
+                            response.write_to(&mut connection.writer).await?;
+                        }
+                        Some(Incoming::Packet(channel, packet, received)) => {
+                            if let Some(publisher) = connection.publisher.as_mut() {
+                                publisher.push(channel, &packet, received);
+                            }
+                        }
+                        None => break,
+                    }
+                }
+                frame = Session::next(&mut connection.session) => {
+                    match frame {
+                        Some(frame) => connection.send_frame(&frame).await?,
+                        None => break,
+                    }
+                }
+                _ = &mut reader => break,
+                _ = reports.tick() => connection.send_report().await?,
+                _ = expiry.tick() => {
+                    // UDP clients keep their session alive with requests, or packets for publishers
+                    let udp = matches!(connection.session.as_ref().and_then(|s| s.delivery.as_ref()), Some(Delivery::Udp { .. }))
+                        || connection.publisher.as_ref().is_some_and(|p| p.tracks.iter().any(|t| t.udp.is_some()));
+                    if udp && last_activity.elapsed() > Duration::from_secs(SESSION_TIMEOUT) {
+                        info!("RTSP client {} session timed out", remote);
+                        break;
+                    }
+                }
+            }
+        }
+        Ok::<(), Error>(())
+    }
+    .await;
+    reader.abort();
+    result
+}
+
+/// Serve the streams to RTSP clients at `rtsp://host:port/{name}`, and accept
+/// publishers pushing to them with ANNOUNCE and RECORD.
+pub async fn run(app_context: AppContext, port: u16) -> Result<(), Error> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    info!("RTSP server listening on TCP port {port}");
+    loop {
+        let (stream, remote) = listener.accept().await?;
+        let app_context = app_context.clone();
+        tokio::spawn(async move {
+            debug!("RTSP client {} connected", remote);
+            if let Err(e) = serve(app_context, stream).await {
+                warn!("RTSP client {} error: {}", remote, e);
+            }
+            debug!("RTSP client {} disconnected", remote);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtsp::{Challenge, Credentials};
+    use std::collections::HashMap;
+
+    #[test]
+    fn rtp_timestamps_start_at_the_announced_offset() {
+        let mut packetizer = Packetizer::new(false);
+        let offset = packetizer.offset;
+        let nal = [0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00];
+        let first = packetizer.packetize(60_000.0, &nal);
+        let second = packetizer.packetize(60_040.0, &nal);
+        let timestamp = |packet: &[u8]| u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        assert_eq!(timestamp(&first[0]), offset);
+        assert_eq!(timestamp(&second[0]), offset.wrapping_add(3600));
+
+        let report = packetizer.sender_report().unwrap();
+        assert_eq!(report.len(), 28);
+        assert_eq!(&report[..4], &[0x80, 200, 0, 6]);
+        assert_eq!(&report[4..8], &packetizer.ssrc.to_be_bytes());
+        assert!(u32::from_be_bytes([report[16], report[17], report[18], report[19]]).wrapping_sub(offset.wrapping_add(3600)) < 900);
+        assert_eq!(u32::from_be_bytes([report[20], report[21], report[22], report[23]]), 2);
+        assert_eq!(u32::from_be_bytes([report[24], report[25], report[26], report[27]]), 8);
+    }
+
+    #[tokio::test]
+    async fn udp_publishers_get_an_even_rtp_port_and_its_rtcp_port() {
+        let publish = Credentials { user: "pub".to_string(), password: "secret".to_string() };
+        let app_context = AppContext::new(HashMap::new(), Some(publish.clone()), None, None);
+        let url = "rtsp://127.0.0.1:8554/live";
+        let sdp = "v=0\r\ns=live\r\nm=video 0 RTP/AVP 96\r\na=rtpmap:96 H264/90000\r\na=control:trackID=0\r\n";
+        let challenge = Challenge::parse(&Message::response(401, "Unauthorized").with_header("WWW-Authenticate", rtsp::digest_challenge())).unwrap();
+        let request = Message::request("ANNOUNCE", url)
+            .with_header("Authorization", publish.authorization(&challenge, "ANNOUNCE", url, 1))
+            .with_body("application/sdp", sdp);
+        let remote: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let mut publisher = Publisher::announce(&app_context, url, "/live", &request, &remote).map_err(|(_, _, e)| e).unwrap();
+
+        let local: SocketAddr = "127.0.0.1:8554".parse().unwrap();
+        let (incoming, _rx) = mpsc::channel(1);
+        let transport = publisher
+            .setup(&format!("{url}/trackID=0"), "RTP/AVP;unicast;client_port=5000-5001", &local, &remote, &incoming)
+            .await
+            .map_err(|(_, _, e)| e)
+            .unwrap();
+        let ports: Vec<u16> = transport_param(&transport, "server_port").unwrap().split('-').map(|p| p.parse().unwrap()).collect();
+        assert_eq!(ports[0] % 2, 0);
+        assert_eq!(ports[1], ports[0] + 1);
+        assert!(UdpSocket::bind(("127.0.0.1", ports[1])).await.is_err(), "RTCP port not bound");
+    }
+}
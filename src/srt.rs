@@ -0,0 +1,452 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use anyhow::{anyhow, Error};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use retina::codec::{CodecItem, ParametersRef, VideoParameters};
+use srt_protocol::settings::KeySettings;
+use srt_tokio::access::{RejectReason, ServerRejectReason};
+use srt_tokio::options::KeySize;
+use srt_tokio::{SrtListener, SrtSocket};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::appcontext::AppContext;
+use crate::bitstream::nal_units;
+use crate::clientsession::ClientSession;
+use crate::mpegts::{self, Demuxer, Pes};
+use crate::rtsp::{RtpReceiver, SdpMedia};
+use crate::rtspclient;
+use crate::streamdef::{FrameSender, Viewer};
+
+/// Latency of the SRT connections when the source URL does not set it.
+const DEFAULT_LATENCY: Duration = Duration::from_millis(120);
+
+/// Transport stream packets per SRT message, the live mode payload being
+/// limited to 1316 bytes.
+const PACKETS_PER_MESSAGE: usize = 7;
+
+const PAYLOAD_TYPE: u8 = 96;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Connect to the remote site.
+    Caller,
+    /// Wait for the remote site to connect, again after each disconnection.
+    Listener,
+}
+
+/// Settings of an SRT source, given in its URL:
+/// `srt://host:port?mode=listener&passphrase=secret&latency=200&streamid=cam1`.
+#[derive(Clone, Debug)]
+pub struct SrtOptions {
+    pub mode: Mode,
+    /// Remote address of a caller, local address of a listener.
+    pub address: String,
+    pub passphrase: Option<String>,
+    pub latency: Duration,
+    pub streamid: Option<String>,
+}
+
+impl SrtOptions {
+    pub fn from_url(url: &url::Url) -> Result<Self, Error> {
+        let host = url.host_str().ok_or_else(|| anyhow!("missing host in {}", url))?;
+        let port = url.port().ok_or_else(|| anyhow!("missing port in {}", url))?;
+        let mut options = Self {
+            mode: Mode::Caller,
+            address: format!("{}:{}", host, port),
+            passphrase: None,
+            latency: DEFAULT_LATENCY,
+            streamid: None,
+        };
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "mode" => {
+                    options.mode = match value.as_ref() {
+                        "caller" => Mode::Caller,
+                        "listener" => Mode::Listener,
+                        _ => return Err(anyhow!("unknown SRT mode '{}'", value)),
+                    }
+                }
+                "passphrase" => options.passphrase = Some(check_passphrase(&value)?),
+                "latency" => options.latency = Duration::from_millis(value.parse().map_err(|_| anyhow!("invalid SRT latency '{}'", value))?),
+                "streamid" => options.streamid = Some(value.into_owned()),
+                _ => return Err(anyhow!("unknown SRT option '{}'", key)),
+            }
+        }
+        Ok(options)
+    }
+
+    async fn connect(&self) -> Result<SrtSocket, Error> {
+        let address = tokio::net::lookup_host(&self.address)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("cannot resolve {}", self.address))?;
+        let mut builder = SrtSocket::builder().latency(self.latency);
+        if let Some(passphrase) = &self.passphrase {
+            builder = builder.encryption(16, passphrase.clone());
+        }
+        let socket = match self.mode {
+            Mode::Caller => builder.call(address, self.streamid.as_deref()).await?,
+            Mode::Listener => builder.listen_on(address).await?,
+        };
+        Ok(socket)
+    }
+}
+
+/// SRT passphrases are 10 to 79 characters long.
+pub fn check_passphrase(passphrase: &str) -> Result<String, Error> {
+    match passphrase.len() {
+        10..=79 => Ok(passphrase.to_string()),
+        _ => Err(anyhow!("SRT passphrase must be 10 to 79 characters long")),
+    }
+}
+
+/// An elementary stream of the transport stream, handed to retina as RTP
+/// packets so that the frames are built as for the RTSP sources.
+struct Track {
+    /// Stream type of a video track, AudioSpecificConfig of an AAC track.
+    format: Vec<u8>,
+    receiver: RtpReceiver,
+}
+
+impl Track {
+    fn new(format: Vec<u8>, media: &SdpMedia) -> Result<Self, Error> {
+        Ok(Self { format, receiver: RtpReceiver::new(0, media)? })
+    }
+}
+
+/// Access unit delimiters, which are not carried over RTP.
+fn is_delimiter(stream_type: u8, nal: &[u8]) -> bool {
+    let Some(&header) = nal.first() else { return true };
+    match stream_type {
+        mpegts::STREAM_TYPE_H264 => header & 0x1f == 9,
+        _ => (header >> 1) & 0x3f == 35,
+    }
+}
+
+/// Frames of the MPEG-TS stream of an SRT source.
+struct Ingest<'a> {
+    name: &'a str,
+    tx: &'a FrameSender,
+    metadata: bool,
+    demuxer: Demuxer,
+    video: Option<Track>,
+    video_params: Option<VideoParameters>,
+    audio: Option<Track>,
+    /// When the data being demuxed was delivered by the socket.
+    received: Instant,
+}
+
+impl Ingest<'_> {
+    fn push(&mut self, data: &[u8]) {
+        self.received = Instant::now();
+        for pes in self.demuxer.push(data) {
+            let result = match pes.stream_type {
+                mpegts::STREAM_TYPE_H264 | mpegts::STREAM_TYPE_H265 => self.push_video(&pes),
+                mpegts::STREAM_TYPE_AAC => self.push_audio(&pes),
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                warn!("SRT {} depacketization error: {}", self.name, e);
+            }
+        }
+    }
+
+    fn push_video(&mut self, pes: &Pes) -> Result<(), Error> {
+        let Some(pts) = pes.pts else { return Ok(()) };
+        if self.video.as_ref().map(|track| track.format.as_slice()) != Some(&[pes.stream_type]) {
+            let media = SdpMedia {
+                media: "video".to_string(),
+                payload_type: PAYLOAD_TYPE,
+                encoding_name: if pes.stream_type == mpegts::STREAM_TYPE_H264 { "h264" } else { "h265" }.to_string(),
+                clock_rate: 90000,
+                ..Default::default()
+            };
+            self.video = Some(Track::new(vec![pes.stream_type], &media)?);
+            self.video_params = None;
+        }
+        let Some(track) = self.video.as_mut() else { return Ok(()) };
+        let nals: Vec<&[u8]> = nal_units(&pes.data)
+            .into_iter()
+            .filter(|nal| !is_delimiter(pes.stream_type, nal))
+            .collect();
+        for (i, nal) in nals.iter().enumerate() {
+            // 33-bit PTS, whose wrap around is also one of the 32-bit RTP timestamps
+            let loss = i == 0 && pes.discontinuity;
+            track.receiver.push_payload(pts as u32, i + 1 == nals.len(), nal, loss, self.received)?;
+        }
+        // no sender report maps the PTS to a wall clock, the frames have no "ntp"
+        track.receiver.broadcast_video(&mut self.video_params, self.metadata, self.tx)
+    }
+
+    fn push_audio(&mut self, pes: &Pes) -> Result<(), Error> {
+        let Some(pts) = pes.pts else { return Ok(()) };
+        for (i, frame) in mpegts::adts_frames(&pes.data).iter().enumerate() {
+            if self.audio.as_ref().map(|track| track.format.as_slice()) != Some(&frame.config) {
+                let media = SdpMedia {
+                    media: "audio".to_string(),
+                    payload_type: PAYLOAD_TYPE,
+                    encoding_name: "mpeg4-generic".to_string(),
+                    clock_rate: frame.sample_rate,
+                    channels: Some(frame.channels),
+                    fmtp: Some(format!(
+                        "streamtype=5;profile-level-id=1;mode=AAC-hbr;sizelength=13;indexlength=3;indexdeltalength=3;config={:02x}{:02x}",
+                        frame.config[0], frame.config[1]
+                    )),
+                    ..Default::default()
+                };
+                let track = Track::new(frame.config.to_vec(), &media)?;
+                if let Some(ParametersRef::Audio(audio_params)) = track.receiver.parameters() {
+                    rtspclient::send_frame(self.tx, rtspclient::audio_config_frame("mpeg4-generic", audio_params));
+                }
+                self.audio = Some(track);
+            }
+            let Some(track) = self.audio.as_mut() else { continue };
+            // one access unit per packet, after its RFC 3640 AU header section
+            let mut payload = vec![0x00, 0x10, (frame.data.len() >> 5) as u8, (frame.data.len() << 3) as u8];
+            payload.extend_from_slice(frame.data);
+            let timestamp = pts * u64::from(frame.sample_rate) / 90000 + 1024 * i as u64;
+            track.receiver.push_payload(timestamp as u32, true, &payload, false, self.received)?;
+            while let Some(item) = track.receiver.pull() {
+                if let CodecItem::AudioFrame(m) = item? {
+                    if let Some(ParametersRef::Audio(audio_params)) = track.receiver.parameters() {
+                        rtspclient::process_audio_frame(m, self.received, "mpeg4-generic", audio_params, None, self.tx);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Receive the MPEG-TS stream of an SRT source until `stop` completes. A
+/// listener waits for the next connection when its caller disconnects.
+pub async fn run_until<Stop>(url: &url::Url, metadata: bool, tx: FrameSender, stop: Stop) -> Result<(), Error>
+where
+    Stop: Future<Output = ()>,
+{
+    let options = SrtOptions::from_url(url)?;
+    // the URL is not logged, it holds the passphrase
+    let name = format!("srt://{}", options.address);
+    tokio::pin!(stop);
+    loop {
+        if options.mode == Mode::Listener {
+            info!("SRT {} waiting for caller", name);
+        }
+        let mut socket = tokio::select! {
+            socket = options.connect() => socket?,
+            _ = &mut stop => return Ok(()),
+        };
+        info!("SRT {} connected", name);
+        let mut ingest = Ingest {
+            name: &name,
+            tx: &tx,
+            metadata,
+            demuxer: Demuxer::default(),
+            video: None,
+            video_params: None,
+            audio: None,
+            received: Instant::now(),
+        };
+        let error = loop {
+            tokio::select! {
+                item = socket.next() => match item {
+                    Some(Ok((_, data))) => ingest.push(&data),
+                    Some(Err(e)) => break Error::from(e),
+                    None => break anyhow!("connection closed"),
+                },
+                _ = &mut stop => {
+                    if let Err(e) = socket.close_and_finish().await {
+                        debug!("SRT {} close failed: {}", name, e);
+                    }
+                    return Ok(());
+                }
+            }
+        };
+        match options.mode {
+            Mode::Caller => return Err(error),
+            Mode::Listener => info!("SRT {} caller disconnected: {}", name, error),
+        }
+    }
+}
+
+/// Stream requested by an SRT caller, with a plain stream id (`name` or
+/// `/name`) or the resource of an access control one (`#!::r=name,m=request`).
+fn requested_path(stream_id: Option<&str>) -> Result<String, ServerRejectReason> {
+    let stream_id = stream_id.ok_or(ServerRejectReason::BadRequest)?;
+    let name = match stream_id.strip_prefix("#!::") {
+        Some(entries) => {
+            let mut name = None;
+            for (key, value) in entries.split(',').filter_map(|entry| entry.split_once('=')) {
+                match key {
+                    "r" => name = Some(value),
+                    // only playback is offered
+                    "m" if value != "request" => return Err(ServerRejectReason::BadMode),
+                    _ => {}
+                }
+            }
+            name.ok_or(ServerRejectReason::BadRequest)?
+        }
+        None => stream_id,
+    };
+    Ok(format!("/{}", name.trim_start_matches('/')))
+}
+
+async fn serve(mut viewer: Viewer, mut client: ClientSession, socket: SrtSocket, remote: SocketAddr) -> Result<(), Error> {
+    let (mut sink, mut stream) = socket.split();
+    let mut remuxer = mpegts::Remuxer::default();
+    loop {
+        tokio::select! {
+            frame = viewer.rx.recv() => match frame {
+                Ok(frame) => {
+                    if !client.accept(&frame) {
+                        continue;
+                    }
+                    let packets = remuxer.remux(frame.clone());
+                    for message in packets.chunks(PACKETS_PER_MESSAGE * mpegts::PACKET_SIZE) {
+                        sink.feed((Instant::now(), Bytes::copy_from_slice(message))).await?;
+                    }
+                    sink.flush().await?;
+                    client.sent(&frame);
+                }
+                Err(RecvError::Lagged(n)) => {
+                    warn!("SRT client {} lagged {} frames, waiting for next keyframe", remote, n);
+                    client.lagged(n);
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            // callers do not send data, the stream ends when they disconnect
+            item = stream.next() => if !matches!(item, Some(Ok(_))) {
+                return Ok(());
+            },
+        }
+    }
+}
+
+/// Serve the streams as MPEG-TS to the SRT callers on `port`, the stream
+/// being selected with the stream id of the caller.
+pub async fn run(app_context: AppContext, port: u16, passphrase: Option<String>) -> Result<(), Error> {
+    let passphrase = passphrase.as_deref().map(check_passphrase).transpose()?;
+    let (_listener, mut incoming) = SrtListener::builder()
+        .latency(DEFAULT_LATENCY)
+        .bind(SocketAddr::from(([0, 0, 0, 0], port)))
+        .await?;
+    info!("SRT server listening on UDP port {port}");
+    while let Some(request) = incoming.incoming().next().await {
+        let remote = request.remote();
+        let stream_id = request.stream_id().map(|id| id.to_string());
+        let stream_def = requested_path(stream_id.as_deref())
+            .and_then(|path| app_context.stream(&path).map(|s| (path, s)).ok_or(ServerRejectReason::Notfound));
+        let (path, stream_def) = match stream_def {
+            Ok(stream_def) => stream_def,
+            Err(reason) => {
+                info!("SRT client {} rejected for stream id {:?}: {:?}", remote, stream_id, reason);
+                let _ = request.reject(RejectReason::Server(reason)).await;
+                continue;
+            }
+        };
+        let key_settings = match &passphrase {
+            Some(passphrase) => match passphrase.clone().try_into() {
+                Ok(passphrase) => Some(KeySettings {
+                    key_size: match request.key_size() {
+                        KeySize::Unspecified => KeySize::AES128,
+                        key_size => key_size,
+                    },
+                    passphrase,
+                }),
+                Err(e) => return Err(anyhow!("invalid SRT passphrase: {}", e)),
+            },
+            None => None,
+        };
+
+        let app_context = app_context.clone();
+        tokio::spawn(async move {
+            let socket = match request.accept(key_settings).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    warn!("SRT client {} handshake failed: {}", remote, e);
+                    return;
+                }
+            };
+            info!("SRT client {} playing {}", remote, path);
+            let viewer = Viewer::new(stream_def, &path);
+            let client = ClientSession::new(&app_context.clients, "srt", remote.to_string(), &path);
+            if let Err(e) = serve(viewer, client, socket, remote).await {
+                warn!("SRT client {} error: {}", remote, e);
+            }
+            debug!("SRT client {} disconnected", remote);
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    const SPS: &[u8] = &[0x67, 0x64, 0x00, 0x1e, 0xac, 0xd9, 0x40, 0xa0, 0x2f, 0xf9, 0x61, 0x00, 0x00, 0x03, 0x00, 0x01, 0x00, 0x00, 0x03, 0x00, 0x3c, 0x8f, 0x16, 0x2d, 0x96];
+    const PPS: &[u8] = &[0x68, 0xeb, 0xec, 0xb2, 0x2c];
+
+    /// Access unit in Annex B, a keyframe carrying its parameter sets.
+    fn access_unit(keyframe: bool) -> Vec<u8> {
+        let nals: Vec<Vec<u8>> = match keyframe {
+            true => vec![SPS.to_vec(), PPS.to_vec(), [&[0x65u8][..], &[0x88; 500]].concat()],
+            false => vec![[&[0x41u8][..], &[0x9a; 100]].concat()],
+        };
+        nals.iter().flat_map(|nal| [&[0, 0, 0, 1][..], nal].concat()).collect()
+    }
+
+    #[tokio::test]
+    async fn frames_sent_by_a_caller_are_broadcast() {
+        let tx = FrameSender::new(100);
+        let mut rx = tx.receiver();
+        // a port free at the time, released for the listener to bind it
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let url = url::Url::parse(&format!("srt://{addr}?mode=listener")).unwrap();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let listener = tokio::spawn(async move {
+            run_until(&url, false, tx, async {
+                let _ = stop_rx.await;
+            })
+            .await
+        });
+
+        let mut caller = SrtSocket::builder().call(addr, None).await.unwrap();
+        let mut muxer = mpegts::Muxer::new(mpegts::STREAM_TYPE_H264);
+        for i in 0..10u64 {
+            let mut ts = vec![];
+            muxer.write_video(&mut ts, 90_000 + i * 3600, i % 5 == 0, &access_unit(i % 5 == 0));
+            for message in ts.chunks(PACKETS_PER_MESSAGE * mpegts::PACKET_SIZE) {
+                caller.send((Instant::now(), Bytes::copy_from_slice(message))).await.unwrap();
+            }
+        }
+
+        let mut frames = vec![];
+        while frames.len() < 3 {
+            let frame = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.expect("no frame").unwrap();
+            frames.push(frame);
+        }
+        assert!(frames[0].is_config());
+        assert_eq!(frames[0].metadata["codec"], "avc1.64001E");
+        assert!(frames[1].is_keyframe());
+        assert_eq!(frames[1].metadata["ts"], 0.0);
+        assert!(!frames[2].is_keyframe() && !frames[2].is_config());
+        assert_eq!(frames[2].metadata["ts"], 40.0);
+
+        let _ = caller.close().await;
+        let _ = stop_tx.send(());
+        listener.await.unwrap().unwrap();
+    }
+}
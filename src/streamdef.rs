@@ -7,36 +7,322 @@
 **
 ** -------------------------------------------------------------------------*/
 
-use tokio::sync::broadcast;
+use log::{error, info};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use tokio::sync::broadcast::{self, error::RecvError, error::SendError};
 use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
 
+pub use crate::dataframe::DataFrame;
+use crate::events::Events;
+use crate::rtmp::PushTarget;
+use crate::rtsp::Credentials;
+use crate::rtspclient::Source;
+
+/// Frames buffered per stream when the config does not set "capacity".
+pub const DEFAULT_CAPACITY: usize = 100;
+
+/// Sending side of the frames of a stream. The last decoder configuration of
+/// each media is kept, so that viewers joining after it was broadcast get it
+/// before their first keyframe.
 #[derive(Clone)]
-pub struct DataFrame {
-    pub metadata: serde_json::Value,
-    pub data: Vec<u8>,
+pub struct FrameSender {
+    tx: broadcast::Sender<DataFrame>,
+    configs: Arc<Mutex<Vec<DataFrame>>>,
+}
+
+impl FrameSender {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self { tx, configs: Arc::default() }
+    }
+
+    pub fn send(&self, frame: DataFrame) -> Result<usize, SendError<DataFrame>> {
+        // the lock orders the frames sent with the receivers subscribed
+        let mut configs = self.configs.lock().unwrap();
+        let frame = frame.stamped();
+        if frame.is_config() {
+            configs.retain(|config| config.metadata["media"] != frame.metadata["media"]);
+            configs.push(frame.clone());
+        }
+        self.tx.send(frame)
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.tx.receiver_count()
+    }
+
+    /// Receiver of the frames broadcast from now on, without the last
+    /// configurations.
+    pub fn subscribe(&self) -> broadcast::Receiver<DataFrame> {
+        self.tx.subscribe()
+    }
+
+    /// Receiver of the frames broadcast from now on, starting with the last
+    /// configurations.
+    pub fn receiver(&self) -> FrameReceiver {
+        let configs = self.configs.lock().unwrap();
+        FrameReceiver {
+            pending: configs.iter().cloned().collect(),
+            rx: self.tx.subscribe(),
+            configs: self.configs.clone(),
+        }
+    }
+}
+
+/// Frames of a stream received by one viewer, see [`FrameSender::receiver`].
+pub struct FrameReceiver {
+    pending: VecDeque<DataFrame>,
+    rx: broadcast::Receiver<DataFrame>,
+    configs: Arc<Mutex<Vec<DataFrame>>>,
+}
+
+impl FrameReceiver {
+    pub async fn recv(&mut self) -> Result<DataFrame, RecvError> {
+        match self.pending.pop_front() {
+            Some(frame) => Ok(frame),
+            None => self.rx.recv().await,
+        }
+    }
+
+    /// Skip the frames queued so far, starting again with the last
+    /// configurations.
+    pub fn resubscribe(&self) -> Self {
+        let configs = self.configs.lock().unwrap();
+        Self {
+            pending: configs.iter().cloned().collect(),
+            rx: self.rx.resubscribe(),
+            configs: self.configs.clone(),
+        }
+    }
+}
+
+/// A viewer of a stream, registered until dropped so that the camera is only
+/// pulled while watched.
+pub struct Viewer {
+    stream_def: Arc<Mutex<StreamsDef>>,
+    pub rx: FrameReceiver,
+}
+
+impl Viewer {
+    pub fn new(stream_def: Arc<Mutex<StreamsDef>>, name: &str) -> Self {
+        let rx = stream_def.lock().unwrap().add_viewer(name);
+        Self { stream_def, rx }
+    }
+
+    pub fn stream_def(&self) -> &Arc<Mutex<StreamsDef>> {
+        &self.stream_def
+    }
+}
+
+impl Drop for Viewer {
+    fn drop(&mut self) {
+        self.stream_def.lock().unwrap().remove_viewer();
+    }
+}
+
+/// Where a stream created by a publisher at an unconfigured path is kept,
+/// so that it can be removed once unused.
+pub struct Created {
+    pub path: String,
+    pub streams: Weak<RwLock<HashMap<String, Arc<Mutex<StreamsDef>>>>>,
 }
 
 pub struct StreamsDef {
-    pub url: url::Url,
-    pub transport: Option<String>,
-    pub tx: broadcast::Sender<DataFrame>,
+    /// Camera pulled while the stream has viewers, `None` for a stream fed by
+    /// an RTSP publisher.
+    pub source: Option<Source>,
+    /// Credentials required to publish to the stream.
+    pub publish: Option<Credentials>,
+    /// Address of the publisher currently feeding the stream.
+    pub publisher: Option<String>,
+    pub tx: FrameSender,
+    pub capacity: usize,
+    /// Path of a lower quality stream of the same camera, used for clients
+    /// that cannot keep up.
+    pub substream: Option<String>,
+    /// RTMP servers the stream is restreamed to.
+    pub push: Vec<Arc<PushTarget>>,
+    /// Bus the state changes of the source are emitted on.
+    pub events: Events,
     pub count: u32,
     pub stop_tx: Option<oneshot::Sender<()>>,
     pub task: Option<JoinHandle<()>>,
+    /// Set for a stream created by its publisher, removed when it has
+    /// neither publisher nor viewers anymore.
+    pub created: Option<Created>,
+    /// Entry of the config "urls" the stream was loaded from, compared with
+    /// the one of a reloaded config.
+    pub config: Option<serde_json::Value>,
 }
 
 impl StreamsDef {
-    pub fn new(url: url::Url, transport: Option<String>) -> Self {
-        let (tx, _) = broadcast::channel::<DataFrame>(100);
+    pub fn new(source: Source, capacity: usize) -> Self {
+        let tx = FrameSender::new(capacity);
+
+        Self {
+            source: Some(source),
+            publish: None,
+            publisher: None,
+            tx,
+            capacity,
+            substream: None,
+            push: vec![],
+            events: Events::default(),
+            count: 0,
+            stop_tx: None,
+            task: None,
+            created: None,
+            config: None,
+        }
+    }
+
+    /// Stream fed by a publisher, with the credentials it has to present.
+    pub fn published(publish: Option<Credentials>, capacity: usize) -> Self {
+        let tx = FrameSender::new(capacity);
 
         Self {
-            url,
-            transport,
+            source: None,
+            publish,
+            publisher: None,
             tx,
+            capacity,
+            substream: None,
+            push: vec![],
+            events: Events::default(),
             count: 0,
             stop_tx: None,
             task: None,
+            created: None,
+            config: None,
+        }
+    }
+
+    /// Register a viewer and start the RTSP client if it is not running yet.
+    pub fn add_viewer(&mut self, name: &str) -> FrameReceiver {
+        let rx = self.tx.receiver();
+        self.count += 1;
+
+        let Some(source) = &self.source else {
+            return rx;
+        };
+        let should_start = self.count == 1
+            || self
+                .task
+                .as_ref()
+                .map(|task| task.is_finished())
+                .unwrap_or(true);
+
+        if should_start {
+            let (stop_tx, stop_rx) = oneshot::channel();
+            let source = source.clone();
+            let tx = self.tx.clone();
+            let name = name.to_string();
+            let events = self.events.clone();
+
+            self.stop_tx = Some(stop_tx);
+            self.task = Some(tokio::spawn(async move {
+                info!("RTSP {} started", name);
+                events.emit("stream_connecting", json!({ "stream": name }));
+                let first_frame = first_media_frame(tx.subscribe());
+                let run = crate::rtspclient::run_until(source, tx, stop_rx);
+                tokio::pin!(run);
+                let result = tokio::select! {
+                    result = &mut run => result,
+                    _ = first_frame => {
+                        events.emit("stream_playing", json!({ "stream": name }));
+                        run.await
+                    }
+                };
+                if let Err(e) = result {
+                    error!("RTSP {} exited with error: {}", name, e);
+                    events.emit("stream_error", json!({ "stream": name, "error": e.to_string() }));
+                }
+                info!("RTSP {} stopped", name);
+                events.emit("stream_stopped", json!({ "stream": name }));
+            }));
+        }
+        rx
+    }
+
+    /// Unregister a viewer and stop the RTSP client when the last one leaves.
+    pub fn remove_viewer(&mut self) {
+        if self.count > 0 {
+            self.count -= 1;
+        }
+
+        if self.count == 0 {
+            if let Some(stop_tx) = self.stop_tx.take() {
+                let _ = stop_tx.send(());
+            }
+            self.task.take();
         }
+        self.remove_if_unused();
     }
-}
\ No newline at end of file
+
+    /// Stop the source of a stream removed from the config for good, its
+    /// viewers receiving the end of the stream once the source has stopped.
+    pub fn close(&mut self) {
+        self.source = None;
+        self.config = None;
+        if let Some(stop_tx) = self.stop_tx.take() {
+            let _ = stop_tx.send(());
+        }
+        self.task.take();
+        self.tx = FrameSender::new(self.capacity);
+    }
+
+    /// Release the stream from its publisher.
+    pub fn release(&mut self) {
+        self.publisher = None;
+        self.remove_if_unused();
+    }
+
+    /// Remove a stream created by its publisher from the streams once it has
+    /// neither publisher nor viewers. The streams are locked while this one
+    /// is, so they must never be locked the other way round.
+    fn remove_if_unused(&mut self) {
+        if self.publisher.is_some() || self.count > 0 {
+            return;
+        }
+        let Some(created) = self.created.take() else {
+            return;
+        };
+        if let Some(streams) = created.streams.upgrade() {
+            info!("Removing stream '{}' left by its publisher", created.path);
+            streams.write().unwrap().remove(&created.path);
+        }
+    }
+}
+
+/// Wait for the first video or audio frame, a source being playing once it
+/// delivers one: its configuration is sent before.
+async fn first_media_frame(mut rx: broadcast::Receiver<DataFrame>) {
+    loop {
+        match rx.recv().await {
+            Ok(frame) if frame.is_config() => continue,
+            Ok(_) | Err(RecvError::Lagged(_)) => return,
+            Err(RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn configurations_do_not_make_a_source_playing() {
+        let tx = FrameSender::new(10);
+        let first_frame = first_media_frame(tx.subscribe());
+        tokio::pin!(first_frame);
+
+        assert!(tx.send(DataFrame::new(json!({ "type": "config", "media": "video" }), vec![])).is_ok());
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut first_frame).await.is_err());
+        assert!(tx.send(DataFrame::new(json!({ "type": "keyframe", "media": "video" }), vec![0x65])).is_ok());
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut first_frame).await.is_ok());
+    }
+}
@@ -0,0 +1,157 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use anyhow::{anyhow, Error};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use rustls_pemfile::certs;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// Default port of `rtsps://` URLs.
+pub const RTSPS_PORT: u16 = 322;
+
+/// Trust settings of an upstream TLS connection, from the "tls" object of a
+/// stream config:
+/// `{"ca": "ca.pem", "fingerprint": "aa:bb:…", "insecure": true}`.
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// PEM bundle of the certificate authorities to trust instead of the
+    /// system ones.
+    pub ca: Option<String>,
+    /// SHA-256 fingerprint of the camera certificate, trusted whatever its
+    /// issuer.
+    pub fingerprint: Option<Vec<u8>>,
+    /// Accept any certificate, for self-signed cameras.
+    pub insecure: bool,
+}
+
+impl TlsOptions {
+    pub fn from_json(value: &serde_json::Value) -> Result<Self, Error> {
+        let fingerprint = value["fingerprint"]
+            .as_str()
+            .map(|fingerprint| {
+                let bytes = fingerprint
+                    .split(':')
+                    .map(|byte| u8::from_str_radix(byte, 16))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| anyhow!("invalid fingerprint '{}'", fingerprint))?;
+                if bytes.len() != 32 {
+                    return Err(anyhow!("fingerprint '{}' is not a SHA-256 digest", fingerprint));
+                }
+                Ok(bytes)
+            })
+            .transpose()?;
+        Ok(Self {
+            ca: value["ca"].as_str().map(str::to_string),
+            fingerprint,
+            insecure: value["insecure"].as_bool().unwrap_or(false),
+        })
+    }
+
+    pub fn client_config(&self) -> Result<ClientConfig, Error> {
+        if self.insecure || self.fingerprint.is_some() {
+            let provider = CryptoProvider::get_default()
+                .cloned()
+                .ok_or_else(|| anyhow!("no rustls crypto provider installed"))?;
+            let verifier = PinnedVerifier {
+                fingerprint: self.fingerprint.clone(),
+                provider,
+            };
+            return Ok(ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth());
+        }
+
+        let mut roots = RootCertStore::empty();
+        match &self.ca {
+            Some(ca) => {
+                let mut reader = BufReader::new(File::open(ca)?);
+                for cert in certs(&mut reader) {
+                    roots.add(cert?)?;
+                }
+            }
+            None => {
+                for cert in rustls_native_certs::load_native_certs().certs {
+                    let _ = roots.add(cert);
+                }
+            }
+        }
+        if roots.is_empty() {
+            return Err(anyhow!("no trusted certificate authority"));
+        }
+        Ok(ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth())
+    }
+
+    /// Open a TLS connection to `host:port`.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TlsStream<TcpStream>, Error> {
+        let connector = TlsConnector::from(Arc::new(self.client_config()?));
+        let server_name = ServerName::try_from(host.to_string())?;
+        let stream = TcpStream::connect((host, port)).await?;
+        Ok(connector.connect(server_name, stream).await?)
+    }
+}
+
+/// Certificate verifier accepting a pinned certificate, or any certificate
+/// when no fingerprint is given. Handshake signatures are still checked.
+#[derive(Debug)]
+struct PinnedVerifier {
+    fingerprint: Option<Vec<u8>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(fingerprint) = &self.fingerprint {
+            let digest = ring::digest::digest(&ring::digest::SHA256, end_entity);
+            if digest.as_ref() != fingerprint.as_slice() {
+                return Err(rustls::Error::General("certificate fingerprint mismatch".to_string()));
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,602 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use actix_web::{get, web, HttpResponse};
+use anyhow::{anyhow, Error};
+use log::{debug, info, warn};
+use ring::hmac;
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+
+use crate::appcontext::AppContext;
+use crate::dataframe::now_ms;
+use crate::streamdef::Viewer;
+use crate::tls::TlsOptions;
+
+/// Events a target can subscribe to.
+const EVENTS: [&str; 4] = ["stream_offline", "stream_online", "first_viewer", "recording_failed"];
+
+/// Seconds a stream stays down before `stream_offline` is sent.
+const DEFAULT_OFFLINE_AFTER: u64 = 30;
+
+/// Deliveries waiting per target, the oldest being dropped beyond.
+const QUEUE: usize = 256;
+
+/// Attempts of a delivery before giving up.
+const MAX_ATTEMPTS: u32 = 10;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Delay between the attempts to restart a monitored source that failed.
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// Largest HTTP response status line accepted.
+const MAX_STATUS_LINE: usize = 1024;
+
+struct Delivery {
+    id: u64,
+    event: &'static str,
+    body: Vec<u8>,
+    attempts: u32,
+}
+
+#[derive(Default)]
+struct Status {
+    queue: VecDeque<Delivery>,
+    next_id: u64,
+    delivered: u64,
+    failed: u64,
+    dropped: u64,
+    /// Outcome of the last attempt.
+    last: Option<serde_json::Value>,
+    next_retry: Option<f64>,
+}
+
+/// An HTTP(S) endpoint the stream state changes are posted to, from the
+/// "webhooks" array of the config:
+/// `{"url": "https://host/hook", "secret": "…", "events": ["stream_offline"], "offline_after": 30, "monitor": true}`.
+pub struct Target {
+    url: url::Url,
+    secret: Option<hmac::Key>,
+    events: Vec<&'static str>,
+    offline_after: Duration,
+    /// Keep the camera streams pulled whether they are watched or not.
+    monitor: bool,
+    tls: TlsOptions,
+    status: Mutex<Status>,
+    queued: Notify,
+}
+
+impl Target {
+    pub fn new(value: &serde_json::Value) -> Result<Self, Error> {
+        let url = value["url"].as_str().ok_or_else(|| anyhow!("webhook without url"))?;
+        let url = url::Url::parse(url)?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(anyhow!("unsupported webhook URL scheme '{}'", url.scheme()));
+        }
+        if url.host_str().is_none() {
+            return Err(anyhow!("missing host in webhook URL"));
+        }
+        let events = match value.get("events") {
+            None => EVENTS.to_vec(),
+            Some(events) => events
+                .as_array()
+                .ok_or_else(|| anyhow!("webhook events must be an array"))?
+                .iter()
+                .map(|event| {
+                    let event = event.as_str().unwrap_or_default();
+                    EVENTS
+                        .iter()
+                        .find(|known| **known == event)
+                        .copied()
+                        .ok_or_else(|| anyhow!("unknown webhook event '{}'", event))
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(Self {
+            url,
+            secret: value["secret"].as_str().map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())),
+            events,
+            offline_after: Duration::from_secs(value["offline_after"].as_u64().unwrap_or(DEFAULT_OFFLINE_AFTER)),
+            monitor: value["monitor"].as_bool().unwrap_or(false),
+            tls: TlsOptions::from_json(&value["tls"])?,
+            status: Mutex::default(),
+            queued: Notify::new(),
+        })
+    }
+
+    /// Targets of the config "webhooks" array.
+    pub fn from_json(value: &serde_json::Value) -> Result<Vec<Arc<Self>>, Error> {
+        value
+            .as_array()
+            .ok_or_else(|| anyhow!("webhooks must be an array"))?
+            .iter()
+            .map(|value| Ok(Arc::new(Self::new(value)?)))
+            .collect()
+    }
+
+    /// URL without its credentials.
+    fn display_url(&self) -> String {
+        let mut url = self.url.clone();
+        let _ = url.set_username("");
+        let _ = url.set_password(None);
+        url.to_string()
+    }
+
+    /// Queue `event` if the target subscribed to it.
+    fn push(&self, event: &'static str, mut payload: serde_json::Value) {
+        if !self.events.contains(&event) {
+            return;
+        }
+        let mut status = self.status.lock().unwrap();
+        let id = status.next_id;
+        status.next_id += 1;
+        payload["id"] = id.into();
+        payload["event"] = event.into();
+        payload["time"] = now_ms().into();
+        info!("webhook {} queued {} {}", self.display_url(), event, payload);
+        if status.queue.len() == QUEUE {
+            status.queue.pop_front();
+            status.dropped += 1;
+        }
+        let body = serde_json::to_vec(&payload).unwrap_or_default();
+        status.queue.push_back(Delivery { id, event, body, attempts: 0 });
+        self.queued.notify_one();
+    }
+
+    /// Status reported in `/api/webhooks`.
+    pub fn to_json(&self) -> serde_json::Value {
+        let status = self.status.lock().unwrap();
+        json!({
+            "url": self.display_url(),
+            "events": self.events,
+            "queued": status.queue.len(),
+            "delivered": status.delivered,
+            "failed": status.failed,
+            "dropped": status.dropped,
+            "last": status.last,
+            "next_retry": status.next_retry,
+        })
+    }
+
+    fn request(&self, delivery: &Delivery) -> Vec<u8> {
+        let host = match self.url.port() {
+            Some(port) => format!("{}:{}", self.url.host_str().unwrap_or_default(), port),
+            None => self.url.host_str().unwrap_or_default().to_string(),
+        };
+        let path = &self.url[url::Position::BeforePath..url::Position::AfterQuery];
+        let timestamp = (now_ms() / 1000.0) as u64;
+        let mut request = format!(
+            "POST {path} HTTP/1.1\r\n\
+             Host: {host}\r\n\
+             User-Agent: rtsp2web-rs\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\
+             X-Webhook-Id: {}\r\n\
+             X-Webhook-Event: {}\r\n\
+             X-Webhook-Timestamp: {timestamp}\r\n",
+            delivery.body.len(),
+            delivery.id,
+            delivery.event,
+        );
+        // signed with the timestamp so that a captured request cannot be replayed later
+        if let Some(key) = &self.secret {
+            let mut context = hmac::Context::with_key(key);
+            context.update(format!("{timestamp}.").as_bytes());
+            context.update(&delivery.body);
+            let signature: String = context.sign().as_ref().iter().map(|b| format!("{b:02x}")).collect();
+            request += &format!("X-Webhook-Signature: sha256={signature}\r\n");
+        }
+        request += "\r\n";
+        let mut request = request.into_bytes();
+        request.extend_from_slice(&delivery.body);
+        request
+    }
+
+    /// Post `delivery`, returning the HTTP status of the response.
+    async fn post(&self, delivery: &Delivery) -> Result<u16, Error> {
+        let host = self.url.host_str().unwrap_or_default();
+        let port = self.url.port_or_known_default().unwrap_or(80);
+        let request = self.request(delivery);
+        if self.url.scheme() == "https" {
+            exchange(self.tls.connect(host, port).await?, &request).await
+        } else {
+            exchange(TcpStream::connect((host, port)).await?, &request).await
+        }
+    }
+
+    /// Deliver the queued events in order, retrying failed deliveries with an
+    /// increasing delay.
+    async fn deliver(&self) {
+        let mut delay = RETRY_DELAY;
+        loop {
+            let delivery = {
+                let mut status = self.status.lock().unwrap();
+                status.next_retry = None;
+                status.queue.front_mut().map(|delivery| {
+                    delivery.attempts += 1;
+                    Delivery { body: delivery.body.clone(), ..*delivery }
+                })
+            };
+            let Some(delivery) = delivery else {
+                self.queued.notified().await;
+                continue;
+            };
+            let result = match tokio::time::timeout(TIMEOUT, self.post(&delivery)).await {
+                Ok(Ok(code)) if (200..300).contains(&code) => Ok(code),
+                Ok(Ok(code)) => Err(anyhow!("HTTP status {}", code)),
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(anyhow!("timeout")),
+            };
+            let retry = {
+                let mut status = self.status.lock().unwrap();
+                status.last = Some(json!({
+                    "id": delivery.id,
+                    "event": delivery.event,
+                    "attempt": delivery.attempts,
+                    "time": now_ms(),
+                    "error": result.as_ref().err().map(|e| e.to_string()),
+                }));
+                let retry = match result {
+                    Ok(code) => {
+                        debug!("webhook {} delivered {} #{}: {}", self.display_url(), delivery.event, delivery.id, code);
+                        status.delivered += 1;
+                        false
+                    }
+                    Err(e) if delivery.attempts >= MAX_ATTEMPTS => {
+                        warn!("webhook {} gave up {} #{}: {}", self.display_url(), delivery.event, delivery.id, e);
+                        status.failed += 1;
+                        false
+                    }
+                    Err(e) => {
+                        warn!("webhook {} failed {} #{}, retrying in {:?}: {}", self.display_url(), delivery.event, delivery.id, delay, e);
+                        status.next_retry = Some(now_ms() + delay.as_millis() as f64);
+                        true
+                    }
+                };
+                // the delivery may have been dropped from a full queue meanwhile
+                if !retry && status.queue.front().map(|front| front.id) == Some(delivery.id) {
+                    status.queue.pop_front();
+                }
+                retry
+            };
+            if !retry {
+                delay = RETRY_DELAY;
+                continue;
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+}
+
+/// Send an HTTP request and read the status code of its response.
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, request: &[u8]) -> Result<u16, Error> {
+    stream.write_all(request).await?;
+    let mut response = Vec::new();
+    let status_line = loop {
+        let mut chunk = [0u8; 512];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow!("connection closed before the response"));
+        }
+        response.extend_from_slice(&chunk[..n]);
+        if let Some(end) = response.iter().position(|b| *b == b'\n') {
+            break String::from_utf8_lossy(&response[..end]).trim().to_string();
+        }
+        if response.len() > MAX_STATUS_LINE {
+            return Err(anyhow!("response status line too long"));
+        }
+    };
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| anyhow!("invalid response '{}'", status_line))
+}
+
+/// State of a stream, followed from the events bus.
+#[derive(Default)]
+struct StreamState {
+    viewers: usize,
+    /// Since when the source has not been playing, while it should.
+    down_since: Option<(Instant, f64)>,
+    /// The current run of the source ended with an error.
+    failed: bool,
+    error: Option<String>,
+    offline: bool,
+}
+
+/// Webhook events derived from the events bus.
+#[derive(Default)]
+struct Watcher {
+    streams: HashMap<String, StreamState>,
+}
+
+impl Watcher {
+    fn on_event(&mut self, event: &serde_json::Value) -> Option<(&'static str, serde_json::Value)> {
+        let name = event["stream"].as_str()?.to_string();
+        let state = self.streams.entry(name.clone()).or_default();
+        match event["type"].as_str()? {
+            "stream_connecting" => {
+                state.failed = false;
+                state.down_since.get_or_insert((Instant::now(), now_ms()));
+            }
+            "stream_playing" => {
+                let down_since = state.down_since.take();
+                if state.offline {
+                    state.offline = false;
+                    let downtime = down_since.map(|(since, _)| since.elapsed().as_secs()).unwrap_or_default();
+                    return Some(("stream_online", json!({ "stream": name, "downtime": downtime })));
+                }
+            }
+            "stream_error" => {
+                state.failed = true;
+                state.error = event["error"].as_str().map(str::to_string);
+                state.down_since.get_or_insert((Instant::now(), now_ms()));
+            }
+            // stopped for lack of viewers
+            "stream_stopped" if !state.failed => state.down_since = None,
+            "viewer_connected" => {
+                state.viewers += 1;
+                if state.viewers == 1 {
+                    let payload = json!({ "stream": name, "transport": event["transport"], "remote": event["remote"] });
+                    return Some(("first_viewer", payload));
+                }
+            }
+            "viewer_disconnected" => state.viewers = state.viewers.saturating_sub(1),
+            "recording_failed" => {
+                let payload = json!({ "stream": name, "file": event["file"], "error": event["error"] });
+                return Some(("recording_failed", payload));
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Streams down for `offline_after`, reported once until they play again.
+    fn offline(&mut self, offline_after: Duration) -> Vec<serde_json::Value> {
+        let mut offline = vec![];
+        for (name, state) in &mut self.streams {
+            let Some((since, since_ms)) = state.down_since else {
+                continue;
+            };
+            if !state.offline && since.elapsed() >= offline_after {
+                state.offline = true;
+                offline.push(json!({ "stream": name, "since": since_ms, "error": state.error }));
+            }
+        }
+        offline
+    }
+}
+
+/// Keep a viewer on each camera stream, restarting the sources that failed,
+/// so that their outages are noticed and their recovery too whether they are
+/// watched or not.
+fn keep_pulled(app_context: &AppContext, viewers: &mut HashMap<String, Viewer>) {
+    let streams = app_context.stream_list();
+    // the streams removed or changed by a config reload are released
+    viewers.retain(|path, viewer| streams.iter().any(|(other, stream_def)| other == path && Arc::ptr_eq(stream_def, viewer.stream_def())));
+    for (path, stream_def) in streams {
+        if stream_def.lock().unwrap().source.is_none() {
+            continue;
+        }
+        let viewer = viewers.entry(path.clone()).or_insert_with(|| Viewer::new(stream_def, &path));
+        let finished = viewer.stream_def().lock().unwrap().task.as_ref().is_none_or(|task| task.is_finished());
+        if finished {
+            // added before the previous one leaves, which restarts the source
+            *viewer = Viewer::new(viewer.stream_def().clone(), &path);
+        }
+    }
+}
+
+/// Post the events of the bus to `target` for as long as the server runs.
+pub async fn run(app_context: AppContext, target: Arc<Target>) {
+    let mut rx = app_context.events.subscribe();
+    let deliver = target.deliver();
+    tokio::pin!(deliver);
+    let mut watcher = Watcher::default();
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let mut viewers = HashMap::new();
+    let mut restart = tokio::time::interval(RESTART_DELAY);
+    loop {
+        tokio::select! {
+            _ = &mut deliver => {}
+            event = rx.recv() => match event {
+                Ok(event) => {
+                    let Ok(event) = serde_json::from_slice(&event) else {
+                        continue;
+                    };
+                    if let Some((kind, payload)) = watcher.on_event(&event) {
+                        target.push(kind, payload);
+                    }
+                }
+                Err(RecvError::Lagged(n)) => warn!("webhook {} missed {} events", target.display_url(), n),
+                Err(RecvError::Closed) => return,
+            },
+            _ = tick.tick() => {
+                for payload in watcher.offline(target.offline_after) {
+                    target.push("stream_offline", payload);
+                }
+            }
+            _ = restart.tick(), if target.monitor => keep_pulled(&app_context, &mut viewers),
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/webhooks",
+    responses(
+        (status = 200, description = "Webhook targets with their queued, delivered, failed and dropped events and the outcome of the last attempt")
+    )
+)]
+#[get("/api/webhooks")]
+pub async fn webhooks(data: web::Data<AppContext>) -> HttpResponse {
+    let targets: Vec<_> = data.webhooks.iter().map(|target| target.to_json()).collect();
+    HttpResponse::Ok().json(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Read an HTTP request, returning its headers and body.
+    async fn read_request(stream: &mut TcpStream) -> (HashMap<String, String>, Vec<u8>) {
+        let mut request = Vec::new();
+        let end = loop {
+            let mut chunk = [0u8; 512];
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "connection closed before the request");
+            request.extend_from_slice(&chunk[..n]);
+            if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end;
+            }
+        };
+        let headers: HashMap<_, _> = String::from_utf8_lossy(&request[..end])
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(": "))
+            .map(|(name, value)| (name.to_lowercase(), value.to_string()))
+            .collect();
+        let length: usize = headers["content-length"].parse().unwrap();
+        let mut body = request[end + 4..].to_vec();
+        while body.len() < length {
+            let mut chunk = [0u8; 512];
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "connection closed before the body");
+            body.extend_from_slice(&chunk[..n]);
+        }
+        (headers, body)
+    }
+
+    fn event(kind: &str) -> serde_json::Value {
+        json!({ "type": kind, "stream": "/cam", "error": "connection refused" })
+    }
+
+    #[test]
+    fn streams_down_for_offline_after_are_reported_until_they_play_again() {
+        let offline_after = Duration::from_millis(50);
+        let mut watcher = Watcher::default();
+        assert!(watcher.on_event(&event("stream_connecting")).is_none());
+        assert!(watcher.on_event(&event("stream_error")).is_none());
+        assert!(watcher.offline(offline_after).is_empty());
+
+        std::thread::sleep(offline_after);
+        let offline = watcher.offline(offline_after);
+        assert_eq!(offline.len(), 1);
+        assert_eq!(offline[0]["stream"], "/cam");
+        assert_eq!(offline[0]["error"], "connection refused");
+        assert!(offline[0]["since"].as_f64().is_some());
+        // reported once
+        assert!(watcher.offline(offline_after).is_empty());
+
+        // a failed source is still down while restarting
+        assert!(watcher.on_event(&event("stream_stopped")).is_none());
+        assert!(watcher.on_event(&event("stream_connecting")).is_none());
+        std::thread::sleep(Duration::from_millis(1000));
+        let (kind, payload) = watcher.on_event(&event("stream_playing")).expect("no stream_online");
+        assert_eq!(kind, "stream_online");
+        assert_eq!(payload["stream"], "/cam");
+        assert_eq!(payload["downtime"], 1);
+        assert!(watcher.on_event(&event("stream_playing")).is_none());
+    }
+
+    #[test]
+    fn streams_stopped_for_lack_of_viewers_are_not_offline() {
+        let mut watcher = Watcher::default();
+        watcher.on_event(&event("stream_connecting"));
+        watcher.on_event(&event("stream_playing"));
+        watcher.on_event(&event("stream_stopped"));
+        assert!(watcher.offline(Duration::ZERO).is_empty());
+
+        // nor those that recover before offline_after
+        watcher.on_event(&event("stream_connecting"));
+        watcher.on_event(&event("stream_error"));
+        watcher.on_event(&event("stream_connecting"));
+        assert!(watcher.on_event(&event("stream_playing")).is_none());
+        assert!(watcher.offline(Duration::ZERO).is_empty());
+    }
+
+    #[test]
+    fn failed_recordings_are_reported() {
+        let mut watcher = Watcher::default();
+        let mut failed = event("recording_failed");
+        failed["file"] = "recordings/cam.ts".into();
+        let (kind, payload) = watcher.on_event(&failed).unwrap();
+        assert_eq!(kind, "recording_failed");
+        assert_eq!(payload, json!({ "stream": "/cam", "file": "recordings/cam.ts", "error": "connection refused" }));
+        assert!(watcher.on_event(&event("recording_started")).is_none());
+        assert!(watcher.on_event(&event("recording_stopped")).is_none());
+    }
+
+    #[tokio::test]
+    async fn signs_deliveries_and_retries_after_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://127.0.0.1:{}/hook", listener.local_addr().unwrap().port());
+        let target = Target::new(&json!({ "url": url, "secret": "s3cret", "events": ["first_viewer"] })).unwrap();
+        target.push("first_viewer", json!({ "stream": "cam" }));
+
+        let server = async {
+            let mut bodies = vec![];
+            for status in ["500 Internal Server Error", "200 OK"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (headers, body) = read_request(&mut stream).await;
+                assert_eq!(headers["x-webhook-event"], "first_viewer");
+                let signature = headers["x-webhook-signature"].strip_prefix("sha256=").unwrap();
+                let signature: Vec<u8> = (0..signature.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&signature[i..i + 2], 16).unwrap())
+                    .collect();
+                let mut signed = format!("{}.", headers["x-webhook-timestamp"]).into_bytes();
+                signed.extend_from_slice(&body);
+                let key = hmac::Key::new(hmac::HMAC_SHA256, b"s3cret");
+                assert!(hmac::verify(&key, &signed, &signature).is_ok());
+                stream.write_all(format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n").as_bytes()).await.unwrap();
+                bodies.push(body);
+            }
+            bodies
+        };
+        let deliver = target.deliver();
+        tokio::pin!(deliver);
+        let bodies = tokio::select! {
+            bodies = server => bodies,
+            _ = &mut deliver => unreachable!(),
+            _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("no retry after the error"),
+        };
+        // until the outcome of the second attempt is recorded
+        let drained = async {
+            while target.to_json()["queued"] != 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::select! {
+            _ = drained => {}
+            _ = &mut deliver => unreachable!(),
+        }
+
+        assert_eq!(bodies[0], bodies[1]);
+        let payload: serde_json::Value = serde_json::from_slice(&bodies[1]).unwrap();
+        assert_eq!(payload["stream"], "cam");
+        let status = target.to_json();
+        assert_eq!(status["delivered"], 1);
+        assert_eq!(status["queued"], 0);
+        assert_eq!(status["last"]["attempt"], 2);
+    }
+}
@@ -6,89 +6,206 @@
 ** SPDX-License-Identifier: Unlicense
 **
 ** -------------------------------------------------------------------------*/
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 
-use actix::{Actor, AsyncContext, StreamHandler};
+use actix::{Actor, AsyncContext, SpawnHandle, StreamHandler};
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_http::ws::Item;
 use actix_web_actors::ws;
-use log::{error, info};
-use tokio::sync::oneshot;
-use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
-use crate::streamdef::DataFrame;
+use anyhow::anyhow;
+use futures::StreamExt;
+use log::{info, warn};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use crate::appcontext::AppContext;
+use crate::bitstream::{self, Bitstream};
+use crate::clientsession::{self, ClientSession, Command, SendQueue};
+use crate::streamdef::{DataFrame, FrameReceiver};
 use crate::streamdef::StreamsDef;
 
 pub struct WebsocketService {
     pub wsurl: String,
     pub wscontext: Arc<Mutex<StreamsDef>>,
+    app_context: AppContext,
+    session: ClientSession,
+    /// Stream currently delivered, which is the substream of `wscontext` when
+    /// the quality has been reduced.
+    subscription: Option<(SpawnHandle, Arc<Mutex<StreamsDef>>)>,
+    /// Bytes of the response body handed to the connection, see [`start`].
+    sent: Arc<AtomicU64>,
+    send_queue: SendQueue,
 }
 
-impl Actor for WebsocketService {
-    type Context = ws::WebsocketContext<Self>;
+type Received = Result<DataFrame, RecvError>;
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        info!("Websocket {} connected", self.wsurl);
-        let rx = self.wscontext.lock().unwrap().tx.subscribe();
-        let stream = tokio_stream::wrappers::BroadcastStream::<DataFrame>::new(rx);
-        ctx.add_stream(stream);
-
-        let mut wscontext = self.wscontext.lock().unwrap();
-        wscontext.count += 1;
-
-        let should_start = wscontext.count == 1
-            || wscontext
-                .task
-                .as_ref()
-                .map(|task| task.is_finished())
-                .unwrap_or(true);
-
-        if should_start {
-            let (stop_tx, stop_rx) = oneshot::channel();
-            let url = wscontext.url.clone();
-            let transport = wscontext.transport.clone();
-            let tx = wscontext.tx.clone();
-            let wsurl = self.wsurl.clone();
-
-            wscontext.stop_tx = Some(stop_tx);
-            wscontext.task = Some(tokio::spawn(async move {
-                info!("RTSP {} started", wsurl);
-                if let Err(e) = crate::rtspclient::run_until(url, transport, tx, stop_rx).await {
-                    error!("RTSP {} exited with error: {}", wsurl, e);
-                }
-                info!("RTSP {} stopped", wsurl);
-            }));
+fn frame_stream(rx: FrameReceiver) -> impl futures::Stream<Item = Received> {
+    futures::stream::unfold(rx, |mut rx| async move {
+        let frame = rx.recv().await;
+        if let Err(RecvError::Closed) = frame {
+            return None;
         }
+        Some((frame, rx))
+    })
+}
+
+/// Perform the websocket handshake and start the service, counting the bytes
+/// of the response body as the connection takes them: the messages written
+/// by the service and not yet counted wait in the websocket context.
+pub fn start(service: WebsocketService, req: &HttpRequest, stream: web::Payload) -> Result<HttpResponse, actix_web::Error> {
+    let sent = service.sent.clone();
+    let body = ws::WebsocketContext::create(service, stream).inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            sent.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+    });
+    Ok(ws::handshake(req)?.streaming(body))
+}
+
+impl WebsocketService {
+    pub fn new(wsurl: String, wscontext: Arc<Mutex<StreamsDef>>, app_context: AppContext, remote: String, format: bitstream::Format) -> Self {
+        let mut session = ClientSession::new(&app_context.clients, "websocket", remote, &wsurl);
+        session.bitstream = Bitstream::new(format);
+        Self {
+            wsurl,
+            wscontext,
+            app_context,
+            session,
+            subscription: None,
+            sent: Arc::default(),
+            send_queue: SendQueue::default(),
+        }
+    }
+
+    fn subscribe(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let substream = self.app_context.substream(&self.wscontext);
+        let (name, streamdef) = match substream {
+            Some(substream) if self.session.on_substream(true) => substream,
+            _ => (self.wsurl.clone(), self.wscontext.clone()),
+        };
+        let rx = streamdef.lock().unwrap().add_viewer(&name);
+        self.session.set_stream(&name);
+        let handle = ctx.add_stream(frame_stream(rx));
+        self.subscription = Some((handle, streamdef));
     }
 
-    fn stopped(&mut self, _ctx: &mut Self::Context) {
-        info!("Websocket {} disconnected", self.wsurl);
-        let mut wscontext = self.wscontext.lock().unwrap();
+    fn unsubscribe(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Some((handle, streamdef)) = self.subscription.take() {
+            ctx.cancel_future(handle);
+            streamdef.lock().unwrap().remove_viewer();
+        }
+    }
 
-        if wscontext.count > 0 {
-            wscontext.count -= 1;
+    /// Adapt the quality to the bytes `written` to the websocket context and
+    /// not yet taken by the connection, moving the client to the substream or
+    /// back when needed.
+    fn adapt(&mut self, written: u64, ctx: &mut ws::WebsocketContext<Self>) {
+        let pending = self.send_queue.update(written, self.sent.load(Ordering::Relaxed));
+        let has_substream = self.app_context.substream(&self.wscontext).is_some();
+        let on_substream = self.session.on_substream(has_substream);
+        let Some(quality) = self.session.adapt(pending, has_substream) else { return };
+
+        info!("Websocket {} quality {}", self.wsurl, quality.as_str());
+        if self.session.on_substream(has_substream) != on_substream {
+            self.unsubscribe(ctx);
+            self.subscribe(ctx);
         }
+        ctx.text(self.session.quality_event().to_string());
+    }
 
-        if wscontext.count == 0 {
-            if let Some(stop_tx) = wscontext.stop_tx.take() {
-                let _ = stop_tx.send(());
+    fn handle_command(&mut self, cmd: &Command, ctx: &mut ws::WebsocketContext<Self>) -> Result<serde_json::Value, anyhow::Error> {
+        match cmd {
+            Command::Pause => self.session.pause(),
+            Command::Resume => self.session.resume(),
+            Command::KeyframeOnly(enabled) => self.session.set_keyframe_only(*enabled),
+            Command::Stats => return Ok(self.session.stats()),
+            Command::Switch(wsurl) => {
+                let wscontext = self
+                    .app_context
+                    .stream(wsurl)
+                    .ok_or_else(|| anyhow!("unknown stream '{}'", wsurl))?;
+                if *wsurl != self.wsurl {
+                    info!("Websocket {} switched to {}", self.wsurl, wsurl);
+                    self.unsubscribe(ctx);
+                    self.wsurl = wsurl.clone();
+                    self.wscontext = wscontext;
+                    self.session.reset_quality();
+                    self.subscribe(ctx);
+                    self.session.wait_keyframe = true;
+                }
+                return Ok(json!({ "stream": self.wsurl }));
+            }
+            Command::Ping(t) => return Ok(clientsession::pong(*t)),
+            Command::Latency { ms, .. } => self.session.stats.lock().unwrap().client_latency.record(*ms),
+            Command::Subscribe(_) | Command::Unsubscribe(_) => {
+                return Err(anyhow!("'{}' is only supported on WebTransport sessions", cmd.name()));
             }
-            wscontext.task.take();
         }
+        Ok(serde_json::Value::Null)
+    }
+}
+
+impl Actor for WebsocketService {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("Websocket {} connected", self.wsurl);
+        self.subscribe(ctx);
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        info!("Websocket {} disconnected ({} frames dropped)", self.wsurl, self.session.frames_dropped());
+        self.unsubscribe(ctx);
     }    
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketService {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        if let Ok(ws::Message::Ping(msg)) = msg {
-            ctx.pong(&msg);
+        match msg {
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Text(text)) => {
+                let reply = match Command::parse(&text) {
+                    Ok(cmd) => {
+                        let result = self.handle_command(&cmd, ctx);
+                        clientsession::reply(cmd.name(), result)
+                    }
+                    Err(e) => {
+                        warn!("Websocket {} invalid command: {}", self.wsurl, e);
+                        clientsession::reply("unknown", Err(e))
+                    }
+                };
+                ctx.text(reply.to_string());
+            }
+            _ => (),
         }
     }
 }
 
-impl StreamHandler<Result<DataFrame, BroadcastStreamRecvError>> for WebsocketService {
-    fn handle(&mut self, msg: Result<DataFrame, BroadcastStreamRecvError>, ctx: &mut Self::Context) {
-        if let Ok(msg) = msg {
-            ctx.text(serde_json::to_string(&msg.metadata).unwrap());
-            ctx.binary(msg.data);
+impl StreamHandler<Received> for WebsocketService {
+    fn handle(&mut self, msg: Received, ctx: &mut Self::Context) {
+        match msg {
+            Ok(msg) => {
+                let mut written = 0;
+                if self.session.accept(&msg) {
+                    for msg in self.session.bitstream.convert(msg) {
+                        // the shared metadata and the times of this client are
+                        // the fragments of one text message
+                        let [json, times] = msg.json_parts();
+                        written += (json.len() + times.len() + msg.data.len()) as u64;
+                        ctx.write_raw(ws::Message::Continuation(Item::FirstText(json)));
+                        ctx.write_raw(ws::Message::Continuation(Item::Last(times)));
+                        ctx.binary(msg.data.clone());
+                        self.session.sent(&msg);
+                    }
+                }
+                self.adapt(written, ctx);
+            }
+            Err(RecvError::Lagged(n)) => {
+                warn!("Websocket {} lagged {} frames, waiting for next keyframe", self.wsurl, n);
+                ctx.text(self.session.lagged(n).to_string());
+            }
+            Err(RecvError::Closed) => (),
         }
     }
 }
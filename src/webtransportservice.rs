@@ -7,13 +7,22 @@
 **
 ** -------------------------------------------------------------------------*/
 
-use anyhow::Error;
-use log::{error, info, warn};
-use tokio::sync::broadcast;
-use wtransport::{Endpoint, Identity, ServerConfig};
+use anyhow::{anyhow, Error};
+use bytes::Bytes;
+use log::{debug, info, warn};
+use serde_json::json;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use wtransport::{Connection, Endpoint, Identity, ServerConfig, VarInt};
 
 use crate::appcontext::AppContext;
-use crate::streamdef::DataFrame;
+use crate::bitstream::{self, Bitstream};
+use crate::clientsession::{self, ClientSession, ClientStats, Command, SendQueue};
+use crate::streamdef::{DataFrame, StreamsDef, Viewer};
 
 /// Generate a 14-day self-signed identity for the QUIC endpoint and return its
 /// SHA-256 fingerprint in dotted-hex format ("aa:bb:cc:…").
@@ -41,34 +50,409 @@ pub fn build_identity(extra_sans: &[String]) -> Result<(Identity, Vec<u8>), Erro
     Ok((identity, fingerprint))
 }
 
+/// Path of the multiplexed session: instead of selecting one stream by path,
+/// the client opens a bidirectional control stream and sends newline-delimited
+/// JSON commands on it:
+///   {"cmd": "subscribe", "stream": "name"}
+///   {"cmd": "unsubscribe", "stream": "name"}
+/// Each command is answered by one JSON line on the same stream. For every
+/// subscription the server opens a unidirectional stream whose first frame is
+/// a header `{"type": "subscribed", "stream": "/name"}` with an empty payload,
+/// followed by the frames of that stream. The `mode` query parameter does not
+/// apply to multiplexed sessions. The root path is used as it cannot name a
+/// stream: stream names are never empty.
+const MUX_PATH: &str = "/";
+
+/// Longest command line accepted on the control stream of a multiplexed session.
+const MAX_LINE: usize = 4096;
+
+/// Bytes written on a connection and not yet sent on the wire, from the UDP
+/// bytes the QUIC connection reports as sent. Shared by every writer of the
+/// connection, as the streams and datagrams of a session leave through the
+/// same congestion window.
+#[derive(Clone)]
+struct Backlog {
+    connection: Connection,
+    queue: Arc<Mutex<SendQueue>>,
+}
+
+impl Backlog {
+    fn new(connection: &Connection) -> Self {
+        Self { connection: connection.clone(), queue: Arc::default() }
+    }
+
+    fn wrote(&self, bytes: usize) -> u64 {
+        let sent = self.connection.quic_connection().stats().udp_tx.bytes;
+        self.queue.lock().unwrap().update(bytes as u64, sent)
+    }
+
+    fn pending(&self) -> u64 {
+        self.wrote(0)
+    }
+}
+
+/// Write one frame using the wire format described in [`pump_frames`].
+async fn write_frame(
+    stream: &mut wtransport::stream::SendStream,
+    backlog: &Backlog,
+    json_parts: &[&[u8]],
+    data: &[u8],
+) -> Result<(), Error> {
+    let json_len: usize = json_parts.iter().map(|part| part.len()).sum();
+    let data_len = data.len() as u32;
+
+    stream.write_all(&(json_len as u32).to_le_bytes()).await?;
+    for part in json_parts {
+        stream.write_all(part).await?;
+    }
+    stream.write_all(&data_len.to_le_bytes()).await?;
+    stream.write_all(data).await?;
+    backlog.wrote(8 + json_len + data.len());
+    Ok(())
+}
+
+/// What a WebTransport client gets next from its [`Feed`].
+enum Delivery {
+    Frame(DataFrame),
+    /// The client lagged behind the broadcast channel.
+    Discontinuity(serde_json::Value),
+    /// The adaptive quality of the client changed.
+    Quality(serde_json::Value),
+    Closed,
+}
+
+/// Frames of a stream delivered to one WebTransport client, following its
+/// adaptive quality by moving between the stream and its substream. The
+/// client counts as a viewer of the delivered stream until the feed is dropped.
+struct Feed {
+    app_context: AppContext,
+    path: String,
+    stream_def: Arc<Mutex<StreamsDef>>,
+    /// Viewer of the stream or substream being delivered.
+    current: Viewer,
+    pending: Option<DataFrame>,
+    backlog: Backlog,
+}
+
+impl Feed {
+    fn new(app_context: &AppContext, path: &str, stream_def: Arc<Mutex<StreamsDef>>, backlog: Backlog) -> Self {
+        Self {
+            app_context: app_context.clone(),
+            path: path.to_string(),
+            current: Viewer::new(stream_def.clone(), path),
+            stream_def,
+            pending: None,
+            backlog,
+        }
+    }
+
+    /// Move to the substream or back to the stream.
+    fn follow(&mut self, on_substream: bool, session: &ClientSession) {
+        let (name, next) = match self.app_context.substream(&self.stream_def) {
+            Some(substream) if on_substream => substream,
+            _ => (self.path.clone(), self.stream_def.clone()),
+        };
+        // the next viewer is added before the current one leaves, which would
+        // stop the camera when it is the last
+        self.current = Viewer::new(next, &name);
+        session.set_stream(&name);
+    }
+
+    async fn next(&mut self, session: &mut ClientSession) -> Delivery {
+        if let Some(frame) = self.pending.take() {
+            if session.accept(&frame) {
+                return Delivery::Frame(frame);
+            }
+        }
+        loop {
+            let frame = match self.current.rx.recv().await {
+                Ok(frame) => frame,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("WebTransport receiver {} lagged {n} frames, waiting for next keyframe", self.path);
+                    return Delivery::Discontinuity(session.lagged(n));
+                }
+                Err(broadcast::error::RecvError::Closed) => return Delivery::Closed,
+            };
+
+            let has_substream = self.app_context.substream(&self.stream_def).is_some();
+            let on_substream = session.on_substream(has_substream);
+            if let Some(quality) = session.adapt(self.backlog.pending(), has_substream) {
+                info!("WebTransport {} quality {}", self.path, quality.as_str());
+                if session.on_substream(has_substream) != on_substream {
+                    self.follow(!on_substream, session);
+                } else {
+                    self.pending = Some(frame);
+                }
+                return Delivery::Quality(session.quality_event());
+            }
+
+            if session.accept(&frame) {
+                return Delivery::Frame(frame);
+            }
+        }
+    }
+}
+
 /// Wire-frame format sent over the unidirectional stream:
 ///   [4 bytes LE: json_len][json_len bytes: UTF-8 JSON]
 ///   [4 bytes LE: data_len][data_len bytes: binary]
 ///   … repeated for every frame
-async fn pump_frames(
+///
+/// A client lagging behind the broadcast channel gets a discontinuity frame
+/// with an empty payload, then delta frames are dropped until the next keyframe.
+async fn pump_frames<Stop>(
     mut stream: wtransport::stream::SendStream,
-    mut rx: broadcast::Receiver<DataFrame>,
-    connection: &wtransport::Connection,
+    feed: &mut Feed,
+    session: &mut ClientSession,
+    stop: Stop,
+) -> Result<(), Error>
+where
+    Stop: Future,
+{
+    tokio::pin!(stop);
+    loop {
+        tokio::select! {
+            biased;
+            delivery = feed.next(session) => {
+                match delivery {
+                    Delivery::Frame(frame) => {
+                        for frame in session.bitstream.convert(frame) {
+                            let [json, times] = frame.json_parts();
+                            write_frame(&mut stream, &feed.backlog, &[&json, &times], &frame.data).await?;
+                            session.sent(&frame);
+                        }
+                    }
+                    Delivery::Discontinuity(event) | Delivery::Quality(event) => {
+                        write_frame(&mut stream, &feed.backlog, &[&serde_json::to_vec(&event)?], &[]).await?;
+                    }
+                    Delivery::Closed => break,
+                }
+            }
+            _ = &mut stop => break,
+        }
+    }
+    Ok(())
+}
+
+/// How frames of a single-stream session are carried, selected with the
+/// `mode` query parameter (`/name?mode=gop`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DeliveryMode {
+    /// Every frame on one ordered unidirectional stream (default).
+    Stream,
+    /// One unidirectional stream per GOP, see [`pump_gops`].
+    Gop,
+    /// Unreliable datagrams for frames that fit, see [`pump_datagrams`].
+    Datagram,
+}
+
+impl std::str::FromStr for DeliveryMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stream" => Ok(DeliveryMode::Stream),
+            "gop" => Ok(DeliveryMode::Gop),
+            "datagram" => Ok(DeliveryMode::Datagram),
+            _ => Err(anyhow!("unknown delivery mode '{}'", s)),
+        }
+    }
+}
+
+/// Frames queued on a GOP stream before it is considered hopelessly behind.
+const GOP_QUEUE: usize = 256;
+
+/// Writer of one GOP on its own unidirectional stream.
+struct GopStream {
+    tx: mpsc::Sender<DataFrame>,
+    abandon_tx: watch::Sender<bool>,
+}
+
+impl GopStream {
+    async fn open(connection: &Connection, backlog: Backlog, stats: Arc<Mutex<ClientStats>>) -> Result<Self, Error> {
+        let mut stream = connection.open_uni().await?.await?;
+        let (tx, mut rx) = mpsc::channel::<DataFrame>(GOP_QUEUE);
+        let (abandon_tx, mut abandon_rx) = watch::channel(false);
+
+        tokio::spawn(async move {
+            let result = async {
+                loop {
+                    let frame = tokio::select! {
+                        biased;
+                        Ok(()) = abandon_rx.changed() => break,
+                        frame = rx.recv() => frame,
+                    };
+                    let Some(frame) = frame else {
+                        stream.finish().await?;
+                        return Ok(());
+                    };
+                    let [json, times] = frame.json_parts();
+                    let json_parts: [&[u8]; 2] = [&json, &times];
+                    tokio::select! {
+                        biased;
+                        Ok(()) = abandon_rx.changed() => break,
+                        result = write_frame(&mut stream, &backlog, &json_parts, &frame.data) => result?,
+                    }
+                    // the discontinuity and quality events are not frames of the stream
+                    if frame.metadata.get("media").is_some() {
+                        stats.lock().unwrap().sent(&frame);
+                    }
+                }
+                stream.reset(VarInt::from_u32(0))?;
+                Ok::<(), Error>(())
+            }
+            .await;
+            if let Err(e) = result {
+                debug!("WebTransport GOP stream closed: {e}");
+            }
+        });
+
+        Ok(Self { tx, abandon_tx })
+    }
+
+    /// Queue a frame, returning false when the stream is too far behind.
+    fn push(&self, frame: DataFrame) -> bool {
+        self.tx.try_send(frame).is_ok()
+    }
+
+    /// Finish the stream once its queued frames are written, or reset it
+    /// right away when frames are still pending.
+    fn close(self) {
+        if self.tx.capacity() < self.tx.max_capacity() {
+            let _ = self.abandon_tx.send(true);
+        }
+    }
+
+    fn abandon(self) {
+        let _ = self.abandon_tx.send(true);
+    }
+}
+
+/// Per-GOP delivery: every keyframe opens a new unidirectional stream carrying
+/// that GOP with the framing of [`pump_frames`], so a lost packet only stalls
+/// the GOP it belongs to. When a keyframe arrives while the previous GOP still
+/// has frames queued, that stream is reset instead of finished. After a lag,
+/// the current GOP is abandoned and the next one starts with a discontinuity
+/// frame.
+async fn pump_gops(
+    connection: &Connection,
+    feed: &mut Feed,
+    session: &mut ClientSession,
 ) -> Result<(), Error> {
+    let mut current: Option<GopStream> = None;
+    let mut events: Vec<DataFrame> = vec![];
+    session.wait_keyframe = true;
     loop {
         tokio::select! {
             biased;
-            result = rx.recv() => {
-                match result {
-                    Ok(frame) => {
-                        let json_bytes = serde_json::to_vec(&frame.metadata)?;
-                        let json_len = json_bytes.len() as u32;
-                        let data_len = frame.data.len() as u32;
-
-                        stream.write_all(&json_len.to_le_bytes()).await?;
-                        stream.write_all(&json_bytes).await?;
-                        stream.write_all(&data_len.to_le_bytes()).await?;
-                        stream.write_all(&frame.data).await?;
+            delivery = feed.next(session) => {
+                match delivery {
+                    Delivery::Frame(frame) => {
+                        for frame in session.bitstream.convert(frame) {
+                            if frame.is_config() {
+                                // the configuration applies from the next keyframe,
+                                // which starts the next GOP stream
+                                events.push(frame);
+                                continue;
+                            }
+                            if frame.is_keyframe() {
+                                if let Some(gop) = current.take() {
+                                    gop.close();
+                                }
+                                let gop = GopStream::open(connection, feed.backlog.clone(), session.stats.clone()).await?;
+                                for event in events.drain(..) {
+                                    gop.push(event);
+                                }
+                                current = Some(gop);
+                            }
+                            if let Some(gop) = current.take() {
+                                if gop.push(frame) {
+                                    current = Some(gop);
+                                } else {
+                                    warn!("WebTransport GOP stream is behind, abandoning it");
+                                    gop.abandon();
+                                    session.wait_keyframe = true;
+                                }
+                            }
+                        }
+                    }
+                    Delivery::Discontinuity(event) => {
+                        if let Some(gop) = current.take() {
+                            gop.abandon();
+                        }
+                        events.push(DataFrame::new(event, Bytes::new()));
+                    }
+                    Delivery::Quality(event) => {
+                        events.push(DataFrame::new(event, Bytes::new()));
+                    }
+                    Delivery::Closed => break,
+                }
+            }
+            _ = connection.closed() => break,
+        }
+    }
+    if let Some(gop) = current.take() {
+        gop.close();
+    }
+    Ok(())
+}
+
+/// Encode a frame for datagram delivery, see [`pump_datagrams`].
+fn encode_datagram(seq: u32, json_parts: &[&[u8]], data: &[u8]) -> Vec<u8> {
+    let json_len: usize = json_parts.iter().map(|part| part.len()).sum();
+    let mut payload = Vec::with_capacity(12 + json_len + data.len());
+    payload.extend_from_slice(&seq.to_le_bytes());
+    payload.extend_from_slice(&(json_len as u32).to_le_bytes());
+    for part in json_parts {
+        payload.extend_from_slice(part);
+    }
+    payload.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    payload.extend_from_slice(data);
+    payload
+}
+
+/// Datagram delivery: each frame is prefixed with a sequence number so the
+/// client can detect losses and reordering:
+///   [4 bytes LE: seq][4 bytes LE: json_len][json][4 bytes LE: data_len][data]
+/// A frame that fits in `max_datagram_size` is sent as one unreliable
+/// datagram; larger frames are sent with the same encoding on a single
+/// unidirectional stream. Discontinuities after a lag are sent on the stream.
+async fn pump_datagrams(
+    connection: &Connection,
+    feed: &mut Feed,
+    session: &mut ClientSession,
+) -> Result<(), Error> {
+    let mut stream = connection.open_uni().await?.await?;
+    let mut seq: u32 = 0;
+    loop {
+        tokio::select! {
+            biased;
+            delivery = feed.next(session) => {
+                match delivery {
+                    Delivery::Frame(frame) => {
+                        for frame in session.bitstream.convert(frame) {
+                            let [json, times] = frame.json_parts();
+                            let payload = encode_datagram(seq, &[&json, &times], &frame.data);
+                            seq = seq.wrapping_add(1);
+
+                            let fits = connection
+                                .max_datagram_size()
+                                .map(|max| payload.len() <= max)
+                                .unwrap_or(false);
+                            if !fits || connection.send_datagram(&payload).is_err() {
+                                stream.write_all(&payload).await?;
+                            }
+                            feed.backlog.wrote(payload.len());
+                            session.sent(&frame);
+                        }
                     }
-                    Err(broadcast::error::RecvError::Lagged(n)) => {
-                        warn!("WebTransport receiver lagged {n} frames, skipping");
+                    Delivery::Discontinuity(event) | Delivery::Quality(event) => {
+                        let event = encode_datagram(seq, &[&serde_json::to_vec(&event)?], &[]);
+                        stream.write_all(&event).await?;
+                        feed.backlog.wrote(event.len());
+                        seq = seq.wrapping_add(1);
                     }
-                    Err(broadcast::error::RecvError::Closed) => break,
+                    Delivery::Closed => break,
                 }
             }
             _ = connection.closed() => break,
@@ -77,6 +461,207 @@ async fn pump_frames(
     Ok(())
 }
 
+/// A stream subscribed on a multiplexed session.
+struct Subscription {
+    stats: Arc<Mutex<ClientStats>>,
+    stop_tx: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Subscription {
+    async fn start(
+        app_context: &AppContext,
+        connection: &Connection,
+        path: &str,
+        stream_def: Arc<Mutex<StreamsDef>>,
+        format: bitstream::Format,
+        backlog: &Backlog,
+    ) -> Result<Self, Error> {
+        let mut send_stream = connection.open_uni().await?.await?;
+        let header = serde_json::to_vec(&json!({ "type": "subscribed", "stream": path }))?;
+        write_frame(&mut send_stream, backlog, &[&header], &[]).await?;
+
+        let mut feed = Feed::new(app_context, path, stream_def, backlog.clone());
+        let remote = connection.remote_address().to_string();
+        let mut session = ClientSession::new(&app_context.clients, "webtransport", remote, path);
+        session.bitstream = Bitstream::new(format);
+        let stats = session.stats.clone();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let connection = connection.clone();
+        let path = path.to_string();
+        let task = tokio::spawn(async move {
+            let stop = async {
+                tokio::select! {
+                    _ = connection.closed() => {},
+                    _ = stop_rx => {},
+                }
+            };
+            if let Err(e) = pump_frames(send_stream, &mut feed, &mut session, stop).await {
+                warn!("WebTransport subscription error on {path}: {e}");
+            }
+            info!("WebTransport mux subscription {path} ended ({} frames dropped)", session.frames_dropped());
+        });
+
+        Ok(Self { stats, stop_tx, task })
+    }
+
+    async fn stop(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.task.await;
+    }
+}
+
+/// Write one JSON line on a control stream.
+async fn write_line<W: AsyncWrite + Unpin>(stream: &mut W, value: &serde_json::Value) -> Result<(), Error> {
+    let mut bytes = serde_json::to_vec(value)?;
+    bytes.push(b'\n');
+    stream.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// Reject a session with invalid query parameters. The session API only
+/// answers 403, 404 and 429, so the session is accepted and closed at once
+/// with the 400 code and the error as reason.
+async fn bad_request(session_request: wtransport::endpoint::SessionRequest, reason: &str) {
+    if let Ok(connection) = session_request.accept().await {
+        connection.close(VarInt::from_u32(400), reason.as_bytes());
+    }
+}
+
+/// Read one newline-terminated line from the control stream, returning `None`
+/// once the peer has finished the stream.
+async fn read_line<R: AsyncRead + Unpin>(stream: &mut R, buf: &mut Vec<u8>) -> Result<Option<String>, Error> {
+    loop {
+        if let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=pos).collect();
+            return Ok(Some(String::from_utf8_lossy(&line).trim().to_string()));
+        }
+        if buf.len() > MAX_LINE {
+            return Err(anyhow!("control line longer than {} bytes", MAX_LINE));
+        }
+        let mut chunk = [0u8; 1024];
+        match stream.read(&mut chunk).await? {
+            0 => return Ok(None),
+            n => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+async fn handle_mux_command(
+    cmd: &Command,
+    app_context: &AppContext,
+    connection: &Connection,
+    subscriptions: &mut HashMap<String, Subscription>,
+    format: bitstream::Format,
+    backlog: &Backlog,
+) -> Result<serde_json::Value, Error> {
+    match cmd {
+        Command::Subscribe(path) => {
+            if subscriptions.contains_key(path) {
+                return Err(anyhow!("already subscribed to '{}'", path));
+            }
+            let stream_def = app_context
+                .stream(path)
+                .ok_or_else(|| anyhow!("unknown stream '{}'", path))?;
+            let subscription = Subscription::start(app_context, connection, path, stream_def, format, backlog).await?;
+            subscriptions.insert(path.clone(), subscription);
+            info!("WebTransport mux subscribed to {path}");
+            Ok(json!({ "stream": path }))
+        }
+        Command::Unsubscribe(path) => {
+            let subscription = subscriptions
+                .remove(path)
+                .ok_or_else(|| anyhow!("not subscribed to '{}'", path))?;
+            subscription.stop().await;
+            info!("WebTransport mux unsubscribed from {path}");
+            Ok(json!({ "stream": path }))
+        }
+        Command::Ping(t) => Ok(clientsession::pong(*t)),
+        Command::Latency { ms, stream } => {
+            let path = stream.as_ref().ok_or_else(|| anyhow!("missing field 'stream'"))?;
+            let subscription = subscriptions
+                .get(path)
+                .ok_or_else(|| anyhow!("not subscribed to '{}'", path))?;
+            subscription.stats.lock().unwrap().client_latency.record(*ms);
+            Ok(serde_json::Value::Null)
+        }
+        Command::Stats => {
+            let mut stats = json!({});
+            for (path, subscription) in subscriptions.iter() {
+                stats[path] = subscription.stats.lock().unwrap().to_json();
+            }
+            Ok(stats)
+        }
+        _ => Err(anyhow!("'{}' is not supported on multiplexed sessions", cmd.name())),
+    }
+}
+
+/// Serve a multiplexed session until the client closes it.
+async fn serve_mux(app_context: &AppContext, connection: &Connection, format: bitstream::Format) -> Result<(), Error> {
+    let (mut ctrl_send, mut ctrl_recv) = connection.accept_bi().await?;
+    let mut subscriptions = HashMap::new();
+    let mut buf = Vec::new();
+    let backlog = Backlog::new(connection);
+
+    let result = async {
+        loop {
+            let line = tokio::select! {
+                line = read_line(&mut ctrl_recv, &mut buf) => line?,
+                _ = connection.closed() => None,
+            };
+            let Some(line) = line else { break };
+            if line.is_empty() {
+                continue;
+            }
+
+            let reply = match Command::parse(&line) {
+                Ok(cmd) => {
+                    let result = handle_mux_command(&cmd, app_context, connection, &mut subscriptions, format, &backlog).await;
+                    clientsession::reply(cmd.name(), result)
+                }
+                Err(e) => clientsession::reply("unknown", Err(e)),
+            };
+            write_line(&mut ctrl_send, &reply).await?;
+        }
+        Ok::<(), Error>(())
+    }
+    .await;
+
+    for (_, subscription) in subscriptions.drain() {
+        subscription.stop().await;
+    }
+    result
+}
+
+/// Serve the optional control stream of a single-stream session, which
+/// accepts `ping`, `latency` and `stats` commands.
+async fn serve_control(connection: Connection, stats: Arc<Mutex<ClientStats>>) -> Result<(), Error> {
+    let (mut ctrl_send, mut ctrl_recv) = connection.accept_bi().await?;
+    let mut buf = Vec::new();
+    while let Some(line) = read_line(&mut ctrl_recv, &mut buf).await? {
+        if line.is_empty() {
+            continue;
+        }
+        let reply = match Command::parse(&line) {
+            Ok(cmd) => {
+                let result = match &cmd {
+                    Command::Ping(t) => Ok(clientsession::pong(*t)),
+                    Command::Latency { ms, .. } => {
+                        stats.lock().unwrap().client_latency.record(*ms);
+                        Ok(serde_json::Value::Null)
+                    }
+                    Command::Stats => Ok(stats.lock().unwrap().to_json()),
+                    _ => Err(anyhow!("'{}' is not supported on WebTransport sessions", cmd.name())),
+                };
+                clientsession::reply(cmd.name(), result)
+            }
+            Err(e) => clientsession::reply("unknown", Err(e)),
+        };
+        write_line(&mut ctrl_send, &reply).await?;
+    }
+    Ok(())
+}
+
 pub async fn run(app_context: AppContext, identity: Identity, port: u16) -> Result<(), Error> {
     let config = ServerConfig::builder()
         .with_bind_default(port)
@@ -99,18 +684,61 @@ pub async fn run(app_context: AppContext, identity: Identity, port: u16) -> Resu
                 }
             };
 
-            let path = percent_encoding::percent_decode_str(session_request.path())
+            let (path, query) = session_request
+                .path()
+                .split_once('?')
+                .unwrap_or((session_request.path(), ""));
+            let path = percent_encoding::percent_decode_str(path)
                 .decode_utf8_lossy()
                 .into_owned();
+            let mode = url::form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "mode")
+                .map(|(_, value)| value.parse::<DeliveryMode>())
+                .transpose();
+            let format = url::form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "format")
+                .map(|(_, value)| value.parse::<bitstream::Format>())
+                .transpose();
+            let format = match format {
+                Ok(format) => format.unwrap_or_default(),
+                Err(e) => {
+                    warn!("Rejecting WebTransport session on {path}: {e}");
+                    bad_request(session_request, &e.to_string()).await;
+                    return;
+                }
+            };
             let remote = session_request.remote_address();
             info!("WebTransport session request from {remote} for path {path}");
 
-            let Some(stream_def) = app_context.streams.get(&path) else {
+            if path == MUX_PATH {
+                let connection = match session_request.accept().await {
+                    Ok(c) => c,
+                    Err(e) => {
+                        warn!("Failed to accept WebTransport mux session: {e}");
+                        return;
+                    }
+                };
+                info!("WebTransport mux session accepted from {remote}");
+                if let Err(e) = serve_mux(&app_context, &connection, format).await {
+                    warn!("WebTransport mux session error from {remote}: {e}");
+                }
+                return;
+            }
+
+            let Some(stream_def) = app_context.stream(&path) else {
                 warn!("Unknown WebTransport path: {path}");
                 session_request.not_found().await;
                 return;
             };
-            let stream_def = stream_def.clone();
+
+            let mode = match mode {
+                Ok(mode) => mode.unwrap_or(DeliveryMode::Stream),
+                Err(e) => {
+                    warn!("Rejecting WebTransport session on {path}: {e}");
+                    bad_request(session_request, &e.to_string()).await;
+                    return;
+                }
+            };
 
             let connection = match session_request.accept().await {
                 Ok(c) => c,
@@ -119,56 +747,181 @@ pub async fn run(app_context: AppContext, identity: Identity, port: u16) -> Resu
                     return;
                 }
             };
-            info!("WebTransport session accepted for {path} from {remote}");
-
-            let rx = {
-                let mut ctx = stream_def.lock().unwrap();
-                let rx = ctx.tx.subscribe();
-                ctx.count += 1;
-
-                let should_start = ctx.count == 1
-                    || ctx.task.as_ref().map(|t| t.is_finished()).unwrap_or(true);
-                if should_start {
-                    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel();
-                    let url = ctx.url.clone();
-                    let transport = ctx.transport.clone();
-                    let tx = ctx.tx.clone();
-                    let path_log = path.clone();
-                    ctx.stop_tx = Some(stop_tx);
-                    ctx.task = Some(tokio::spawn(async move {
-                        info!("RTSP {path_log} started (WebTransport)");
-                        if let Err(e) =
-                            crate::rtspclient::run_until(url, transport, tx, stop_rx).await
-                        {
-                            error!("RTSP {path_log} exited with error: {e}");
-                        }
-                        info!("RTSP {path_log} stopped (WebTransport)");
-                    }));
+            info!("WebTransport session accepted for {path} from {remote} ({mode:?}, {})", format.as_str());
+
+            let mut feed = Feed::new(&app_context, &path, stream_def, Backlog::new(&connection));
+
+            let mut session = ClientSession::new(&app_context.clients, "webtransport", remote.to_string(), &path);
+            session.bitstream = Bitstream::new(format);
+            let control = tokio::spawn(serve_control(connection.clone(), session.stats.clone()));
+            let result = match mode {
+                DeliveryMode::Stream => async {
+                    let opening = connection.open_uni().await?;
+                    let send_stream = opening.await?;
+                    pump_frames(send_stream, &mut feed, &mut session, connection.closed()).await
                 }
-                rx
+                .await,
+                DeliveryMode::Gop => pump_gops(&connection, &mut feed, &mut session).await,
+                DeliveryMode::Datagram => pump_datagrams(&connection, &mut feed, &mut session).await,
             };
 
-            let result = async {
-                let opening = connection.open_uni().await?;
-                let send_stream = opening.await?;
-                pump_frames(send_stream, rx, &connection).await
-            }
-            .await;
-
             if let Err(e) = result {
                 warn!("WebTransport session error on {path}: {e}");
             }
-
-            let mut ctx = stream_def.lock().unwrap();
-            if ctx.count > 0 {
-                ctx.count -= 1;
-            }
-            if ctx.count == 0 {
-                if let Some(stop_tx) = ctx.stop_tx.take() {
-                    let _ = stop_tx.send(());
-                }
-                ctx.task.take();
-            }
+            control.abort();
+            info!("WebTransport session {path} from {remote} ended ({} frames dropped)", session.frames_dropped());
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn control_lines_are_split_on_newlines() {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let mut buf = Vec::new();
+        // two commands in one write, the second one ending in the next write
+        client.write_all(b"{\"cmd\": \"ping\", \"t\": 1}\r\n\n{\"cmd\": \"sub").await.unwrap();
+        assert_eq!(read_line(&mut server, &mut buf).await.unwrap().unwrap(), r#"{"cmd": "ping", "t": 1}"#);
+        assert_eq!(read_line(&mut server, &mut buf).await.unwrap().unwrap(), "");
+        client.write_all(b"scribe\", \"stream\": \"Van\"}\n").await.unwrap();
+        let line = read_line(&mut server, &mut buf).await.unwrap().unwrap();
+        assert!(matches!(Command::parse(&line), Ok(Command::Subscribe(s)) if s == "/Van"));
+
+        write_line(&mut server, &clientsession::reply("subscribe", Ok(json!({ "stream": "/Van" })))).await.unwrap();
+        let mut reply = vec![0u8; 256];
+        let n = client.read(&mut reply).await.unwrap();
+        assert!(reply[..n].ends_with(b"}\n"));
+        let reply: serde_json::Value = serde_json::from_slice(&reply[..n]).unwrap();
+        assert_eq!(reply["result"]["stream"], "/Van");
+
+        // a line cut by the end of the stream is dropped
+        client.write_all(b"{\"cmd\": \"st").await.unwrap();
+        drop(client);
+        assert!(read_line(&mut server, &mut buf).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn control_lines_are_capped() {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let mut buf = Vec::new();
+        let longest = format!("{}\n", "x".repeat(MAX_LINE));
+        client.write_all(longest.as_bytes()).await.unwrap();
+        assert_eq!(read_line(&mut server, &mut buf).await.unwrap().unwrap().len(), MAX_LINE);
+
+        client.write_all("x".repeat(MAX_LINE + 1).as_bytes()).await.unwrap();
+        let error = read_line(&mut server, &mut buf).await.unwrap_err();
+        assert_eq!(error.to_string(), format!("control line longer than {MAX_LINE} bytes"));
+    }
+
+    #[test]
+    fn datagrams_are_prefixed_with_their_sequence_number() {
+        let payload = encode_datagram(0x0102_0304, &[b"{\"type\":\"delta\"", b",\"sent\":1}"], &[0xAA, 0xBB]);
+        let json = b"{\"type\":\"delta\",\"sent\":1}";
+        assert_eq!(&payload[..4], &[4, 3, 2, 1]);
+        assert_eq!(&payload[4..8], &(json.len() as u32).to_le_bytes());
+        assert_eq!(&payload[8..8 + json.len()], json);
+        assert_eq!(&payload[8 + json.len()..12 + json.len()], &2u32.to_le_bytes());
+        assert_eq!(&payload[12 + json.len()..], &[0xAA, 0xBB]);
+
+        // the sequence number wraps, and an event has an empty payload
+        let payload = encode_datagram(u32::MAX.wrapping_add(1), &[b"{}"], &[]);
+        assert_eq!(payload, [0, 0, 0, 0, 2, 0, 0, 0, b'{', b'}', 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn gop_streams_are_reset_when_closed_with_frames_pending() {
+        let frame = DataFrame::new(json!({ "type": "delta", "media": "video" }), vec![0; 10]);
+
+        let (tx, mut rx) = mpsc::channel(GOP_QUEUE);
+        let (abandon_tx, abandon_rx) = watch::channel(false);
+        GopStream { tx, abandon_tx }.close();
+        assert!(!*abandon_rx.borrow());
+        assert!(rx.recv().await.is_none());
+
+        let (tx, _rx) = mpsc::channel(GOP_QUEUE);
+        let (abandon_tx, abandon_rx) = watch::channel(false);
+        let gop = GopStream { tx, abandon_tx };
+        assert!(gop.push(frame.clone()));
+        gop.close();
+        assert!(*abandon_rx.borrow());
+
+        // a stream too far behind refuses the frames
+        let (tx, _rx) = mpsc::channel(1);
+        let (abandon_tx, _abandon_rx) = watch::channel(false);
+        let gop = GopStream { tx, abandon_tx };
+        assert!(gop.push(frame.clone()));
+        assert!(!gop.push(frame));
+    }
+
+    /// Read one frame of the wire format of [`pump_frames`].
+    async fn read_frame(stream: &mut wtransport::RecvStream) -> Result<(serde_json::Value, Vec<u8>), Error> {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).await?;
+        let mut json = vec![0u8; u32::from_le_bytes(len) as usize];
+        stream.read_exact(&mut json).await?;
+        stream.read_exact(&mut len).await?;
+        let mut data = vec![0u8; u32::from_le_bytes(len) as usize];
+        stream.read_exact(&mut data).await?;
+        Ok((serde_json::from_slice(&json)?, data))
+    }
+
+    #[tokio::test]
+    async fn every_gop_is_sent_on_its_own_stream() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        // a capacity small enough for the receiver to lag
+        let stream_def = Arc::new(Mutex::new(StreamsDef::published(None, 2)));
+        let app_context = AppContext::new(HashMap::from([("/cam".to_string(), stream_def.clone())]), None, None, None);
+        let port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (identity, fingerprint) = build_identity(&[]).unwrap();
+        let server = tokio::spawn(run(app_context, identity, port));
+
+        let config = wtransport::ClientConfig::builder()
+            .with_bind_default()
+            .with_server_certificate_hashes([wtransport::tls::Sha256Digest::new(fingerprint.try_into().unwrap())])
+            .build();
+        let connection = Endpoint::client(config)
+            .unwrap()
+            .connect(format!("https://127.0.0.1:{port}/cam?mode=gop"))
+            .await
+            .unwrap();
+        while stream_def.lock().unwrap().count == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let send = |kind: &str, n: u8| {
+            let tx = stream_def.lock().unwrap().tx.clone();
+            assert!(tx.send(DataFrame::new(json!({ "type": kind, "media": "video" }), vec![n])).is_ok());
+        };
+
+        send("keyframe", 1);
+        send("delta", 2);
+        let mut first = connection.accept_uni().await.unwrap();
+        let (metadata, data) = read_frame(&mut first).await.unwrap();
+        assert_eq!((metadata["type"].as_str(), data), (Some("keyframe"), vec![1]));
+        assert!(metadata["sent"].is_f64());
+        assert_eq!(read_frame(&mut first).await.unwrap().1, vec![2]);
+
+        // the next keyframe finishes the GOP written entirely
+        send("keyframe", 3);
+        let mut buf = [0u8; 1];
+        assert!(first.read(&mut buf).await.unwrap().is_none());
+        let mut second = connection.accept_uni().await.unwrap();
+        assert_eq!(read_frame(&mut second).await.unwrap().1, vec![3]);
+
+        // a lag resets the current GOP, the next one starting with the discontinuity
+        for n in 4..9 {
+            send("delta", n);
+        }
+        assert!(second.read(&mut buf).await.is_err());
+        send("keyframe", 9);
+        let mut third = connection.accept_uni().await.unwrap();
+        let (metadata, data) = read_frame(&mut third).await.unwrap();
+        assert_eq!(metadata["type"], "discontinuity");
+        assert!(data.is_empty());
+        assert_eq!(read_frame(&mut third).await.unwrap().1, vec![9]);
+
+        server.abort();
+    }
+}
@@ -0,0 +1,470 @@
+/* ---------------------------------------------------------------------------
+** This software is in the public domain, furnished "as is", without technical
+** support, and with no warranty, express or implied, as to its usefulness for
+** any purpose.
+**
+** SPDX-License-Identifier: Unlicense
+**
+** -------------------------------------------------------------------------*/
+
+use actix_web::{delete, http::header, post, web, HttpRequest, HttpResponse};
+use anyhow::{anyhow, Error};
+use log::{debug, info, warn};
+use retina::codec::VideoParameters;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use str0m::change::SdpOffer;
+use str0m::format::Codec;
+use str0m::media::{KeyframeRequestKind, MediaData, MediaKind, Mid};
+use str0m::net::{Protocol, Receive};
+use str0m::{Candidate, Event, IceConnectionState, Input, Output, Rtc, RtcConfig};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+use crate::appcontext::{AppContext, PublishError};
+use crate::bitstream::nal_units;
+use crate::dataframe::now_ms;
+use crate::rtsp::{RtpReceiver, SdpMedia};
+use crate::rtspclient;
+use crate::streamdef::{DataFrame, FrameSender, StreamsDef};
+
+/// Time given to the publisher to complete ICE and DTLS.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Least interval between two keyframe requests sent after packet loss.
+const KEYFRAME_INTERVAL: Duration = Duration::from_secs(1);
+
+const PAYLOAD_TYPE: u8 = 96;
+
+/// Registry of the WHIP sessions, ended by a DELETE of their URL.
+#[derive(Clone, Default)]
+pub struct Sessions(Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>);
+
+impl Sessions {
+    fn register(&self, id: &str) -> oneshot::Receiver<()> {
+        let (stop_tx, stop_rx) = oneshot::channel();
+        self.0.lock().unwrap().insert(id.to_string(), stop_tx);
+        stop_rx
+    }
+
+    fn stop(&self, id: &str) -> bool {
+        match self.0.lock().unwrap().remove(id) {
+            Some(stop_tx) => {
+                let _ = stop_tx.send(());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Frames of a WHIP publisher, broadcast on its stream until dropped.
+struct Feed {
+    id: String,
+    stream_def: Arc<Mutex<StreamsDef>>,
+    tx: FrameSender,
+    video: Option<Mid>,
+    /// H.264 access units are handed to retina as single NAL unit packets, so
+    /// that the parameters and frames are built as for the RTSP sources.
+    receiver: RtpReceiver,
+    video_params: Option<VideoParameters>,
+    audio_start: Option<u64>,
+    last_keyframe_request: Option<Instant>,
+}
+
+impl Feed {
+    fn new(id: &str, stream_def: Arc<Mutex<StreamsDef>>) -> Result<Self, Error> {
+        let tx = stream_def.lock().unwrap().tx.clone();
+        let media = SdpMedia {
+            media: "video".to_string(),
+            payload_type: PAYLOAD_TYPE,
+            encoding_name: "h264".to_string(),
+            clock_rate: 90000,
+            ..Default::default()
+        };
+        Ok(Self {
+            id: id.to_string(),
+            stream_def,
+            tx,
+            video: None,
+            receiver: RtpReceiver::new(0, &media)?,
+            video_params: None,
+            audio_start: None,
+            last_keyframe_request: None,
+        })
+    }
+
+    /// Broadcast the frames of `data`, returning whether a keyframe should be
+    /// requested after a loss.
+    fn push(&mut self, data: &MediaData) -> bool {
+        match data.params.spec().codec {
+            Codec::H264 => self.push_video(data),
+            Codec::Opus => {
+                self.push_audio(data);
+                false
+            }
+            codec => {
+                debug!("WHIP {} ignoring {:?} frame", self.id, codec);
+                false
+            }
+        }
+    }
+
+    fn push_video(&mut self, data: &MediaData) -> bool {
+        if let Some(info) = data.last_sender_info {
+            self.receiver.sender_report(system_ms(info.ntp_time), info.rtp_time.numer() as u32);
+        }
+        let nals = nal_units(&data.data);
+        for (i, nal) in nals.iter().enumerate() {
+            let loss = i == 0 && !data.contiguous;
+            let mark = i + 1 == nals.len();
+            if let Err(e) = self.receiver.push_payload(data.time.numer() as u32, mark, nal, loss, data.network_time) {
+                warn!("WHIP {} depacketization error: {}", self.id, e);
+            }
+        }
+        if let Err(e) = self.receiver.broadcast_video(&mut self.video_params, false, &self.tx) {
+            warn!("WHIP {} depacketization error: {}", self.id, e);
+        }
+        !data.contiguous || self.video_params.is_none()
+    }
+
+    /// Opus packets are forwarded as they are, one per frame.
+    fn push_audio(&mut self, data: &MediaData) {
+        let time = data.time.numer();
+        let start = *self.audio_start.get_or_insert_with(|| {
+            let metadata = json!({
+                "type": "config",
+                "media": "audio",
+                "codec": "opus",
+                "sample_rate": data.time.denom(),
+                "channels": data.params.spec().channels.unwrap_or(2),
+            });
+            rtspclient::send_frame(&self.tx, DataFrame::new(metadata, vec![]));
+            time
+        });
+        let mut metadata = json!({
+            "ts": time.saturating_sub(start) as f64 * 1000.0 / data.time.denom() as f64,
+            "received": now_ms() - data.network_time.elapsed().as_secs_f64() * 1000.0,
+            "media": "audio",
+            "codec": "opus",
+        });
+        if let Some(info) = data.last_sender_info {
+            let ticks = (time as u32).wrapping_sub(info.rtp_time.numer() as u32) as i32;
+            metadata["ntp"] = (system_ms(info.ntp_time) + f64::from(ticks) * 1000.0 / data.time.denom() as f64).into();
+        }
+        rtspclient::send_frame(&self.tx, DataFrame::new(metadata, data.data.to_vec()));
+    }
+
+    /// Ask the publisher for a keyframe, at most once per [`KEYFRAME_INTERVAL`].
+    fn request_keyframe(&mut self, rtc: &mut Rtc) {
+        let Some(mid) = self.video else { return };
+        if self.last_keyframe_request.is_some_and(|t| t.elapsed() < KEYFRAME_INTERVAL) {
+            return;
+        }
+        self.last_keyframe_request = Some(Instant::now());
+        if let Some(mut writer) = rtc.writer(mid) {
+            if let Err(e) = writer.request_keyframe(None, KeyframeRequestKind::Pli) {
+                debug!("WHIP {} keyframe request failed: {}", self.id, e);
+            }
+        }
+    }
+}
+
+impl Drop for Feed {
+    fn drop(&mut self) {
+        self.stream_def.lock().unwrap().release();
+    }
+}
+
+/// Milliseconds since the Unix epoch of the NTP time of a sender report.
+fn system_ms(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64() * 1000.0).unwrap_or_default()
+}
+
+/// Local address of the interface routing to `peer`, offered as ICE candidate.
+/// Being ICE-lite, the server gathers no server reflexive or relay candidates:
+/// publishers must reach this address directly, which fails when the server
+/// is behind a NAT unless the port range is forwarded, or when the publisher
+/// is only allowed out through a TURN server.
+fn candidate_ip(peer: IpAddr) -> Result<IpAddr, Error> {
+    let any: IpAddr = match peer {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = std::net::UdpSocket::bind((any, 0))?;
+    socket.connect((peer, 9))?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Drive the WebRTC session until the publisher disconnects or `stop` completes.
+async fn run(mut rtc: Rtc, socket: UdpSocket, mut feed: Feed, mut stop: oneshot::Receiver<()>) -> Result<(), Error> {
+    let local = socket.local_addr()?;
+    let connect_deadline = Instant::now() + CONNECT_TIMEOUT;
+    let mut connected = false;
+    let mut buf = vec![0u8; 2000];
+    loop {
+        let timeout = loop {
+            match rtc.poll_output()? {
+                Output::Timeout(timeout) => break timeout,
+                Output::Transmit(transmit) => {
+                    socket.send_to(&transmit.contents, transmit.destination).await?;
+                }
+                Output::Event(Event::Connected) => {
+                    info!("WHIP {} connected", feed.id);
+                    connected = true;
+                }
+                Output::Event(Event::IceConnectionStateChange(IceConnectionState::Disconnected)) => return Ok(()),
+                Output::Event(Event::MediaAdded(media)) if media.kind == MediaKind::Video => {
+                    feed.video = Some(media.mid);
+                }
+                Output::Event(Event::MediaData(data)) => {
+                    if feed.push(&data) {
+                        feed.request_keyframe(&mut rtc);
+                    }
+                }
+                Output::Event(_) => {}
+            }
+        };
+        if !rtc.is_alive() {
+            return Ok(());
+        }
+        if !connected && Instant::now() > connect_deadline {
+            return Err(anyhow!("publisher did not connect"));
+        }
+
+        let input = tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (n, source) = received?;
+                match Receive::new(Protocol::Udp, source, local, &buf[..n]) {
+                    Ok(receive) => Input::Receive(Instant::now(), receive),
+                    Err(e) => {
+                        debug!("WHIP {} ignoring datagram from {}: {}", feed.id, source, e);
+                        continue;
+                    }
+                }
+            }
+            _ = tokio::time::sleep_until(timeout.into()) => Input::Timeout(Instant::now()),
+            _ = &mut stop => {
+                rtc.disconnect();
+                return Ok(());
+            }
+        };
+        rtc.handle_input(input)?;
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/whip/{name}",
+    params(("name" = String, Path, description = "Stream to publish")),
+    request_body(content = String, content_type = "application/sdp", description = "SDP offer"),
+    responses(
+        (status = 201, description = "SDP answer, the Location header giving the session URL to DELETE"),
+        (status = 401, description = "Missing or invalid publish credentials"),
+        (status = 403, description = "Stream pulled from its camera or already published"),
+        (status = 404, description = "Unknown stream")
+    )
+)]
+#[post("/whip/{name:.*}")]
+pub async fn publish(req: HttpRequest, body: web::Bytes, name: web::Path<String>, data: web::Data<AppContext>) -> HttpResponse {
+    let app_context = data.get_ref();
+    let path = format!("/{}", name.trim_matches('/'));
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|c| c.to_str().ok()).unwrap_or_default();
+    if !content_type.starts_with("application/sdp") {
+        return HttpResponse::UnsupportedMediaType().body("expected application/sdp");
+    }
+    let offer = match std::str::from_utf8(&body).map_err(Error::from).and_then(|sdp| Ok(SdpOffer::from_sdp_string(sdp)?)) {
+        Ok(offer) => offer,
+        Err(e) => return HttpResponse::BadRequest().body(format!("invalid SDP offer: {e}")),
+    };
+    let Some(peer) = req.peer_addr() else {
+        return HttpResponse::BadRequest().finish();
+    };
+
+    let mut rtc = RtcConfig::new()
+        .set_ice_lite(true)
+        .clear_codecs()
+        .enable_h264(true)
+        .enable_opus(true, false)
+        .build(Instant::now());
+    let socket = match candidate_ip(peer.ip()) {
+        Ok(ip) => UdpSocket::bind(SocketAddr::new(ip, 0)).await.map_err(Error::from),
+        Err(e) => Err(e),
+    };
+    let socket = match socket {
+        Ok(socket) => socket,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let candidate = socket.local_addr().map_err(Error::from).and_then(|addr| Ok(Candidate::host(addr, "udp")?));
+    match candidate {
+        Ok(candidate) => {
+            rtc.add_local_candidate(candidate);
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
+    let answer = match rtc.sdp_api().accept_offer(offer) {
+        Ok(answer) => answer,
+        Err(e) => return HttpResponse::BadRequest().body(format!("unacceptable SDP offer: {e}")),
+    };
+
+    let authorization = req.headers().get(header::AUTHORIZATION).and_then(|a| a.to_str().ok());
+    let stream_def = match app_context.claim_stream(&path, authorization, "POST", &peer.to_string()) {
+        Ok(stream_def) => stream_def,
+        Err(e @ PublishError::UnknownStream(_)) => return HttpResponse::NotFound().body(e.to_string()),
+        Err(e @ PublishError::Unauthorized(_)) => {
+            return HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Bearer realm=\"rtsp2web-rs\""))
+                .body(e.to_string())
+        }
+        Err(e @ PublishError::Forbidden(_)) => return HttpResponse::Forbidden().body(e.to_string()),
+    };
+
+    let id = format!("{:016x}", rand_id());
+    let feed = match Feed::new(&id, stream_def) {
+        Ok(feed) => feed,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let stop = app_context.whip.register(&id);
+    let sessions = app_context.whip.clone();
+    let session_id = id.clone();
+    info!("WHIP {} publishing {} from {}", id, path, peer);
+    tokio::spawn(async move {
+        if let Err(e) = run(rtc, socket, feed, stop).await {
+            warn!("WHIP {} exited with error: {}", session_id, e);
+        }
+        sessions.stop(&session_id);
+        info!("WHIP {} stopped", session_id);
+    });
+
+    HttpResponse::Created()
+        .content_type("application/sdp")
+        .insert_header((header::LOCATION, format!("/whip{}/{}", path, id)))
+        .body(answer.to_sdp_string())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/whip/{name}/{session}",
+    params(
+        ("name" = String, Path, description = "Published stream"),
+        ("session" = String, Path, description = "Session identifier from the Location header")
+    ),
+    responses(
+        (status = 200, description = "Session ended"),
+        (status = 404, description = "Unknown session")
+    )
+)]
+#[delete("/whip/{name:.*}")]
+pub async fn unpublish(name: web::Path<String>, data: web::Data<AppContext>) -> HttpResponse {
+    let id = name.rsplit('/').next().unwrap_or_default();
+    if data.get_ref().whip.stop(id) {
+        info!("WHIP {} deleted", id);
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+fn rand_id() -> u64 {
+    let mut bytes = [0u8; 8];
+    let _ = SystemRandom::new().fill(&mut bytes);
+    u64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtsp::Credentials;
+    use actix_web::{test, App};
+    use str0m::change::SdpAnswer;
+    use str0m::media::Direction;
+
+    /// Drive a str0m client until it is connected to the server.
+    async fn connect(client: &mut Rtc, socket: &UdpSocket) {
+        let local = socket.local_addr().unwrap();
+        let mut buf = vec![0u8; 2000];
+        loop {
+            let timeout = loop {
+                match client.poll_output().unwrap() {
+                    Output::Timeout(timeout) => break timeout,
+                    Output::Transmit(transmit) => {
+                        socket.send_to(&transmit.contents, transmit.destination).await.unwrap();
+                    }
+                    Output::Event(Event::Connected) => return,
+                    Output::Event(_) => {}
+                }
+            };
+            let input = tokio::select! {
+                received = socket.recv_from(&mut buf) => {
+                    let (n, source) = received.unwrap();
+                    Input::Receive(Instant::now(), Receive::new(Protocol::Udp, source, local, &buf[..n]).unwrap())
+                }
+                _ = tokio::time::sleep_until(timeout.into()) => Input::Timeout(Instant::now()),
+            };
+            client.handle_input(input).unwrap();
+        }
+    }
+
+    #[actix_web::test]
+    async fn publishers_connect_and_are_stopped_by_a_delete_of_their_location() {
+        let credentials = Credentials { user: "obs".to_string(), password: "token".to_string() };
+        let stream_def = Arc::new(Mutex::new(StreamsDef::published(Some(credentials), 100)));
+        let app_context = AppContext::new(HashMap::from([("/cam".to_string(), stream_def.clone())]), None, None, None);
+        let app = test::init_service(App::new().app_data(web::Data::new(app_context.clone())).service(publish).service(unpublish)).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut client = RtcConfig::new().build(Instant::now());
+        client.add_local_candidate(Candidate::host(socket.local_addr().unwrap(), "udp").unwrap());
+        let mut change = client.sdp_api();
+        change.add_media(MediaKind::Video, Direction::SendOnly, None, None, None);
+        let (offer, pending) = change.apply().unwrap();
+
+        let post = |authorization: &str| {
+            test::TestRequest::post()
+                .uri("/whip/cam")
+                .peer_addr(socket.local_addr().unwrap())
+                .insert_header((header::CONTENT_TYPE, "application/sdp"))
+                .insert_header((header::AUTHORIZATION, authorization.to_string()))
+                .set_payload(offer.to_sdp_string())
+                .to_request()
+        };
+        let response = test::call_service(&app, post("Bearer wrong")).await;
+        assert_eq!(response.status().as_u16(), 401);
+
+        let response = test::call_service(&app, post("Bearer token")).await;
+        assert_eq!(response.status().as_u16(), 201);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "application/sdp");
+        let location = response.headers().get(header::LOCATION).unwrap().to_str().unwrap().to_string();
+        let id = location.strip_prefix("/whip/cam/").unwrap().to_string();
+        assert!(id.len() == 16 && id.chars().all(|c| c.is_ascii_hexdigit()));
+        let answer = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+        assert!(answer.contains("a=ice-lite"));
+        assert!(answer.contains("a=recvonly"));
+        assert!(answer.contains("H264/90000"));
+        let candidates: Vec<&str> = answer.lines().filter(|line| line.starts_with("a=candidate")).collect();
+        assert_eq!(candidates.len(), 1);
+        assert!(candidates[0].contains(" 127.0.0.1 ") && candidates[0].contains(" typ host"));
+        client.sdp_api().accept_answer(pending, SdpAnswer::from_sdp_string(&answer).unwrap()).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(10), connect(&mut client, &socket)).await.unwrap();
+        assert_eq!(stream_def.lock().unwrap().publisher, Some(socket.local_addr().unwrap().to_string()));
+        let response = test::call_service(&app, post("Bearer token")).await;
+        assert_eq!(response.status().as_u16(), 403);
+
+        let delete = |uri: &str| test::TestRequest::delete().uri(uri).to_request();
+        assert_eq!(test::call_service(&app, delete("/whip/cam/0000000000000000")).await.status().as_u16(), 404);
+        assert_eq!(test::call_service(&app, delete(&location)).await.status().as_u16(), 200);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while stream_def.lock().unwrap().publisher.is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(test::call_service(&app, delete(&location)).await.status().as_u16(), 404);
+    }
+}